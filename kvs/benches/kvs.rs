@@ -0,0 +1,204 @@
+// kvsのengineとserverのbenchmark
+// cargo bench -- <filter>で対象を絞り込める. 結果はtarget/criterionに保存され、前回との差分が表示される
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::{Client, Kvs, Server};
+use std::{net::SocketAddr, path::Path, time::Instant};
+use tempdir::TempDir;
+use tokio::{net::TcpListener, runtime::Runtime, sync::oneshot};
+
+// (keyのbytes数, valueのbytes数)
+const SIZES: [(usize, usize); 4] = [(16, 16), (16, 1024), (16, 64 * 1024), (256, 1024)];
+// get/iterで事前に書き込んでおくentryの数
+const ENTRIES: usize = 1_000;
+
+fn key(i: usize, size: usize) -> String {
+    format!("{:0width$}", i, width = size)
+}
+
+fn fill(kvs: &mut Kvs, entries: usize, key_size: usize, value_size: usize) {
+    let batch = (0..entries)
+        .map(|i| (key(i, key_size), vec![b'x'; value_size]))
+        .collect();
+    kvs.put_batch_raw(batch).unwrap();
+}
+
+fn label(key_size: usize, value_size: usize) -> String {
+    format!("key{}/value{}", key_size, value_size)
+}
+
+// 書き込みのbenchmarkは同じfileに追記し続けるとcompactionされずに大きくなるので、
+// sampleごとに新しいstoreを作り、計測後に削除する
+fn fresh_store() -> (TempDir, Kvs) {
+    let tmp_dir = TempDir::new("").unwrap();
+    let kvs = Kvs::new(tmp_dir.path().join("bench.kvs")).unwrap();
+    (tmp_dir, kvs)
+}
+
+// 大きいvalueはsampleを減らし、書き込む合計のbytes数を抑える
+fn sample_size(value_size: usize) -> usize {
+    if value_size >= 64 * 1024 {
+        10
+    } else {
+        100
+    }
+}
+
+fn put(c: &mut Criterion) {
+    let mut group = c.benchmark_group("put");
+    for &(key_size, value_size) in &SIZES {
+        let value = vec![b'x'; value_size];
+        group.sample_size(sample_size(value_size));
+        group.throughput(Throughput::Bytes((key_size + value_size) as u64));
+        group.bench_function(label(key_size, value_size), |b| {
+            b.iter_custom(|iters| {
+                let (_tmp_dir, mut kvs) = fresh_store();
+                let start = Instant::now();
+                for i in 0..iters as usize {
+                    kvs.put_raw(key(i % ENTRIES, key_size), value.clone())
+                        .unwrap();
+                }
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for &(key_size, value_size) in &SIZES {
+        let tmp_dir = TempDir::new("").unwrap();
+        let mut kvs = Kvs::new(tmp_dir.path().join("bench.kvs")).unwrap();
+        fill(&mut kvs, ENTRIES, key_size, value_size);
+        let mut i = 0;
+        group.throughput(Throughput::Bytes((key_size + value_size) as u64));
+        group.bench_function(label(key_size, value_size), |b| {
+            b.iter(|| {
+                i = (i + 7) % ENTRIES;
+                black_box(kvs.get_raw(&key(i, key_size)).unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn delete(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete");
+    for &(key_size, value_size) in &SIZES {
+        group.sample_size(sample_size(value_size));
+        group.bench_function(label(key_size, value_size), |b| {
+            // 削除するentryを書き込む時間は含めない
+            b.iter_custom(|iters| {
+                let iters = iters as usize;
+                let (_tmp_dir, mut kvs) = fresh_store();
+                fill(&mut kvs, iters, key_size, value_size);
+                let start = Instant::now();
+                for i in 0..iters {
+                    kvs.delete_raw(&key(i, key_size)).unwrap();
+                }
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+// 開く際のKeyIndexの構築はfileの大きさに比例する
+fn open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    group.sample_size(10);
+    for &entries in &[10_000, 100_000] {
+        let tmp_dir = TempDir::new("").unwrap();
+        let path = tmp_dir.path().join("bench.kvs");
+        // 上書きされたentryも読み込む必要があるので、keyの半分を2回書き込む
+        let mut kvs = Kvs::new(&path).unwrap();
+        fill(&mut kvs, entries / 2, 16, 100);
+        fill(&mut kvs, entries / 2, 16, 100);
+        drop(kvs);
+
+        group.throughput(Throughput::Elements(entries as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(entries), &path, |b, path| {
+            b.iter(|| Kvs::open_read_only(path as &Path).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn iter(c: &mut Criterion) {
+    let tmp_dir = TempDir::new("").unwrap();
+    let mut kvs = Kvs::new(tmp_dir.path().join("bench.kvs")).unwrap();
+    for i in 0..ENTRIES {
+        kvs.put(key(i, 16), &"x".repeat(100)).unwrap();
+    }
+
+    let mut group = c.benchmark_group("iter");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+    group.bench_function(BenchmarkId::from_parameter(ENTRIES), |b| {
+        b.iter(|| {
+            for value in kvs.iter::<String>() {
+                black_box(value.unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+// 1つのconnectionでrequestを直列に送った場合のthroughput
+fn server(c: &mut Criterion) {
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let tmp_dir = TempDir::new("").unwrap();
+    let (addr, shutdown) = start_server(&mut runtime, &tmp_dir.path().join("bench.kvs"));
+    let mut client = runtime
+        .block_on(Client::connect(&addr.to_string(), None))
+        .unwrap();
+
+    let mut group = c.benchmark_group("server");
+    for &value_size in &[16, 1024] {
+        let value = vec![b'x'; value_size];
+        group.throughput(Throughput::Bytes((16 + value_size) as u64));
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("put", value_size), |b| {
+            b.iter(|| {
+                i += 1;
+                runtime
+                    .block_on(client.put_raw(key(i % ENTRIES, 16), value.clone()))
+                    .unwrap()
+            })
+        });
+        // 全てのkeyを書き込んでおき、getが必ずvalueを返すようにする
+        for n in 0..ENTRIES {
+            runtime
+                .block_on(client.put_raw(key(n, 16), value.clone()))
+                .unwrap();
+        }
+        group.bench_function(BenchmarkId::new("get", value_size), |b| {
+            b.iter(|| {
+                i = (i + 7) % ENTRIES;
+                black_box(runtime.block_on(client.get_raw(&key(i, 16))).unwrap())
+            })
+        });
+    }
+    group.finish();
+
+    shutdown.send(()).unwrap();
+}
+
+fn start_server(runtime: &mut Runtime, path: &Path) -> (SocketAddr, oneshot::Sender<()>) {
+    // bindしたlistenerをそのまま渡すので、起動を待たずに接続できる
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = Server::new(Kvs::new(path).unwrap());
+    runtime.spawn(server.serve(listener, async {
+        shutdown_rx.await.ok();
+    }));
+    (addr, shutdown_tx)
+}
+
+criterion_group!(engine, put, get, delete, open, iter);
+criterion_group!(network, server);
+criterion_main!(engine, network);
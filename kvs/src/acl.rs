@@ -0,0 +1,110 @@
+use crate::{KvsError, Result};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+// serverに対して許可する操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Get,
+    Put,
+    Delete,
+}
+
+// tokenごとに許可する操作とkeyのprefixを定義する
+//
+// ```toml
+// [[grants]]
+// token = "todo-secret"
+// operations = ["get", "put", "delete"]
+// prefixes = ["todo/"]
+// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct Acl {
+    grants: Vec<Grant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Grant {
+    token: String,
+    operations: Vec<Operation>,
+    // 空文字を指定すると全てのkeyを許可する
+    prefixes: Vec<String>,
+}
+
+impl Acl {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Acl::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(|err| KvsError::InvalidAcl(err.to_string()))
+    }
+
+    // tokenに対応するgrantを返す
+    pub(crate) fn authenticate(&self, token: &str) -> Result<Grant> {
+        self.grants
+            .iter()
+            .find(|grant| grant.token == token)
+            .cloned()
+            .ok_or(KvsError::PermissionDenied)
+    }
+}
+
+impl Grant {
+    pub(crate) fn authorize(&self, operation: Operation, key: &str) -> Result<()> {
+        if self.operations.contains(&operation)
+            && self
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+        {
+            Ok(())
+        } else {
+            Err(KvsError::PermissionDenied)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACL: &str = r#"
+[[grants]]
+token = "todo"
+operations = ["get", "put", "delete"]
+prefixes = ["todo/"]
+
+[[grants]]
+token = "reader"
+operations = ["get"]
+prefixes = [""]
+"#;
+
+    #[test]
+    fn authorize() -> Result<()> {
+        let acl = Acl::from_toml(ACL)?;
+
+        let todo = acl.authenticate("todo")?;
+        assert!(todo.authorize(Operation::Put, "todo/1").is_ok());
+        assert!(todo.authorize(Operation::Delete, "todo/1").is_ok());
+        assert!(todo
+            .authorize(Operation::Get, "other/1")
+            .unwrap_err()
+            .is_permission_denied());
+
+        let reader = acl.authenticate("reader")?;
+        assert!(reader.authorize(Operation::Get, "other/1").is_ok());
+        assert!(reader
+            .authorize(Operation::Put, "other/1")
+            .unwrap_err()
+            .is_permission_denied());
+
+        assert!(acl
+            .authenticate("unknown")
+            .unwrap_err()
+            .is_permission_denied());
+        Ok(())
+    }
+}
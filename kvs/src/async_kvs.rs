@@ -0,0 +1,143 @@
+// Kvsの操作を専用のthreadで実行し、結果をasyncに待つfacade
+// file IOでruntimeのthreadをblockしない. cloneしたhandleは同じthreadに操作を送るので、
+// handlerごとにcloneして渡せばlockなしで共有できる
+use crate::{Kvs, KvsError, Result};
+use std::{sync::mpsc, thread};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&mut Kvs) + Send>;
+
+#[derive(Clone)]
+pub struct AsyncKvs {
+    tx: mpsc::Sender<Job>,
+}
+
+impl AsyncKvs {
+    // kvsを所有するthreadを起動する. 全てのhandleがdropされるとthreadは終了し、fileを閉じる
+    pub fn new(mut kvs: Kvs) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("kvs-engine".to_owned())
+            .spawn(move || rx.into_iter().for_each(|job| job(&mut kvs)))?;
+        Ok(Self { tx })
+    }
+
+    // 任意の操作をkvsのthreadで実行する. 操作は送った順に1つずつ実行される
+    pub async fn run<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Kvs) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(Box::new(move |kvs: &mut Kvs| {
+                let _ = reply.send(f(kvs));
+            }))
+            .map_err(|_| KvsError::EngineStopped)?;
+        rx.await.map_err(|_| KvsError::EngineStopped)?
+    }
+
+    // serialize/deserializeは呼び出し側のtaskで行い、kvsのthreadではbytesのみを扱う
+    pub async fn get<T>(&self, key: impl Into<String>) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let key = key.into();
+        let bytes = self.run(move |kvs| kvs.get_raw(&key)).await?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    pub async fn put<T>(&self, key: impl Into<String>, value: &T) -> Result<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        let (key, bytes) = (key.into(), bincode::serialize(value)?);
+        self.run(move |kvs| kvs.put_raw(key, bytes)).await
+    }
+
+    pub async fn delete<T>(&self, key: impl Into<String>) -> Result<Option<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let key = key.into();
+        match self.run(move |kvs| kvs.delete_raw(&key)).await? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    // prefixから始まるkeyとvalueをkeyの順に返す
+    pub async fn scan<T>(&self, prefix: impl Into<String>) -> Result<Vec<(String, T)>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.scan_raw(prefix)
+            .await?
+            .into_iter()
+            .map(|(key, bytes)| Ok((key, bincode::deserialize(&bytes)?)))
+            .collect()
+    }
+
+    // valueの型が混在している場合に利用する
+    pub async fn scan_raw(&self, prefix: impl Into<String>) -> Result<Vec<(String, Vec<u8>)>> {
+        let prefix = prefix.into();
+        self.run(move |kvs| {
+            let mut keys = kvs
+                .owned_keys()
+                .filter(|key| key.starts_with(&prefix))
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys.into_iter()
+                .map(|key| kvs.get_raw(&key).map(|bytes| (key, bytes)))
+                .collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+
+    #[tokio::test]
+    async fn operations() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let kvs = AsyncKvs::new(Kvs::new(tmp_dir.path().join("test.kvs"))?)?;
+
+        kvs.put("user/2", "bob").await?;
+        kvs.put("user/1", "alice").await?;
+        kvs.put("item/1", &1_u32).await?;
+        assert_eq!(kvs.get::<String>("user/1").await?, "alice");
+        assert!(kvs
+            .get::<String>("user/3")
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert_eq!(
+            kvs.scan::<String>("user/").await?,
+            vec![
+                ("user/1".to_owned(), "alice".to_owned()),
+                ("user/2".to_owned(), "bob".to_owned())
+            ]
+        );
+        assert_eq!(kvs.scan_raw("").await?.len(), 3);
+
+        // cloneしたhandleは同じkvsを操作する
+        let handles = (0..10_u32)
+            .map(|i| {
+                let kvs = kvs.clone();
+                tokio::spawn(async move { kvs.put(format!("n/{}", i), &i).await })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await??;
+        }
+        assert_eq!(kvs.scan::<u32>("n/").await?.len(), 10);
+        assert_eq!(kvs.delete::<u32>("n/3").await?, Some(3));
+        assert_eq!(kvs.delete::<u32>("n/3").await?, None);
+        assert_eq!(kvs.run(|kvs| Ok(kvs.stats().keys)).await?, 12);
+        Ok(())
+    }
+}
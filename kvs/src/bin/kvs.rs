@@ -1,5 +1,8 @@
-use kvs::{cli, Kvs, KvsError};
-use std::path::PathBuf;
+use kvs::{
+    cli, Acl, Cluster, Codec, DumpFilter, EngineKind, Format, Kvs, KvsError, NodeId, Protocol,
+    RaftConfig, Report, Server, TlsConfig, ValueFormat,
+};
+use std::{fs::File, io, net::SocketAddr, path::PathBuf};
 use structopt::{clap, StructOpt};
 
 #[derive(StructOpt, Debug)]
@@ -23,6 +26,15 @@ pub struct Opt {
     )]
     pub file: PathBuf, // server commandでは無効にしたい
 
+    #[structopt(
+        long = "engine",
+        global = true,
+        help = "storage engine. log or lsm. lsm stores data in the directory given by --file.",
+        env = "KVS_ENGINE",
+        default_value = "log"
+    )]
+    pub engine: EngineKind,
+
     #[structopt(subcommand)]
     pub cmd: SubCommand,
 }
@@ -35,16 +47,102 @@ pub enum SubCommand {
         key: String,
         #[structopt(help = "value")]
         value: String,
+        #[structopt(
+            long = "format",
+            help = "how to encode value. string, raw, json or hex.",
+            default_value = "string"
+        )]
+        format: ValueFormat,
     },
     #[structopt(about = "Get value from disk.")]
     Get {
         #[structopt(help = "key")]
         key: String,
+        #[structopt(
+            long = "format",
+            help = "how to print value. string, raw, json or hex.",
+            default_value = "string"
+        )]
+        format: ValueFormat,
+    },
+    #[structopt(about = "Print value of unknown type. guess bincode fields or json.")]
+    Inspect {
+        #[structopt(help = "key")]
+        key: String,
+    },
+    #[structopt(about = "Print all past values and deletions of key with log offset.")]
+    History {
+        #[structopt(help = "key")]
+        key: String,
+        #[structopt(
+            long = "format",
+            help = "how to print value. string, raw, json or hex.",
+            default_value = "string"
+        )]
+        format: ValueFormat,
     },
     #[structopt(about = "Mark delete to given key value. return value if key exists.")]
     Delete {
         #[structopt(help = "key")]
         key: String,
+        #[structopt(
+            long = "format",
+            help = "how to print deleted value. string, raw, json or hex.",
+            default_value = "string"
+        )]
+        format: ValueFormat,
+    },
+    #[structopt(about = "Print file size, live/stale bytes and number of keys.")]
+    Stats,
+
+    #[structopt(about = "Print each entry in file with offset and crc check. for debugging.")]
+    Dump {
+        #[structopt(long = "key", help = "print only entries of this key.")]
+        key: Option<String>,
+        #[structopt(long = "from", help = "print only entries at or after this offset.")]
+        from: Option<u64>,
+        #[structopt(long = "to", help = "print only entries before this offset.")]
+        to: Option<u64>,
+    },
+    #[structopt(about = "Write all entries as json lines or csv.")]
+    Export {
+        #[structopt(
+            long = "format",
+            help = "output format. jsonl or csv.",
+            default_value = "jsonl"
+        )]
+        format: Format,
+        #[structopt(
+            long = "codec",
+            help = "how to decode values. string(written by kvs put), json or raw(base64).",
+            default_value = "string"
+        )]
+        codec: Codec,
+        #[structopt(long = "output", short = "o", help = "output file. default is stdout.")]
+        output: Option<PathBuf>,
+    },
+    #[structopt(about = "Load entries from json lines or csv written by export.")]
+    Import {
+        #[structopt(
+            long = "format",
+            help = "input format. jsonl or csv.",
+            default_value = "jsonl"
+        )]
+        format: Format,
+        #[structopt(
+            long = "codec",
+            help = "how to encode values. string(read by kvs get), json or raw(base64).",
+            default_value = "string"
+        )]
+        codec: Codec,
+        #[structopt(
+            long = "batch-size",
+            help = "number of entries to write at once.",
+            default_value = "1000"
+        )]
+        batch_size: usize,
+        #[structopt(help = "input file. default is stdin.")]
+        input: Option<PathBuf>,
     },
 
     #[structopt(about = "Server mode.")]
@@ -56,6 +154,111 @@ pub enum SubCommand {
             default_value = "0.0.0.0:4002"
         )]
         addr: String,
+        #[structopt(flatten)]
+        tls: TlsOpt,
+        #[structopt(
+            long = "acl",
+            help = "acl config(toml). require clients to authenticate with token.",
+            env = "KVS_ACL"
+        )]
+        acl: Option<PathBuf>,
+        #[structopt(
+            long = "protocol",
+            help = "protocol to serve. kvs or resp(redis compatible).",
+            env = "KVS_PROTOCOL",
+            default_value = "kvs"
+        )]
+        protocol: Protocol,
+        #[structopt(
+            long = "http-addr",
+            help = "http bind address. serve http/json gateway in addition to tcp.",
+            env = "KVS_HTTP_ADDR"
+        )]
+        http_addr: Option<SocketAddr>,
+        #[structopt(
+            long = "http-max-body-bytes",
+            help = "max body bytes of http put. larger requests are rejected with 413.",
+            env = "KVS_HTTP_MAX_BODY_BYTES",
+            default_value = "8388608"
+        )]
+        http_max_body_bytes: u64,
+        #[structopt(
+            long = "replica-of",
+            help = "leader address. run as read-only follower replicating the leader's log.",
+            env = "KVS_REPLICA_OF"
+        )]
+        replica_of: Option<String>,
+        #[structopt(
+            long = "replica-token",
+            help = "token to authenticate with the leader.",
+            env = "KVS_REPLICA_TOKEN"
+        )]
+        replica_token: Option<String>,
+        #[structopt(
+            long = "raft-cluster",
+            help = "cluster config(toml). run as raft node. raft log is stored in <file>.raft",
+            env = "KVS_RAFT_CLUSTER",
+            requires = "raft-id"
+        )]
+        raft_cluster: Option<PathBuf>,
+        #[structopt(
+            long = "raft-id",
+            help = "node id of this server in cluster config.",
+            env = "KVS_RAFT_ID"
+        )]
+        raft_id: Option<NodeId>,
+        #[structopt(
+            long = "metrics-addr",
+            help = "http bind address. serve prometheus metrics on /metrics.",
+            env = "KVS_METRICS_ADDR"
+        )]
+        metrics_addr: Option<SocketAddr>,
+        #[structopt(
+            long = "cache-bytes",
+            help = "cache values read by get up to this many bytes. 0 disables cache.",
+            env = "KVS_CACHE_BYTES",
+            default_value = "0"
+        )]
+        cache_bytes: usize,
+    },
+
+    #[structopt(about = "Http/json gateway mode.")]
+    Http {
+        #[structopt(
+            long = "addr",
+            help = "http bind address.",
+            env = "KVS_HTTP_ADDR",
+            default_value = "0.0.0.0:4003"
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "max-body-bytes",
+            help = "max body bytes of http put. larger requests are rejected with 413.",
+            env = "KVS_HTTP_MAX_BODY_BYTES",
+            default_value = "8388608"
+        )]
+        http_max_body_bytes: u64,
+        #[structopt(flatten)]
+        tls: TlsOpt,
+        #[structopt(
+            long = "acl",
+            help = "acl config(toml). require clients to authenticate with bearer token.",
+            env = "KVS_ACL"
+        )]
+        acl: Option<PathBuf>,
+        #[structopt(
+            long = "metrics-addr",
+            help = "http bind address. serve prometheus metrics on /metrics.",
+            env = "KVS_METRICS_ADDR"
+        )]
+        metrics_addr: Option<SocketAddr>,
+        #[structopt(
+            long = "cache-bytes",
+            help = "cache values read by get up to this many bytes. 0 disables cache.",
+            env = "KVS_CACHE_BYTES",
+            default_value = "0"
+        )]
+        cache_bytes: usize,
     },
 
     #[structopt(about = "Client mode.")]
@@ -67,34 +270,400 @@ pub enum SubCommand {
             default_value = "0.0.0.0:4002"
         )]
         addr: String,
+        #[structopt(
+            long = "shards",
+            help = "comma separated server addresses. route keys to servers by consistent hash. overrides addr.",
+            env = "KVS_SHARDS",
+            use_delimiter = true
+        )]
+        shards: Vec<String>,
+        #[structopt(
+            long = "vnodes",
+            help = "number of virtual nodes per server on hash ring.",
+            default_value = "128"
+        )]
+        vnodes: u32,
+        #[structopt(flatten)]
+        tls: TlsOpt,
+        #[structopt(
+            long = "tls-server-name",
+            help = "server name to verify server certificate. default is host of addr."
+        )]
+        tls_server_name: Option<String>,
+        #[structopt(long = "token", help = "token to authenticate.", env = "KVS_TOKEN")]
+        token: Option<String>,
+        #[structopt(subcommand)]
+        cmd: Option<ClientCommand>,
+    },
+
+    #[structopt(about = "Interactive shell on local file or remote server.")]
+    Shell {
+        #[structopt(
+            long = "addr",
+            help = "server address. operate on local file if not specified.",
+            env = "KVS_ADDR"
+        )]
+        addr: Option<String>,
+        #[structopt(flatten)]
+        tls: TlsOpt,
+        #[structopt(
+            long = "tls-server-name",
+            help = "server name to verify server certificate. default is host of addr."
+        )]
+        tls_server_name: Option<String>,
+        #[structopt(long = "token", help = "token to authenticate.", env = "KVS_TOKEN")]
+        token: Option<String>,
+        #[structopt(
+            long = "history",
+            help = "file to save command history. default is ~/.kvs_history",
+            env = "KVS_HISTORY"
+        )]
+        history: Option<PathBuf>,
+    },
+
+    #[structopt(about = "Move keys between sharded servers after adding or removing servers.")]
+    Rebalance {
+        #[structopt(
+            long = "from",
+            help = "comma separated server addresses before change.",
+            use_delimiter = true,
+            required = true
+        )]
+        from: Vec<String>,
+        #[structopt(
+            long = "to",
+            help = "comma separated server addresses after change.",
+            use_delimiter = true,
+            required = true
+        )]
+        to: Vec<String>,
+        #[structopt(
+            long = "vnodes",
+            help = "number of virtual nodes per server on hash ring.",
+            default_value = "128"
+        )]
+        vnodes: u32,
+        #[structopt(long = "dry-run", help = "print keys to move without moving.")]
+        dry_run: bool,
+        #[structopt(flatten)]
+        tls: TlsOpt,
+        #[structopt(long = "token", help = "token to authenticate.", env = "KVS_TOKEN")]
+        token: Option<String>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ClientCommand {
+    #[structopt(about = "Send echo request.")]
+    Echo {
+        #[structopt(help = "message", default_value = "Hello kvs!")]
+        message: String,
+    },
+    #[structopt(about = "Put key value to server.")]
+    Put {
+        #[structopt(help = "key")]
+        key: String,
+        #[structopt(help = "value")]
+        value: String,
+        #[structopt(
+            long = "format",
+            help = "how to encode value. string, raw, json or hex.",
+            default_value = "string"
+        )]
+        format: ValueFormat,
+    },
+    #[structopt(about = "Get value from server.")]
+    Get {
+        #[structopt(help = "key")]
+        key: String,
+        #[structopt(
+            long = "format",
+            help = "how to print value. string, raw, json or hex.",
+            default_value = "string"
+        )]
+        format: ValueFormat,
+    },
+    #[structopt(about = "Print value of unknown type. guess bincode fields or json.")]
+    Inspect {
+        #[structopt(help = "key")]
+        key: String,
     },
+    #[structopt(about = "Delete key from server.")]
+    Delete {
+        #[structopt(help = "key")]
+        key: String,
+        #[structopt(
+            long = "format",
+            help = "how to print deleted value. string, raw, json or hex.",
+            default_value = "string"
+        )]
+        format: ValueFormat,
+    },
+}
+
+impl From<ClientCommand> for cli::client::Command {
+    fn from(cmd: ClientCommand) -> Self {
+        use cli::client::Command;
+        match cmd {
+            ClientCommand::Echo { message } => Command::Echo { message },
+            ClientCommand::Put { key, value, format } => Command::Put { key, value, format },
+            ClientCommand::Get { key, format } => Command::Get { key, format },
+            ClientCommand::Inspect { key } => Command::Inspect { key },
+            ClientCommand::Delete { key, format } => Command::Delete { key, format },
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct TlsOpt {
+    #[structopt(
+        long = "tls-cert",
+        help = "pem encoded certificate. enable tls.",
+        env = "KVS_TLS_CERT"
+    )]
+    pub cert: Option<PathBuf>,
+    #[structopt(
+        long = "tls-key",
+        help = "pem encoded private key of certificate.",
+        env = "KVS_TLS_KEY"
+    )]
+    pub key: Option<PathBuf>,
+    #[structopt(
+        long = "tls-ca",
+        help = "pem encoded ca certificate. server requires client certificate signed by this ca.",
+        env = "KVS_TLS_CA"
+    )]
+    pub ca: Option<PathBuf>,
+}
+
+impl From<TlsOpt> for TlsConfig {
+    fn from(opt: TlsOpt) -> Self {
+        TlsConfig {
+            cert: opt.cert,
+            key: opt.key,
+            ca: opt.ca,
+            server_name: None,
+        }
+    }
 }
 
 fn run() -> Result<(), anyhow::Error> {
     let opt = Opt::from_args();
 
-    let mut kvs = Kvs::new(opt.file)?;
+    // 壊れたfileも読めるよう、Kvsとして開かずに読む
+    if let SubCommand::Dump { key, from, to } = opt.cmd {
+        let file = io::BufReader::new(File::open(&opt.file)?);
+        let filter = DumpFilter { key, from, to };
+        let summary = kvs::dump(file, io::stdout().lock(), &filter)?;
+        eprintln!(
+            "{} entries, {} deleted, {} corrupt",
+            summary.entries, summary.deleted, summary.corrupt
+        );
+        return match summary.error {
+            Some(err) => Err(anyhow::anyhow!(err)),
+            None => Ok(()),
+        };
+    }
+
+    // 参照のみのcommandはserverなどのwriterと同時に実行できるよう、lockを取らずに開く
+    // clientとrebalanceはfileを利用しないので開かない
+    let (file, engine) = (&opt.file, opt.engine);
+    let open = || Kvs::with_engine(file, engine);
+    // fileがまだない場合は空のstoreとして扱う. 作成はせず、getはNot Foundになる
+    let open_read_only = || {
+        if file.exists() {
+            Kvs::open_read_only_with_engine(file, engine)
+        } else {
+            Kvs::in_memory()
+        }
+    };
 
     match opt.cmd {
-        SubCommand::Put { key, value } => {
-            kvs.put::<_, String>(key, &value)?;
+        SubCommand::Put { key, value, format } => {
+            open()?.put_raw(key, format.encode(&value)?)?;
+        }
+        SubCommand::Get { key, format } => {
+            cli::print_value(format, &open_read_only()?.get_raw(&key)?)?
+        }
+        SubCommand::Inspect { key } => {
+            println!("{}", kvs::inspect(&open_read_only()?.get_raw(&key)?))
+        }
+        SubCommand::History { key, format } => {
+            cli::print_history(format, &open_read_only()?.history_raw(&key)?)?
         }
-        SubCommand::Get { key } => {
-            println!("{}", kvs.get::<String>(key.as_str())?);
+        SubCommand::Delete { key, format } => {
+            cli::print_deleted(format, open()?.delete_raw(&key)?)?
         }
-        SubCommand::Delete { key } => {
-            if let Some(value) = kvs.delete::<String>(&key)? {
-                println!("{}", value);
+        SubCommand::Stats => println!("{}", open_read_only()?.stats()),
+        SubCommand::Dump { .. } => unreachable!(),
+        SubCommand::Export {
+            format,
+            codec,
+            output,
+        } => {
+            let mut kvs = open_read_only()?;
+            let report = match output {
+                Some(path) => kvs::export(&mut kvs, File::create(path)?, format, codec)?,
+                None => kvs::export(&mut kvs, io::stdout().lock(), format, codec)?,
+            };
+            print_report("Exported", &report)?;
+        }
+        SubCommand::Import {
+            format,
+            codec,
+            batch_size,
+            input,
+        } => {
+            let mut kvs = open()?;
+            let report = match input {
+                Some(path) => kvs::import(&mut kvs, File::open(path)?, format, codec, batch_size)?,
+                None => kvs::import(&mut kvs, io::stdin().lock(), format, codec, batch_size)?,
+            };
+            print_report("Imported", &report)?;
+        }
+        SubCommand::Server {
+            addr,
+            tls,
+            acl,
+            protocol,
+            http_addr,
+            http_max_body_bytes,
+            replica_of,
+            replica_token,
+            raft_cluster,
+            raft_id,
+            metrics_addr,
+            cache_bytes,
+        } => {
+            let mut kvs = open()?;
+            kvs.set_cache_capacity(cache_bytes);
+            let mut server = Server::new(kvs)
+                .tls(tls.into())
+                .protocol(protocol)
+                .http_max_body_bytes(http_max_body_bytes);
+            if let Some(acl) = acl {
+                server = server.acl(Acl::from_file(acl)?);
+            }
+            if let Some(http_addr) = http_addr {
+                server = server.http(http_addr);
+            }
+            if let Some(metrics_addr) = metrics_addr {
+                server = server.metrics(metrics_addr);
+            }
+            if let Some(leader) = replica_of {
+                server = server.replica_of(leader);
+            }
+            if let Some(token) = replica_token {
+                server = server.replica_token(token);
+            }
+            if let (Some(cluster), Some(id)) = (raft_cluster, raft_id) {
+                let mut dir = file.clone().into_os_string();
+                dir.push(".raft");
+                server = server.raft(RaftConfig {
+                    id,
+                    cluster: Cluster::from_file(cluster)?,
+                    dir: dir.into(),
+                });
+            }
+            cli::server::server_main(addr, server)?
+        }
+        SubCommand::Http {
+            addr,
+            http_max_body_bytes,
+            tls,
+            acl,
+            metrics_addr,
+            cache_bytes,
+        } => {
+            let mut kvs = open()?;
+            kvs.set_cache_capacity(cache_bytes);
+            let mut server = Server::new(kvs)
+                .tls(tls.into())
+                .http_max_body_bytes(http_max_body_bytes);
+            if let Some(acl) = acl {
+                server = server.acl(Acl::from_file(acl)?);
+            }
+            if let Some(metrics_addr) = metrics_addr {
+                server = server.metrics(metrics_addr);
             }
-            println!("Successfully deleted");
+            cli::server::http_main(addr, server)?
         }
-        SubCommand::Server { addr, .. } => cli::server::server_main(addr)?,
-        SubCommand::Client { addr, .. } => cli::client::client_main(addr)?,
+        SubCommand::Client {
+            addr,
+            shards,
+            vnodes,
+            tls,
+            tls_server_name,
+            token,
+            cmd,
+        } => cli::client::client_main(
+            if shards.is_empty() {
+                vec![addr]
+            } else {
+                shards
+            },
+            vnodes,
+            TlsConfig {
+                server_name: tls_server_name,
+                ..tls.into()
+            },
+            token,
+            cmd.map(Into::into).unwrap_or(cli::client::Command::Echo {
+                message: "Hello kvs!".to_owned(),
+            }),
+        )?,
+        SubCommand::Shell {
+            addr,
+            tls,
+            tls_server_name,
+            token,
+            history,
+        } => {
+            use cli::shell::Target;
+            let target = match addr {
+                Some(addr) => Target::Remote {
+                    addr,
+                    tls: TlsConfig {
+                        server_name: tls_server_name,
+                        ..tls.into()
+                    },
+                    token,
+                },
+                None => Target::Local(Box::new(open()?)),
+            };
+            cli::shell::shell_main(target, history)?
+        }
+        SubCommand::Rebalance {
+            from,
+            to,
+            vnodes,
+            dry_run,
+            tls,
+            token,
+        } => cli::client::rebalance_main(from, to, vnodes, tls.into(), token, dry_run)?,
     }
 
     Ok(())
 }
 
+// 件数と失敗したrecordをstderrに出力する. 失敗したrecordがあればerrorを返す
+fn print_report(action: &str, report: &Report) -> Result<(), anyhow::Error> {
+    for err in &report.errors {
+        eprintln!("{}", err);
+    }
+    eprintln!(
+        "{} {} entries, {} errors",
+        action,
+        report.entries,
+        report.errors.len()
+    );
+    if report.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("{} records failed", report.errors.len()))
+    }
+}
+
 fn main() {
     if let Err(err) = run() {
         let code = match err.downcast_ref::<KvsError>() {
@@ -102,6 +671,18 @@ fn main() {
                 eprintln!("Not Found");
                 2
             }
+            Some(KvsError::PermissionDenied) => {
+                eprintln!("Permission Denied");
+                3
+            }
+            Some(KvsError::ReadOnly) => {
+                eprintln!("Read Only");
+                4
+            }
+            Some(err @ KvsError::NotLeader(_)) => {
+                eprintln!("{}", err);
+                5
+            }
             _ => {
                 eprintln!("{}", err);
                 1
@@ -0,0 +1,404 @@
+// kvsのentryをJSON LinesかCSVで書き出し、読み込む
+// valueのbytesはCodecでJSONの値に変換する
+//
+// JSON Lines: {"key":"todo/1","value":"..."}
+// CSV: 1行目はheader(key,value). JSONの値が文字列でない場合はJSONの文字列表現を書き出す
+use crate::{Kvs, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    str::FromStr,
+};
+
+// importで1度に書き込むentryの数のdefault
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(KvsError::InvalidFormat(s.to_owned())),
+        }
+    }
+}
+
+// valueのbytesの解釈
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    // bincodeでserializeされたString. kvs commandやshellで書き込んだvalue
+    String,
+    // JSONのbytes. HTTP gatewayにapplication/jsonで書き込んだvalue
+    Json,
+    // 任意のbytes. base64の文字列で表現するので、どのvalueも失わずに移せる
+    Raw,
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(Codec::String),
+            "json" => Ok(Codec::Json),
+            "raw" => Ok(Codec::Raw),
+            _ => Err(KvsError::InvalidCodec(s.to_owned())),
+        }
+    }
+}
+
+impl Codec {
+    fn decode(self, bytes: &[u8]) -> Result<Json> {
+        match self {
+            Codec::String => Ok(Json::String(bincode::deserialize(bytes)?)),
+            Codec::Json => serde_json::from_slice(bytes)
+                .map_err(|err| KvsError::InvalidRecord(err.to_string())),
+            Codec::Raw => Ok(Json::String(base64::encode(bytes))),
+        }
+    }
+
+    fn encode(self, value: Json) -> Result<Vec<u8>> {
+        match (self, value) {
+            (Codec::String, Json::String(s)) => Ok(bincode::serialize(&s)?),
+            (Codec::Json, value) => Ok(value.to_string().into_bytes()),
+            (Codec::Raw, Json::String(s)) => {
+                base64::decode(&s).map_err(|err| KvsError::InvalidRecord(err.to_string()))
+            }
+            (_, value) => Err(KvsError::InvalidRecord(format!(
+                "value must be string: {}",
+                value
+            ))),
+        }
+    }
+
+    // CSVのvalueのcolumnから戻す. json以外はそのまま文字列として扱う
+    fn parse(self, text: String) -> Result<Json> {
+        match self {
+            Codec::Json => {
+                serde_json::from_str(&text).map_err(|err| KvsError::InvalidRecord(err.to_string()))
+            }
+            _ => Ok(Json::String(text)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: Json,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CsvRecord {
+    key: String,
+    value: String,
+}
+
+// exportとimportの結果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    // 書き出した、または書き込んだentryの数
+    pub entries: u64,
+    // 失敗したrecordと理由. exportはkey、importは行番号で示す
+    pub errors: Vec<String>,
+}
+
+// 全てのentryをkeyの順に書き出す. decodeできないvalueはerrorsに記録して飛ばす
+pub fn export<W: Write>(kvs: &mut Kvs, w: W, format: Format, codec: Codec) -> Result<Report> {
+    let mut keys = kvs.owned_keys().collect::<Vec<_>>();
+    keys.sort();
+
+    let mut report = Report::default();
+    let mut writer = Writer::new(w, format);
+    for key in keys {
+        match kvs.get_raw(&key).and_then(|bytes| codec.decode(&bytes)) {
+            Ok(value) => writer.write(Record { key, value })?,
+            Err(err) => {
+                report.errors.push(format!("key {}: {}", key, err));
+                continue;
+            }
+        }
+        report.entries += 1;
+    }
+    writer.finish()?;
+    Ok(report)
+}
+
+enum Writer<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Writer<W> {
+    fn new(w: W, format: Format) -> Self {
+        match format {
+            Format::JsonLines => Writer::JsonLines(w),
+            Format::Csv => Writer::Csv(Box::new(csv::Writer::from_writer(w))),
+        }
+    }
+
+    fn write(&mut self, record: Record) -> Result<()> {
+        match self {
+            Writer::JsonLines(w) => {
+                serde_json::to_writer(&mut *w, &record)
+                    .map_err(|err| KvsError::InvalidRecord(err.to_string()))?;
+                w.write_all(b"\n")?;
+            }
+            Writer::Csv(w) => {
+                let value = match record.value {
+                    Json::String(s) => s,
+                    value => value.to_string(),
+                };
+                w.serialize(CsvRecord {
+                    key: record.key,
+                    value,
+                })
+                .map_err(csv_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Writer::JsonLines(mut w) => w.flush()?,
+            Writer::Csv(mut w) => w.flush()?,
+        }
+        Ok(())
+    }
+}
+
+// batch_sizeのentryごとにまとめて書き込み、fsyncする
+// 不正なrecordはerrorsに記録して飛ばす. 読み込み自体に失敗した場合はerrorを返す
+pub fn import<R: Read>(
+    kvs: &mut Kvs,
+    r: R,
+    format: Format,
+    codec: Codec,
+    batch_size: usize,
+) -> Result<Report> {
+    let mut importer = Importer {
+        kvs,
+        codec,
+        batch_size: batch_size.max(1),
+        batch: Vec::new(),
+        report: Report::default(),
+    };
+
+    match format {
+        Format::JsonLines => {
+            for (i, line) in BufReader::new(r).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record = serde_json::from_str::<Record>(&line)
+                    .map_err(|err| KvsError::InvalidRecord(err.to_string()));
+                importer.add(i as u64 + 1, record)?;
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(r);
+            let headers = reader.headers().map_err(csv_error)?.clone();
+            let mut row = csv::StringRecord::new();
+            loop {
+                match reader.read_record(&mut row) {
+                    Ok(true) => (),
+                    Ok(false) => break,
+                    Err(err) if err.is_io_error() => return Err(csv_error(err)),
+                    Err(err) => {
+                        let line = err.position().map(|p| p.line()).unwrap_or_default();
+                        importer.add(line, Err(csv_error(err)))?;
+                        continue;
+                    }
+                }
+                let line = row.position().map(|p| p.line()).unwrap_or_default();
+                let record = row
+                    .deserialize::<CsvRecord>(Some(&headers))
+                    .map_err(csv_error)
+                    .and_then(|CsvRecord { key, value }| {
+                        Ok(Record {
+                            key,
+                            value: codec.parse(value)?,
+                        })
+                    });
+                importer.add(line, record)?;
+            }
+        }
+    }
+    importer.finish()
+}
+
+struct Importer<'a> {
+    kvs: &'a mut Kvs,
+    codec: Codec,
+    batch_size: usize,
+    batch: Vec<(String, Vec<u8>)>,
+    report: Report,
+}
+
+impl<'a> Importer<'a> {
+    fn add(&mut self, line: u64, record: Result<Record>) -> Result<()> {
+        let codec = self.codec;
+        match record.and_then(|record| Ok((record.key, codec.encode(record.value)?))) {
+            Ok(entry) => self.batch.push(entry),
+            Err(err) => self.report.errors.push(format!("line {}: {}", line, err)),
+        }
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let batch = std::mem::take(&mut self.batch);
+        let n = batch.len() as u64;
+        self.kvs.put_batch_raw(batch)?;
+        self.kvs.sync()?;
+        self.report.entries += n;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Report> {
+        self.flush()?;
+        Ok(self.report)
+    }
+}
+
+fn csv_error(err: csv::Error) -> KvsError {
+    if err.is_io_error() {
+        match err.into_kind() {
+            csv::ErrorKind::Io(err) => return err.into(),
+            _ => unreachable!(),
+        }
+    }
+    KvsError::InvalidRecord(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+
+    fn kvs(dir: &tempdir::TempDir, name: &str) -> StdResult<Kvs, Error> {
+        Ok(Kvs::new(dir.path().join(name))?)
+    }
+
+    #[test]
+    fn codec() -> StdResult<(), Error> {
+        let bytes = bincode::serialize("hello")?;
+        assert_eq!(Codec::String.decode(&bytes)?, Json::from("hello"));
+        assert_eq!(Codec::String.encode(Json::from("hello"))?, bytes);
+        assert!(Codec::String.encode(Json::from(1)).is_err());
+
+        let value = serde_json::json!({"id": 1, "done": false});
+        let bytes = Codec::Json.encode(value.clone())?;
+        assert_eq!(Codec::Json.decode(&bytes)?, value);
+        assert!(Codec::Json.decode(b"{").is_err());
+
+        let bytes = vec![0, 159, 146, 150, 255];
+        let value = Codec::Raw.decode(&bytes)?;
+        assert_eq!(Codec::Raw.encode(value)?, bytes);
+        assert!(Codec::Raw.encode(Json::from("***")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn export_import() -> StdResult<(), Error> {
+        let dir = tempdir::TempDir::new("")?;
+        let mut src = kvs(&dir, "src.kvs")?;
+        src.put("todo/2", &"buy milk".to_owned())?;
+        src.put("todo/1", &"write, \"docs\"\nand tests".to_owned())?;
+        src.put("deleted", &"x".to_owned())?;
+        src.delete::<String>("deleted")?;
+
+        for &format in &[Format::JsonLines, Format::Csv] {
+            let mut buf = Vec::new();
+            let report = export(&mut src, &mut buf, format, Codec::String)?;
+            assert_eq!(report.entries, 2);
+            assert!(report.errors.is_empty());
+
+            let mut dst = kvs(&dir, &format!("{:?}.kvs", format))?;
+            let report = import(&mut dst, buf.as_slice(), format, Codec::String, 1)?;
+            assert_eq!(report.entries, 2);
+            assert!(report.errors.is_empty());
+            assert_eq!(dst.get::<String>("todo/1")?, "write, \"docs\"\nand tests");
+            assert_eq!(dst.get::<String>("todo/2")?, "buy milk");
+            assert!(!dst.contains_key("deleted"));
+        }
+
+        let mut buf = Vec::new();
+        export(&mut src, &mut buf, Format::JsonLines, Codec::String)?;
+        assert_eq!(
+            String::from_utf8(buf)?.lines().next(),
+            Some(r#"{"key":"todo/1","value":"write, \"docs\"\nand tests"}"#)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn raw_roundtrip() -> StdResult<(), Error> {
+        let dir = tempdir::TempDir::new("")?;
+        let mut src = kvs(&dir, "src.kvs")?;
+        src.put_raw("bin", vec![0, 1, 2, 255])?;
+        src.put("text", &"hello".to_owned())?;
+
+        // stringとして読めないvalueはerrorとして報告する
+        let report = export(&mut src, Vec::new(), Format::JsonLines, Codec::String)?;
+        assert_eq!(report.entries, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with("key bin:"));
+
+        let mut buf = Vec::new();
+        export(&mut src, &mut buf, Format::Csv, Codec::Raw)?;
+        let mut dst = kvs(&dir, "dst.kvs")?;
+        import(&mut dst, buf.as_slice(), Format::Csv, Codec::Raw, 10)?;
+        assert_eq!(dst.get_raw("bin")?, vec![0, 1, 2, 255]);
+        assert_eq!(dst.get::<String>("text")?, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn import_errors() -> StdResult<(), Error> {
+        let dir = tempdir::TempDir::new("")?;
+        let mut dst = kvs(&dir, "dst.kvs")?;
+
+        let jsonl = r#"{"key":"a","value":{"n":1}}
+
+not json
+{"key":"b","value":[1,2]}
+{"key":"c"}
+"#;
+        let report = import(
+            &mut dst,
+            jsonl.as_bytes(),
+            Format::JsonLines,
+            Codec::Json,
+            2,
+        )?;
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[0].starts_with("line 3:"));
+        assert!(report.errors[1].starts_with("line 5:"));
+        assert_eq!(dst.get_raw("b")?, b"[1,2]".to_vec());
+
+        let csv = "key,value\nx,\"{\"\"n\"\":2}\"\ny,{broken\nz\n";
+        let report = import(&mut dst, csv.as_bytes(), Format::Csv, Codec::Json, 10)?;
+        assert_eq!(report.entries, 1);
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[0].starts_with("line 3:"));
+        assert!(report.errors[1].starts_with("line 4:"));
+        assert_eq!(dst.get_raw("x")?, br#"{"n":2}"#.to_vec());
+        Ok(())
+    }
+}
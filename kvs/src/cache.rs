@@ -0,0 +1,102 @@
+// 読み込んだvalueをkeyごとに保持するLRU cache. 同じkeyを繰り返しgetする場合にfileの読み込みを省く
+// 大きさはkeyとvalueのbytes数の合計で制限し、超えた場合は最も長く参照されていないkeyから捨てる
+use std::collections::{BTreeMap, HashMap};
+
+pub(crate) struct Cache {
+    capacity: usize,
+    bytes: usize,
+    // valueと最後に参照した時点
+    entries: HashMap<String, (Vec<u8>, u64)>,
+    // 参照した時点の古い順のkey
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Cache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            bytes: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+        Some(value.clone())
+    }
+
+    // capacityより大きいvalueは保持しない
+    pub(crate) fn insert(&mut self, key: &str, value: &[u8]) {
+        self.remove(key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        while self.bytes + size > self.capacity {
+            match self.order.values().next().cloned() {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.bytes += size;
+        self.entries
+            .insert(key.to_owned(), (value.to_vec(), self.tick));
+        self.order.insert(self.tick, key.to_owned());
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some((value, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.bytes -= key.len() + value.len();
+        }
+    }
+
+    #[cfg(test)]
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used() {
+        // keyとvalueで1件あたり4bytes
+        let mut cache = Cache::new(12);
+        cache.insert("a", &[1, 1, 1]);
+        cache.insert("b", &[2, 2, 2]);
+        cache.insert("c", &[3, 3, 3]);
+        assert_eq!(cache.bytes(), 12);
+
+        // 参照したaは残り、bが捨てられる
+        assert_eq!(cache.get("a"), Some(vec![1, 1, 1]));
+        cache.insert("d", &[4, 4, 4]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(vec![1, 1, 1]));
+        assert_eq!(cache.get("c"), Some(vec![3, 3, 3]));
+        assert_eq!(cache.bytes(), 12);
+
+        // 上書きは大きさを差し替える
+        cache.insert("d", &[4]);
+        assert_eq!(cache.bytes(), 10);
+        cache.remove("a");
+        cache.remove("none");
+        assert_eq!(cache.bytes(), 6);
+        assert_eq!(cache.get("a"), None);
+
+        // capacityを超えるvalueは保持しない
+        cache.insert("e", &[5; 12]);
+        assert_eq!(cache.get("e"), None);
+        assert_eq!(cache.get("c"), Some(vec![3, 3, 3]));
+    }
+}
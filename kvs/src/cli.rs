@@ -1,58 +1,197 @@
+pub mod shell;
+
+use crate::{KvsError, ValueFormat, Version};
+use std::io::{self, Write};
+
+// valueをformatで表現してstdoutに書き出す. rawの場合はbytesをそのまま書き、改行を付けない
+pub fn print_value(format: ValueFormat, value: &[u8]) -> Result<(), KvsError> {
+    let output = format.decode(value)?;
+    let mut stdout = io::stdout();
+    stdout.write_all(&output)?;
+    if format != ValueFormat::Raw {
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+// deleteで削除されたvalueを出力する
+// 削除は完了しているので、valueをformatで出力できない場合も警告のみで失敗にはしない
+pub fn print_deleted(format: ValueFormat, value: Option<Vec<u8>>) -> Result<(), KvsError> {
+    if let Some(value) = value {
+        if let Err(err) = print_value(format, &value) {
+            eprintln!("Failed to print deleted value: {}. try --format", err);
+        }
+    }
+    println!("Successfully deleted");
+    Ok(())
+}
+
+// keyの過去のvalueを1行に1versionずつ、logのoffsetとともに出力する
+pub fn print_history(format: ValueFormat, versions: &[Version]) -> Result<(), KvsError> {
+    let mut stdout = io::stdout();
+    for version in versions {
+        match &version.value {
+            Some(value) => writeln!(
+                stdout,
+                "offset: {} {}",
+                version.offset,
+                String::from_utf8_lossy(&format.decode(value)?)
+            )?,
+            None => writeln!(stdout, "offset: {} (deleted)", version.offset)?,
+        }
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
 pub mod server {
     use crate::Server;
+    use std::{future::Future, net::SocketAddr};
+
+    pub fn server_main(addr: String, server: Server) -> Result<(), crate::KvsError> {
+        block_on(server.run_until(addr, shutdown_signal()))
+    }
 
-    pub fn server_main(addr: String) -> Result<(), crate::KvsError> {
+    // HTTP/JSON gatewayのみ起動する
+    pub fn http_main(addr: SocketAddr, server: Server) -> Result<(), crate::KvsError> {
+        block_on(server.run_http_until(addr, shutdown_signal()))
+    }
+
+    fn block_on<F>(f: F) -> Result<(), crate::KvsError>
+    where
+        F: Future<Output = Result<(), crate::KvsError>>,
+    {
         tokio::runtime::Builder::new()
             .enable_all()
             .threaded_scheduler()
             .build()
             .unwrap()
             .block_on(async {
-                tracing_subscriber::FmtSubscriber::builder()
-                    .with_timer(tracing_subscriber::fmt::time::ChronoLocal::rfc3339())
-                    .with_target(true)
-                    .with_env_filter(
-                        std::env::var("KVS_LOG").unwrap_or_else(|_| "kvs=info".to_owned()),
-                    )
-                    .init();
-
-                // TODO handle signal
-                Server::new().run(addr).await
+                let _guard = crate::telemetry::init()?;
+                f.await
             })
     }
+
+    // SIGINT(ctrl-c)かSIGTERMを受け取るとcompleteする
+    #[cfg(unix)]
+    async fn shutdown_signal() {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM signal handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn shutdown_signal() {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install CTRL+C signal handler");
+    }
 }
 
 pub mod client {
-    use crate::protocol::message;
+    use crate::{ShardedClient, TlsConfig, ValueFormat};
+    use std::future::Future;
+
+    // serverに送る操作. valueはformatで表現する
+    #[derive(Debug)]
+    pub enum Command {
+        Echo {
+            message: String,
+        },
+        Get {
+            key: String,
+            format: ValueFormat,
+        },
+        Put {
+            key: String,
+            value: String,
+            format: ValueFormat,
+        },
+        Delete {
+            key: String,
+            format: ValueFormat,
+        },
+        Inspect {
+            key: String,
+        },
+    }
+
+    // nodesが複数の場合はkeyをconsistent hashで各serverに振り分ける
+    pub fn client_main(
+        nodes: Vec<String>,
+        vnodes: u32,
+        tls: TlsConfig,
+        token: Option<String>,
+        command: Command,
+    ) -> Result<(), crate::KvsError> {
+        block_on(async {
+            let mut client =
+                ShardedClient::connect(&nodes, vnodes, Some(&tls), token.as_deref()).await?;
+            tracing::info!(?nodes, tls = tls.is_enabled(), "Successfully connected");
 
-    pub fn client_main(addr: String) -> Result<(), crate::KvsError> {
+            match command {
+                Command::Echo { message } => {
+                    let message = client.echo(&message).await?;
+                    tracing::info!("Got response from server {}", message);
+                }
+                Command::Get { key, format } => {
+                    super::print_value(format, &client.get_raw(&key).await?)?
+                }
+                Command::Put { key, value, format } => {
+                    client.put_raw(key, format.encode(&value)?).await?
+                }
+                Command::Inspect { key } => {
+                    println!("{}", crate::inspect(&client.get_raw(&key).await?))
+                }
+                Command::Delete { key, format } => {
+                    super::print_deleted(format, client.delete_raw(&key).await?)?
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // fromの構成からtoの構成へ、担当が変わるkeyを移動する
+    pub fn rebalance_main(
+        from: Vec<String>,
+        to: Vec<String>,
+        vnodes: u32,
+        tls: TlsConfig,
+        token: Option<String>,
+        dry_run: bool,
+    ) -> Result<(), crate::KvsError> {
+        block_on(async {
+            let migrations =
+                crate::rebalance(&from, &to, vnodes, Some(&tls), token.as_deref(), dry_run).await?;
+            for m in &migrations {
+                println!("{} {} -> {}", m.key, m.from, m.to);
+            }
+            println!(
+                "{} {} keys",
+                if dry_run { "Would move" } else { "Moved" },
+                migrations.len()
+            );
+            Ok(())
+        })
+    }
+
+    fn block_on<F>(f: F) -> Result<(), crate::KvsError>
+    where
+        F: Future<Output = Result<(), crate::KvsError>>,
+    {
         tokio::runtime::Builder::new()
             .enable_all()
             .threaded_scheduler()
             .build()
             .unwrap()
             .block_on(async {
-                tracing_subscriber::FmtSubscriber::builder()
-                    .with_timer(tracing_subscriber::fmt::time::ChronoLocal::rfc3339())
-                    .with_target(true)
-                    .with_env_filter(
-                        std::env::var("KVS_LOG").unwrap_or_else(|_| "kvs=info".to_owned()),
-                    )
-                    .init();
-
-                let stream = tokio::net::TcpStream::connect(&addr).await?;
-                tracing::info!(?addr, "Successfully connected");
-
-                let mut operator = message::Operator::with_stream(stream)?;
-                let echo_request = message::Message::from_payload(message::Payload::EchoRequest {
-                    message: "Hello kvs!".to_owned(),
-                })?;
-                operator.send(echo_request).await?;
-
-                if let message::Payload::EchoResponse { message, .. } = operator.receive().await? {
-                    tracing::info!("Got response from server {}", message);
-                }
-                Ok(())
+                let _guard = crate::telemetry::init()?;
+                f.await
             })
     }
 }
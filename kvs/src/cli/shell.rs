@@ -0,0 +1,446 @@
+// 対話的にcommandを実行するREPL
+// localのfileの場合は起動時に1度だけindexを構築し、終了するまでfileを開いたままにする
+use crate::{Client, Kvs, KvsError, Stats, TlsConfig};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+use std::{cell::RefCell, env, path::PathBuf, rc::Rc};
+use tokio::runtime::Runtime;
+
+const COMMANDS: [&str; 7] = ["get", "put", "delete", "scan", "stats", "help", "exit"];
+
+// 2語目にkeyをとるcommand
+const KEY_COMMANDS: [&str; 4] = ["get", "put", "delete", "scan"];
+
+const HELP: &str = "\
+get <key>          print value of key
+put <key> <value>  put value. value is the rest of line
+delete <key>       delete key
+scan [prefix]      print keys starting with prefix and their values
+stats              print file size, live/stale bytes and number of keys
+exit               exit shell";
+
+// shellで操作するkvs
+pub enum Target {
+    Local(Box<Kvs>),
+    Remote {
+        addr: String,
+        tls: TlsConfig,
+        token: Option<String>,
+    },
+}
+
+// historyを指定しない場合は$HOME/.kvs_historyに保存する
+pub fn shell_main(target: Target, history: Option<PathBuf>) -> Result<(), KvsError> {
+    let history = history
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history")));
+    let backend = Rc::new(RefCell::new(Backend::open(target)?));
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper {
+        backend: backend.clone(),
+    }));
+    if let Some(history) = &history {
+        // 初回はfileが存在しない
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            // ctrl-cは入力中の行を破棄する
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.trim());
+        }
+        let command = match Command::parse(&line) {
+            Ok(Some(Command::Exit)) => break,
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(usage) => {
+                eprintln!("{}", usage);
+                continue;
+            }
+        };
+        if let Err(err) = backend.borrow_mut().execute(command) {
+            eprintln!("{}", describe(&err));
+        }
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
+
+// kvs commandのexit codeと同じ表記にする
+fn describe(err: &KvsError) -> String {
+    match err {
+        KvsError::NotFound => "Not Found".to_owned(),
+        KvsError::PermissionDenied => "Permission Denied".to_owned(),
+        KvsError::ReadOnly => "Read Only".to_owned(),
+        err => err.to_string(),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Get { key: String },
+    Put { key: String, value: String },
+    Delete { key: String },
+    Scan { prefix: String },
+    Stats,
+    Help,
+    Exit,
+}
+
+impl Command {
+    // 空行の場合はNoneを返す. 不正な入力の場合はusageを返す
+    fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim();
+        let (name, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim_start()),
+            None => (line, ""),
+        };
+        let args = rest.split_whitespace().collect::<Vec<_>>();
+        let usage = |args: &str| Err(format!("usage: {} {}", name, args));
+
+        let command = match (name, args.as_slice()) {
+            ("", _) => return Ok(None),
+            ("get", [key]) => Command::Get {
+                key: (*key).to_owned(),
+            },
+            ("get", _) => return usage("<key>"),
+            // valueは空白を含めて行の残り全てとする
+            ("put", [key, ..]) if args.len() > 1 => Command::Put {
+                key: (*key).to_owned(),
+                value: rest[key.len()..].trim_start().to_owned(),
+            },
+            ("put", _) => return usage("<key> <value>"),
+            ("delete", [key]) => Command::Delete {
+                key: (*key).to_owned(),
+            },
+            ("delete", _) => return usage("<key>"),
+            ("scan", []) => Command::Scan {
+                prefix: String::new(),
+            },
+            ("scan", [prefix]) => Command::Scan {
+                prefix: (*prefix).to_owned(),
+            },
+            ("scan", _) => return usage("[prefix]"),
+            ("stats", []) => Command::Stats,
+            ("help", _) => Command::Help,
+            ("exit", []) | ("quit", []) => Command::Exit,
+            _ => return Err(format!("unknown command: {}. type help for usage", name)),
+        };
+        Ok(Some(command))
+    }
+}
+
+enum Backend {
+    Local(Box<Kvs>),
+    // Clientはasyncなので、操作ごとにshellのthreadでruntimeをblockする
+    Remote {
+        runtime: Box<Runtime>,
+        client: Client,
+    },
+}
+
+impl Backend {
+    fn open(target: Target) -> Result<Self, KvsError> {
+        match target {
+            Target::Local(kvs) => Ok(Backend::Local(kvs)),
+            Target::Remote { addr, tls, token } => {
+                let mut runtime = tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()?;
+                let client = runtime.block_on(async {
+                    let mut client = Client::connect(&addr, Some(&tls)).await?;
+                    if let Some(token) = token {
+                        client.auth(&token).await?;
+                    }
+                    Ok::<_, KvsError>(client)
+                })?;
+                Ok(Backend::Remote {
+                    runtime: Box::new(runtime),
+                    client,
+                })
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<(), KvsError> {
+        match command {
+            Command::Get { key } => println!("{}", self.get(&key)?),
+            Command::Put { key, value } => self.put(key, value)?,
+            Command::Delete { key } => {
+                if let Some(value) = self.delete(&key)? {
+                    println!("{}", value);
+                }
+                println!("Successfully deleted");
+            }
+            Command::Scan { prefix } => {
+                for key in self.keys(&prefix)? {
+                    match self.get(&key) {
+                        Ok(value) => println!("{} {}", key, value),
+                        Err(KvsError::NotFound) => (),
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            Command::Stats => println!("{}", self.stats()?),
+            Command::Help => println!("{}", HELP),
+            Command::Exit => (),
+        }
+        Ok(())
+    }
+
+    // valueはkvs commandと同様にStringとして扱う
+    fn get(&mut self, key: &str) -> Result<String, KvsError> {
+        match self {
+            Backend::Local(kvs) => kvs.get(key),
+            Backend::Remote { runtime, client } => runtime.block_on(client.get(key)),
+        }
+    }
+
+    fn put(&mut self, key: String, value: String) -> Result<(), KvsError> {
+        match self {
+            Backend::Local(kvs) => kvs.put(key, &value),
+            Backend::Remote { runtime, client } => runtime.block_on(client.put(key, &value)),
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> Result<Option<String>, KvsError> {
+        match self {
+            Backend::Local(kvs) => kvs.delete(key),
+            Backend::Remote { runtime, client } => runtime.block_on(client.delete(key)),
+        }
+    }
+
+    // prefixにmatchするkeyをsortして返す
+    fn keys(&mut self, prefix: &str) -> Result<Vec<String>, KvsError> {
+        let mut keys = match self {
+            Backend::Local(kvs) => kvs
+                .owned_keys()
+                .filter(|key| key.starts_with(prefix))
+                .collect(),
+            Backend::Remote { runtime, client } => runtime.block_on(client.keys(prefix))?,
+        };
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn stats(&mut self) -> Result<Stats, KvsError> {
+        match self {
+            Backend::Local(kvs) => Ok(kvs.stats()),
+            Backend::Remote { runtime, client } => runtime.block_on(client.stats()),
+        }
+    }
+}
+
+// 1語目はcommand名、keyをとるcommandの2語目はkeyを補完する
+struct ShellHelper {
+    backend: Rc<RefCell<Backend>>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &line[start..];
+        let candidates = match line[..start]
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [] => COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| (*command).to_owned())
+                .collect(),
+            [command] if KEY_COMMANDS.contains(command) => {
+                // 補完の失敗で入力中の行を失わないよう、errorは候補なしとして扱う
+                self.backend.borrow_mut().keys(word).unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+        Ok((
+            start,
+            candidates
+                .into_iter()
+                .map(|candidate| Pair {
+                    display: candidate.clone(),
+                    replacement: candidate,
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use rustyline::history::History;
+    use std::result::Result as StdResult;
+
+    #[test]
+    fn parse() {
+        let parse = |line| Command::parse(line).unwrap();
+        assert_eq!(parse("  "), None);
+        assert_eq!(
+            parse("get key1"),
+            Some(Command::Get {
+                key: "key1".to_owned()
+            })
+        );
+        assert_eq!(
+            parse("put key1  hello  world "),
+            Some(Command::Put {
+                key: "key1".to_owned(),
+                value: "hello  world".to_owned()
+            })
+        );
+        assert_eq!(
+            parse("scan"),
+            Some(Command::Scan {
+                prefix: "".to_owned()
+            })
+        );
+        assert_eq!(parse("quit"), Some(Command::Exit));
+        assert!(Command::parse("get").is_err());
+        assert!(Command::parse("put key1").is_err());
+        assert!(Command::parse("delete a b").is_err());
+        assert!(Command::parse("select 1").is_err());
+    }
+
+    #[test]
+    fn complete() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+        for key in &["user/1", "user/2", "item/1"] {
+            kvs.put(*key, &"value".to_owned())?;
+        }
+        let helper = ShellHelper {
+            backend: Rc::new(RefCell::new(Backend::Local(Box::new(kvs)))),
+        };
+        let history = History::new();
+        let complete = |line: &str| {
+            let (start, candidates) = helper
+                .complete(line, line.len(), &Context::new(&history))
+                .unwrap();
+            let candidates = candidates
+                .into_iter()
+                .map(|pair| pair.replacement)
+                .collect::<Vec<_>>();
+            (start, candidates)
+        };
+
+        assert_eq!(
+            complete("s"),
+            (0, vec!["scan".to_owned(), "stats".to_owned()])
+        );
+        assert_eq!(
+            complete("get user/"),
+            (4, vec!["user/1".to_owned(), "user/2".to_owned()])
+        );
+        assert_eq!(complete("delete  i"), (8, vec!["item/1".to_owned()]));
+        assert_eq!(complete("put user/1 u"), (11, vec![]));
+        assert_eq!(complete("stats "), (6, vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn execute() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut backend = Backend::open(Target::Local(Box::new(Kvs::new(
+            tmp_dir.path().join("test.kvs"),
+        )?)))?;
+        backend.execute(Command::Put {
+            key: "key1".to_owned(),
+            value: "hello world".to_owned(),
+        })?;
+        assert_eq!(backend.get("key1")?, "hello world");
+        assert_eq!(backend.keys("key")?, vec!["key1".to_owned()]);
+        assert_eq!(backend.stats()?.keys, 1);
+        assert!(backend
+            .execute(Command::Get {
+                key: "key2".to_owned()
+            })
+            .unwrap_err()
+            .is_not_found());
+        backend.execute(Command::Delete {
+            key: "key1".to_owned(),
+        })?;
+        assert!(backend.keys("")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn remote() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?.to_string();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        // Backendはruntimeを内部で持つので、serverは別threadのruntimeで動かす
+        let server = std::thread::spawn(move || {
+            let mut runtime = Runtime::new()?;
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                crate::Server::new(kvs)
+                    .serve(listener, async {
+                        shutdown_rx.await.ok();
+                    })
+                    .await
+            })
+        });
+
+        let mut backend = Backend::open(Target::Remote {
+            addr,
+            tls: TlsConfig::default(),
+            token: None,
+        })?;
+        backend.put("user/1".to_owned(), "alice".to_owned())?;
+        backend.put("user/2".to_owned(), "bob".to_owned())?;
+        assert_eq!(backend.get("user/2")?, "bob");
+        assert_eq!(
+            backend.keys("user/")?,
+            vec!["user/1".to_owned(), "user/2".to_owned()]
+        );
+        assert_eq!(backend.delete("user/1")?, Some("alice".to_owned()));
+        let stats = backend.stats()?;
+        assert_eq!(stats.keys, 1);
+        assert_eq!(stats.puts, 2);
+
+        drop(backend);
+        shutdown_tx.send(()).unwrap();
+        server.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use crate::{
+    protocol::message::{Message, Operator, Payload},
+    tls, KvsError, Result, Stats, TlsConfig,
+};
+
+// kvs serverのclient
+pub struct Client {
+    operator: Operator,
+}
+
+impl Client {
+    pub async fn connect(addr: &str, tls: Option<&TlsConfig>) -> Result<Self> {
+        Ok(Self {
+            operator: tls::connect(addr, tls).await?,
+        })
+    }
+
+    // serverにACLが設定されている場合は最初にtokenで認証する
+    pub async fn auth(&mut self, token: &str) -> Result<()> {
+        match self
+            .request(Payload::AuthRequest {
+                token: token.to_owned(),
+            })
+            .await?
+        {
+            Payload::AuthResponse => Ok(()),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    pub async fn echo(&mut self, message: &str) -> Result<String> {
+        match self
+            .request(Payload::EchoRequest {
+                message: message.to_owned(),
+            })
+            .await?
+        {
+            Payload::EchoResponse { message } => Ok(message),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    pub async fn put<K, T>(&mut self, key: K, value: &T) -> Result<()>
+    where
+        K: Into<String>,
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let bytes = bincode::serialize(value)?;
+        self.put_raw(key, bytes).await
+    }
+
+    pub async fn get<T>(&mut self, key: &str) -> Result<T>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let bytes = self.get_raw(key).await?;
+        bincode::deserialize::<T>(bytes.as_slice()).map_err(KvsError::from)
+    }
+
+    pub async fn delete<T>(&mut self, key: &str) -> Result<Option<T>>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        match self.delete_raw(key).await? {
+            Some(bytes) => Ok(Some(bincode::deserialize::<T>(bytes.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn put_raw<K: Into<String>>(&mut self, key: K, value: Vec<u8>) -> Result<()> {
+        match self
+            .request(Payload::PutRequest {
+                key: key.into(),
+                value,
+            })
+            .await?
+        {
+            Payload::PutResponse => Ok(()),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    pub async fn get_raw(&mut self, key: &str) -> Result<Vec<u8>> {
+        match self
+            .request(Payload::GetRequest {
+                key: key.to_owned(),
+            })
+            .await?
+        {
+            Payload::GetResponse { value } => Ok(value),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    pub async fn delete_raw(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self
+            .request(Payload::DeleteRequest {
+                key: key.to_owned(),
+            })
+            .await?
+        {
+            Payload::DeleteResponse { value } => Ok(value),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    // prefixにmatchし、getが許可されているkeyを返す
+    pub async fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        match self
+            .request(Payload::KeysRequest {
+                prefix: prefix.to_owned(),
+            })
+            .await?
+        {
+            Payload::KeysResponse { keys } => Ok(keys),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    // serverのfileとindexの状態、および起動以降の操作の件数を返す
+    pub async fn stats(&mut self) -> Result<Stats> {
+        match self.request(Payload::StatsRequest).await? {
+            Payload::StatsResponse { stats } => Ok(stats),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    // offset以降のlogの送信をserverに要求する. 以降はreceive_logでlogを受け取る
+    pub(crate) async fn replicate(&mut self, offset: u64) -> Result<()> {
+        self.operator
+            .send(Message::from_payload(Payload::ReplicateRequest { offset })?)
+            .await
+    }
+
+    // logの開始offsetとlogを返す
+    pub(crate) async fn receive_log(&mut self) -> Result<(u64, Vec<u8>)> {
+        match self.operator.receive().await? {
+            Payload::ReplicateResponse { offset, log } => Ok((offset, log)),
+            Payload::ErrorResponse { kind, message } => Err(kind.into_error(message)),
+            payload => Err(unexpected(payload)),
+        }
+    }
+
+    // Raftのmessageを送る. 応答は待たない
+    pub(crate) async fn send_raft(&mut self, message: Vec<u8>) -> Result<()> {
+        self.operator
+            .send(Message::from_payload(Payload::RaftMessage { message })?)
+            .await
+    }
+
+    // ErrorResponseはKvsErrorに変換して返す
+    async fn request(&mut self, payload: Payload) -> Result<Payload> {
+        self.operator.send(Message::from_payload(payload)?).await?;
+        match self.operator.receive().await? {
+            Payload::ErrorResponse { kind, message } => Err(kind.into_error(message)),
+            payload => Ok(payload),
+        }
+    }
+}
+
+fn unexpected(payload: Payload) -> KvsError {
+    KvsError::InvalidPayloadKind(payload.kind() as u8)
+}
@@ -0,0 +1,154 @@
+// kvsのfileを先頭からentryごとにdecodeし、offsetとcrcの検証結果とともに書き出す
+// indexを構築しないので、壊れたfileも途中まで読める
+use crate::{entry::Entry, Result};
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Clone, Default)]
+pub struct DumpFilter {
+    // keyが一致するentryのみ書き出す
+    pub key: Option<String>,
+    // offsetがfrom以上to未満のentryのみ書き出す
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl DumpFilter {
+    fn matches(&self, offset: u64, entry: &Entry) -> bool {
+        self.key.as_ref().is_none_or(|key| *key == entry.key)
+            && self.from.is_none_or(|from| offset >= from)
+            && self.to.is_none_or(|to| offset < to)
+    }
+}
+
+// 書き出したentryの集計
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DumpSummary {
+    pub entries: u64,
+    pub deleted: u64,
+    // crcが一致しないentry
+    pub corrupt: u64,
+    // decodeできなかった位置と理由. 以降のentryは読めない
+    pub error: Option<String>,
+}
+
+// 1行に1entryを"offset: {} len: {} crc: ok|ng {:?}"の形式で書き出す
+pub fn dump<R: BufRead, W: Write>(mut r: R, mut w: W, filter: &DumpFilter) -> Result<DumpSummary> {
+    let mut summary = DumpSummary::default();
+    let mut offset = 0_u64;
+    loop {
+        if filter.to.is_some_and(|to| offset >= to) || r.fill_buf()?.is_empty() {
+            break;
+        }
+        let entry = match Entry::decode(&mut r) {
+            Ok(entry) => entry,
+            Err(err) => {
+                let err = format!("offset {}: {}", offset, err);
+                writeln!(w, "{}", err)?;
+                summary.error = Some(err);
+                break;
+            }
+        };
+        let len = entry.len() as u64;
+        if filter.matches(offset, &entry) {
+            let valid = entry.is_valid()?;
+            writeln!(
+                w,
+                "offset: {} len: {} crc: {} {:?}",
+                offset,
+                len,
+                if valid { "ok" } else { "ng" },
+                entry
+            )?;
+            summary.entries += 1;
+            summary.deleted += entry.is_deleted() as u64;
+            summary.corrupt += !valid as u64;
+        }
+        offset += len;
+    }
+    w.flush()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+
+    fn file(entries: &[Entry]) -> StdResult<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            entry.encode(&mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    fn dump_string(buf: &[u8], filter: &DumpFilter) -> StdResult<(String, DumpSummary), Error> {
+        let mut out = Vec::new();
+        let summary = dump(buf, &mut out, filter)?;
+        Ok((String::from_utf8(out)?, summary))
+    }
+
+    #[test]
+    fn dump_entries() -> StdResult<(), Error> {
+        let one = Entry::new("1", b"one".to_vec())?;
+        let two = Entry::new("2", vec![0; 40])?;
+        let buf = file(&[one.clone(), two.clone(), one.mark_delete()?])?;
+
+        let (out, summary) = dump_string(&buf, &DumpFilter::default())?;
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            format!("offset: 0 len: {} crc: ok {:?}", one.len(), one)
+        );
+        assert!(lines[0].contains("|state: Active|") && lines[0].ends_with("|value: \"one\"|"));
+        // valueは先頭のみ表示する
+        assert!(lines[1].starts_with(&format!("offset: {} ", one.len())));
+        assert!(lines[1].ends_with(&format!("|value: {:?}...|", "\0".repeat(32))));
+        assert!(lines[2].contains("|state: Deleted|"));
+        assert_eq!(
+            summary,
+            DumpSummary {
+                entries: 3,
+                deleted: 1,
+                corrupt: 0,
+                error: None,
+            }
+        );
+
+        let filter = DumpFilter {
+            key: Some("1".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(dump_string(&buf, &filter)?.1.entries, 2);
+        let filter = DumpFilter {
+            from: Some(one.len() as u64),
+            to: Some((one.len() + two.len()) as u64),
+            ..Default::default()
+        };
+        let (out, summary) = dump_string(&buf, &filter)?;
+        assert_eq!(summary.entries, 1);
+        assert!(out.contains("|key: 2|"));
+        Ok(())
+    }
+
+    #[test]
+    fn dump_broken() -> StdResult<(), Error> {
+        let one = Entry::new("1", b"one".to_vec())?;
+        let mut buf = file(&[one.clone(), one.clone(), one.clone()])?;
+        // 2つめのentryのvalueを書き換え、3つめのentryを途中で切る
+        let n = one.len();
+        buf[2 * n - 1] = b'x';
+        buf.truncate(3 * n - 2);
+
+        let (out, summary) = dump_string(&buf, &DumpFilter::default())?;
+        assert!(out.lines().nth(1).unwrap().contains(" crc: ng "));
+        assert_eq!(summary.entries, 2);
+        assert_eq!(summary.corrupt, 1);
+        let err = summary.error.unwrap();
+        assert!(err.starts_with(&format!("offset {}: ", 2 * n)), "{}", err);
+        assert!(out.ends_with(&format!("{}\n", err)));
+        Ok(())
+    }
+}
@@ -1,13 +1,17 @@
 use crate::{
-    entry::{self, Entry},
+    cache::Cache,
+    entry::{self, Entry, Location},
     error::KvsError,
-    Result,
+    metrics::{EngineMetrics, Stats},
+    Result, Storage,
 };
-use std::io::BufReader;
+use std::io::{BufReader, Cursor};
 use std::{
     collections,
-    io::{BufWriter, Read, Seek, SeekFrom::*, Write},
+    io::{Seek, SeekFrom::*},
+    time::Instant,
 };
+use tracing::{debug_span, error, field, warn};
 
 pub struct Engine<F> {
     file: F,
@@ -15,20 +19,34 @@ pub struct Engine<F> {
     position: u64,
     // entryをdecodeする際に利用するBufReaderのbuffer sizeに利用する
     last_entry_len: usize,
+    // indexから参照されているentryのbytesの合計
+    live_bytes: u64,
+    // 有効な場合、getで読み込んだvalueを保持する. put/deleteしたkeyは取り除く
+    cache: Option<Cache>,
+    metrics: EngineMetrics,
+    // 書き込みに失敗し、末尾の途中まで書いたbytesを取り除けなかった
+    // positionとlogの末尾がずれているので、開き直すまで書き込みを拒否する
+    failed: bool,
 }
 
 impl<F> Engine<F>
 where
-    F: Read + Write + Seek,
+    F: Storage,
 {
     pub(crate) fn new(mut file: F) -> Result<Self> {
-        let index = entry::KeyIndex::construct_from(&mut file)?;
-        let position = file.seek(Current(0))?;
+        let (index, position) = entry::KeyIndex::construct_from(&mut file)?;
+        // 末尾に不完全なentryがある場合は、その手前から追記する
+        let position = file.seek(Start(position as u64))?;
+        let live_bytes = index.0.values().map(|location| location.len as u64).sum();
         Ok(Self {
             file,
             index,
             position,
             last_entry_len: 0,
+            live_bytes,
+            cache: None,
+            metrics: EngineMetrics::default(),
+            failed: false,
         })
     }
 
@@ -36,33 +54,155 @@ where
     where
         K: Into<String>,
     {
-        self.put_entry(Entry::new(key, value)?, true)
+        let key = key.into();
+        let span = debug_span!(
+            "engine",
+            op = "put",
+            key = key.as_str(),
+            value_bytes = value.len()
+        );
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let result = Entry::new(key, value).and_then(|entry| self.put_entry(entry, true));
+        self.metrics.put.observe(start.elapsed());
+        result
     }
 
     fn put_entry(&mut self, entry: Entry, update_index: bool) -> Result<()> {
-        let mut w = BufWriter::with_capacity(entry.len(), &mut self.file);
-        let n = entry.encode(&mut w)?;
-        w.flush()?;
+        self.invalidate(&entry.key);
+        let mut buf = Vec::with_capacity(entry.len());
+        let n = entry.encode(&mut buf)?;
         debug_assert_eq!(entry.len(), n, "decoded bytes does not match");
+        self.append(&buf)?;
 
         if update_index {
-            self.index.0.insert(entry.key, self.position as usize);
+            let location = Location {
+                offset: self.position as usize,
+                len: n,
+            };
+            self.live_bytes += n as u64;
+            if let Some(old) = self.index.0.insert(entry.key, location) {
+                self.live_bytes -= old.len as u64;
+            }
         }
         self.position += n as u64;
 
         Ok(())
     }
 
+    // 複数のentryをまとめてencodeし、1度の書き込みで追記する
+    pub(crate) fn put_batch(&mut self, batch: Vec<(String, Vec<u8>)>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let span = debug_span!("engine", op = "put_batch", entries = batch.len());
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let n = batch.len() as u32;
+        let mut buf = Vec::new();
+        let mut locations = Vec::with_capacity(batch.len());
+        for (key, value) in batch {
+            let entry = Entry::new(key, value)?;
+            let offset = self.position as usize + buf.len();
+            let len = entry.encode(&mut buf)?;
+            locations.push((entry.key, Location { offset, len }));
+        }
+        self.append(&buf)?;
+
+        for (key, location) in locations {
+            self.invalidate(&key);
+            self.live_bytes += location.len as u64;
+            if let Some(old) = self.index.0.insert(key, location) {
+                self.live_bytes -= old.len as u64;
+            }
+        }
+        self.position += buf.len() as u64;
+
+        // entryごとのlatencyは均等に按分する
+        let elapsed = start.elapsed() / n;
+        (0..n).for_each(|_| self.metrics.put.observe(elapsed));
+        Ok(())
+    }
+
+    // bufをlogの末尾に追記する. 失敗した場合は途中まで書き込まれたbytesを切り詰めてpositionに揃える
+    // 残したまま追記を続けると、以降のentryのoffsetがずれて読み込めなくなる
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        if self.failed {
+            return Err(KvsError::WriteFailed);
+        }
+        let result = self.file.write_all(buf).and_then(|_| self.file.flush());
+        if let Err(err) = result {
+            let position = self.position;
+            let restored = self
+                .file
+                .set_len(position)
+                .and_then(|_| self.file.seek(Start(position)));
+            if let Err(restore_err) = restored {
+                error!(%err, %restore_err, "Failed to truncate partially written entry");
+                self.failed = true;
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
     pub(crate) fn get<K>(&mut self, key: K) -> Result<Vec<u8>>
     where
         K: AsRef<str>,
     {
-        self.get_entry(key.as_ref()).map(|entry| entry.value)
+        let span = debug_span!(
+            "engine",
+            op = "get",
+            key = key.as_ref(),
+            value_bytes = field::Empty
+        );
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let result = self.get_cached(key.as_ref());
+        self.metrics.get.observe(start.elapsed());
+        if let Ok(value) = &result {
+            span.record("value_bytes", value.len());
+        }
+        result
+    }
+
+    fn get_cached(&mut self, key: &str) -> Result<Vec<u8>> {
+        if let Some(cache) = self.cache.as_mut() {
+            if let Some(value) = cache.get(key) {
+                self.metrics.cache_hits.inc();
+                return Ok(value);
+            }
+            self.metrics.cache_misses.inc();
+        }
+        let value = self.get_entry(key)?.value;
+        if let Some(cache) = self.cache.as_mut() {
+            cache.insert(key, &value);
+        }
+        Ok(value)
     }
 
-    fn get_entry(&mut self, key: &str) -> Result<Entry> {
-        if let Some(&offset) = self.index.0.get(key) {
-            self.file.seek(Start(offset as u64))?;
+    // bytesが0の場合はcacheを無効にする. 変更すると保持していたvalueは捨てる
+    pub(crate) fn set_cache_capacity(&mut self, bytes: usize) {
+        self.cache = if bytes == 0 {
+            None
+        } else {
+            Some(Cache::new(bytes))
+        };
+    }
+
+    fn invalidate(&mut self, key: &str) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove(key);
+        }
+    }
+
+    // metricsとcacheには数えない. indexの構築など内部での読み込みに利用する
+    pub(crate) fn get_entry(&mut self, key: &str) -> Result<Entry> {
+        if let Some(location) = self.index.0.get(key) {
+            self.file.seek(Start(location.offset as u64))?;
             let mut r = BufReader::with_capacity(self.last_entry_len, &mut self.file);
             let entry = Entry::decode_with_check(&mut r)?;
             r.seek(Start(self.position))?;
@@ -79,8 +219,15 @@ where
     where
         K: AsRef<str>,
     {
-        self.delete_entry(key.as_ref())
-            .map(|opt| opt.map(|entry| entry.value))
+        let span = debug_span!("engine", op = "delete", key = key.as_ref());
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let result = self
+            .delete_entry(key.as_ref())
+            .map(|opt| opt.map(|entry| entry.value));
+        self.metrics.delete.observe(start.elapsed());
+        result
     }
 
     fn delete_entry(&mut self, key: &str) -> Result<Option<Entry>> {
@@ -93,16 +240,167 @@ where
         self.put_entry(entry.mark_delete()?, false)?;
 
         // remove from index
-        self.index.0.remove(key);
+        self.remove_index(key);
         Ok(Some(entry))
     }
 
+    fn remove_index(&mut self, key: &str) {
+        if let Some(location) = self.index.0.remove(key) {
+            self.live_bytes -= location.len as u64;
+        }
+    }
+
+    // logの末尾のoffset. replicationではfollowerが次に受け取るentryのoffsetとして利用する
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    // offsetから始まるentryをencodeされたまま返す
+    // max_bytesを超えた時点で読み込みをやめる. offsetがentryの境界でない場合はエラーになる
+    pub(crate) fn read_log(&mut self, offset: u64, max_bytes: usize) -> Result<Vec<u8>> {
+        if offset > self.position {
+            return Err(KvsError::InvalidOffset(offset));
+        }
+        let mut log = Vec::new();
+        self.file.seek(Start(offset))?;
+        let mut r = BufReader::new(&mut self.file);
+        let mut position = offset;
+        while position < self.position && log.len() < max_bytes {
+            let entry = Entry::decode_with_check(&mut r)?;
+            position += entry.encode(&mut log)? as u64;
+        }
+        r.seek(Start(self.position))?;
+        Ok(log)
+    }
+
+    // keyのentryをlogの先頭から順にすべて返す. 削除されたentryはvalueがNoneになる
+    // logを全て読むので、keyの数やfileの大きさに比例して時間がかかる
+    pub(crate) fn history(&mut self, key: &str) -> Result<Vec<Version>> {
+        let span = debug_span!("engine", op = "history", key = key, versions = field::Empty);
+        let _enter = span.enter();
+
+        let mut versions = Vec::new();
+        self.file.seek(Start(0))?;
+        let mut r = BufReader::new(&mut self.file);
+        let mut position = 0;
+        while position < self.position {
+            let entry = Entry::decode_with_check(&mut r)?;
+            let offset = position;
+            position += entry.len() as u64;
+            if entry.key == key {
+                versions.push(Version {
+                    offset,
+                    value: if entry.is_deleted() {
+                        None
+                    } else {
+                        Some(entry.value)
+                    },
+                });
+            }
+        }
+        r.seek(Start(self.position))?;
+        span.record("versions", versions.len());
+        Ok(versions)
+    }
+
+    // read_logで読み込んだlogを検証して追記し、indexに反映する
+    pub(crate) fn apply_log(&mut self, log: &[u8]) -> Result<()> {
+        let mut r = Cursor::new(log);
+        while (r.position() as usize) < log.len() {
+            let entry = Entry::decode_with_check(&mut r)?;
+            if entry.is_deleted() {
+                let key = entry.key.clone();
+                self.put_entry(entry, false)?;
+                self.remove_index(&key);
+            } else {
+                self.put_entry(entry, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    // 他のprocessがpositionより後に追記したentryを読み込み、indexに反映する
+    // 書き込み途中のentryは読み込まず、次回のrefreshで読み込む. 反映したentryのkeyを返す
+    pub(crate) fn refresh(&mut self) -> Result<Vec<String>> {
+        let span = debug_span!("engine", op = "refresh", entries = field::Empty);
+        let _enter = span.enter();
+
+        let mut keys = Vec::new();
+        self.file.seek(Start(self.position))?;
+        let mut r = BufReader::new(&mut self.file);
+        let mut position = self.position;
+        let mut locations = Vec::new();
+        let err = loop {
+            match Entry::decode(&mut r) {
+                Ok(entry) => {
+                    let len = entry.len();
+                    let location = if entry.is_deleted() {
+                        None
+                    } else {
+                        Some(Location {
+                            offset: position as usize,
+                            len,
+                        })
+                    };
+                    locations.push((entry.key, location));
+                    position += len as u64;
+                }
+                Err(err) => break err,
+            }
+        };
+        if !err.is_eof() {
+            return Err(err);
+        }
+        r.seek(Start(position))?;
+
+        for (key, location) in locations {
+            self.invalidate(&key);
+            match location {
+                Some(location) => {
+                    self.live_bytes += location.len as u64;
+                    if let Some(old) = self.index.0.insert(key.clone(), location) {
+                        self.live_bytes -= old.len as u64;
+                    }
+                }
+                None => self.remove_index(&key),
+            }
+            keys.push(key);
+        }
+        self.position = position;
+        span.record("entries", keys.len());
+        Ok(keys)
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.index.0.contains_key(key)
+    }
+
     pub(crate) fn keys(&self) -> Keys<'_> {
         Keys {
             inner: self.index.0.iter(),
         }
     }
 
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            file_bytes: self.position,
+            live_bytes: self.live_bytes,
+            stale_bytes: self.position - self.live_bytes,
+            keys: self.index.0.len() as u64,
+            gets: self.metrics.get.count(),
+            puts: self.metrics.put.count(),
+            deletes: self.metrics.delete.count(),
+            cache_hits: self.metrics.cache_hits.get(),
+            cache_misses: self.metrics.cache_misses.get(),
+            filter_hits: self.metrics.filter_hits.get(),
+            filter_false_positives: self.metrics.filter_false_positives.get(),
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> &EngineMetrics {
+        &self.metrics
+    }
+
     #[cfg(test)]
     fn dump(&mut self, buf: &mut [u8]) -> Result<()> {
         self.file.seek(Start(0))?;
@@ -111,8 +409,40 @@ where
     }
 }
 
+impl<F: Storage> Engine<F> {
+    // 書き込み途中でcrashした際に末尾に残った不完全なentryを切り詰める
+    // 残したまま追記すると、以降のentryをdecodeできなくなる
+    pub(crate) fn truncate_incomplete(&mut self) -> Result<()> {
+        let len = self.file.seek(End(0))?;
+        if len > self.position {
+            warn!(
+                bytes = len - self.position,
+                "Truncate incomplete entry at the end of log"
+            );
+            self.file.set_len(self.position)?;
+        }
+        self.file.seek(Start(self.position))?;
+        Ok(())
+    }
+
+    // bufferの内容を書き出したうえで、storageの永続化を保証する. fileの場合はfsyncする
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.file.sync()?;
+        Ok(())
+    }
+}
+
+// history()で返すkeyの過去のentry
+// entryはtimestampを持たないので、logのoffsetの順序が書き込まれた順序になる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version<T = Vec<u8>> {
+    pub offset: u64,
+    // 削除された場合はNone
+    pub value: Option<T>,
+}
+
 pub struct Keys<'a> {
-    inner: collections::hash_map::Iter<'a, String, usize>,
+    inner: collections::hash_map::Iter<'a, String, Location>,
 }
 
 impl<'a> Iterator for Keys<'a> {
@@ -123,6 +453,27 @@ impl<'a> Iterator for Keys<'a> {
     }
 }
 
+// keyを順不同で所有したStringとして返す. lsm engineはkeyをmemory上に持たないので、tableから読み込んだkeyを返す
+pub struct OwnedKeys<'a> {
+    inner: Box<dyn Iterator<Item = String> + 'a>,
+}
+
+impl<'a> OwnedKeys<'a> {
+    pub(crate) fn new(inner: impl Iterator<Item = String> + 'a) -> Self {
+        Self {
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl<'a> Iterator for OwnedKeys<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::entry::*;
@@ -135,7 +486,7 @@ mod tests {
 
     #[test]
     fn put_and_get() -> StdResult<(), Error> {
-        let entries = vec![
+        let entries = [
             Entry::new("1", vec![b'1'])?,
             Entry::new("2", vec![b'2', b'2'])?,
             Entry::new("3", vec![b'3', b'3', b'3'])?,
@@ -193,6 +544,192 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn stats() -> StdResult<(), Error> {
+        let mut kvs = in_memory_kvs();
+        let (one, two) = (Entry::new("1", vec![b'1'])?, Entry::new("2", vec![b'2'])?);
+
+        kvs.put("1", vec![b'1'])?;
+        kvs.put("2", vec![b'2'])?;
+        kvs.put("1", vec![b'1'])?;
+        kvs.delete("2")?;
+        kvs.get("1")?;
+
+        let stats = kvs.stats();
+        assert_eq!(stats.keys, 1);
+        assert_eq!(stats.live_bytes, one.len() as u64);
+        // 上書き前の"1", "2"と"2"の削除entry
+        assert_eq!(
+            stats.stale_bytes,
+            (one.len() + two.len() + two.mark_delete()?.len()) as u64
+        );
+        assert_eq!(stats.file_bytes, stats.live_bytes + stats.stale_bytes);
+        // deleteは内部でentryを読むが、getとしては数えない
+        assert_eq!((stats.gets, stats.puts, stats.deletes), (1, 3, 1));
+
+        // 再度開いても同じ値になる
+        let restored = dump_and_restore(kvs).stats();
+        assert_eq!(
+            (restored.file_bytes, restored.live_bytes, restored.keys),
+            (stats.file_bytes, stats.live_bytes, stats.keys)
+        );
+        assert_eq!(restored.gets, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache() -> StdResult<(), Error> {
+        let mut kvs = in_memory_kvs();
+        kvs.set_cache_capacity(1024);
+        kvs.put("1", vec![b'1'])?;
+
+        assert_eq!(kvs.get("1")?, vec![b'1']);
+        assert_eq!(kvs.get("1")?, vec![b'1']);
+        // 上書き、削除、replicationで適用したkeyはcacheから取り除かれる
+        kvs.put("1", vec![b'2'])?;
+        assert_eq!(kvs.get("1")?, vec![b'2']);
+        kvs.put_batch(vec![("1".to_owned(), vec![b'3'])])?;
+        assert_eq!(kvs.get("1")?, vec![b'3']);
+        let mut log = Vec::new();
+        Entry::new("1", vec![b'4'])?.encode(&mut log)?;
+        kvs.apply_log(&log)?;
+        assert_eq!(kvs.get("1")?, vec![b'4']);
+        kvs.delete("1")?;
+        assert!(kvs.get("1").unwrap_err().is_not_found());
+
+        let stats = kvs.stats();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 5));
+
+        // 無効にするとhit/missを数えない
+        kvs.set_cache_capacity(0);
+        kvs.put("2", vec![b'2'])?;
+        kvs.get("2")?;
+        kvs.get("2")?;
+        assert_eq!(kvs.stats().cache_misses, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn put_batch() -> StdResult<(), Error> {
+        let mut kvs = in_memory_kvs();
+        kvs.put("1", vec![b'1'])?;
+        kvs.put_batch(vec![
+            ("2".to_owned(), vec![b'2']),
+            ("1".to_owned(), vec![b'x']),
+            ("3".to_owned(), vec![b'3']),
+            ("2".to_owned(), vec![b'y']),
+        ])?;
+        kvs.put_batch(Vec::new())?;
+
+        // 同じkeyは後のvalueが残る
+        assert_eq!(kvs.get("1")?, vec![b'x']);
+        assert_eq!(kvs.get("2")?, vec![b'y']);
+        assert_eq!(kvs.get("3")?, vec![b'3']);
+        let stats = kvs.stats();
+        assert_eq!((stats.keys, stats.puts), (3, 5));
+
+        let mut restored = dump_and_restore(kvs);
+        assert_eq!(restored.get("2")?, vec![b'y']);
+        assert_eq!(restored.stats().live_bytes, stats.live_bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn history() -> StdResult<(), Error> {
+        let mut kvs = in_memory_kvs();
+        let one = Entry::new("1", vec![b'1'])?;
+        kvs.put("1", vec![b'1'])?;
+        kvs.put("2", vec![b'2'])?;
+        kvs.put("1", vec![b'x'])?;
+        kvs.delete("1")?;
+        kvs.put("1", vec![b'y'])?;
+
+        let version = |offset: usize, value: Option<u8>| Version {
+            offset: offset as u64,
+            value: value.map(|v| vec![v]),
+        };
+        let expected = vec![
+            version(0, Some(b'1')),
+            version(2 * one.len(), Some(b'x')),
+            version(3 * one.len(), None),
+            version(3 * one.len() + one.mark_delete()?.len(), Some(b'y')),
+        ];
+        assert_eq!(kvs.history("1")?, expected);
+        assert!(kvs.history("3")?.is_empty());
+        // historyの後も追記できる
+        kvs.put("3", vec![b'3'])?;
+        assert_eq!(kvs.get("3")?, vec![b'3']);
+
+        let mut kvs = dump_and_restore(kvs);
+        assert_eq!(kvs.history("1")?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn refresh() -> StdResult<(), Error> {
+        let mut writer = in_memory_kvs();
+        writer.put("1", vec![b'1'])?;
+        let mut reader = Engine::new(Cursor::new(writer.file.get_ref().clone()))?;
+        assert!(reader.refresh()?.is_empty());
+
+        writer.put("2", vec![b'2'])?;
+        writer.delete("1")?;
+        writer.put("3", vec![b'3'])?;
+        let position = writer.position() as usize;
+        writer.put("4", vec![b'4'])?;
+        // 最後のentryは書き込み途中の状態にする
+        copy_file(&writer, &mut reader, writer.position() as usize - 1);
+
+        assert_eq!(reader.refresh()?, vec!["2", "1", "3"]);
+        assert!(reader.get("1").unwrap_err().is_not_found());
+        assert_eq!(reader.get("2")?, vec![b'2']);
+        assert!(reader.get("4").unwrap_err().is_not_found());
+        assert_eq!(reader.position(), position as u64);
+
+        copy_file(&writer, &mut reader, writer.position() as usize);
+        assert_eq!(reader.refresh()?, vec!["4"]);
+        assert_eq!(reader.get("4")?, vec![b'4']);
+        assert_eq!(reader.index.0, writer.index.0);
+        assert_eq!(reader.stats().live_bytes, writer.stats().live_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn replicate_log() -> StdResult<(), Error> {
+        let mut leader = in_memory_kvs();
+        let mut follower = in_memory_kvs();
+
+        leader.put("1", vec![b'1'])?;
+        leader.put("2", vec![b'2'])?;
+        // max_bytesを超えた時点で区切られる
+        let log = leader.read_log(0, 1)?;
+        follower.apply_log(&log)?;
+        assert_eq!(follower.get("1")?, vec![b'1']);
+        assert!(follower.get("2").unwrap_err().is_not_found());
+
+        leader.delete("1")?;
+        let log = leader.read_log(follower.position(), 1024)?;
+        follower.apply_log(&log)?;
+        assert!(follower.get("1").unwrap_err().is_not_found());
+        assert_eq!(follower.get("2")?, vec![b'2']);
+        assert_eq!(follower.position(), leader.position());
+        assert!(leader.read_log(leader.position(), 1024)?.is_empty());
+        assert!(leader.read_log(leader.position() + 1, 1024).is_err());
+
+        // followerのlogはleaderと同じ内容になる
+        let follower = dump_and_restore(follower);
+        assert_eq!(follower.index.0, leader.index.0);
+
+        Ok(())
+    }
+
+    // writerのfileの先頭からlenまでをreaderのfileにする. 他のprocessによる追記を再現する
+    fn copy_file(writer: &InMemoryKvs, reader: &mut InMemoryKvs, len: usize) {
+        *reader.file.get_mut() = writer.file.get_ref()[..len].to_vec();
+    }
+
     fn in_memory_kvs() -> InMemoryKvs {
         Engine::new(Cursor::new(Vec::new())).unwrap()
     }
@@ -200,9 +737,7 @@ mod tests {
     // kvsのfile(buffer)をdumpして再度、kvsを作成しなおす
     // 既存のfileがある状態でのkvsの利用と同じことをやろうとしている
     fn dump_and_restore(mut kvs: InMemoryKvs) -> InMemoryKvs {
-        let mut buff = std::iter::repeat(0)
-            .take(kvs.position as usize)
-            .collect::<Vec<u8>>();
+        let mut buff = std::iter::repeat_n(0, kvs.position as usize).collect::<Vec<u8>>();
         kvs.dump(&mut buff).unwrap();
 
         let mut cursor = Cursor::new(buff);
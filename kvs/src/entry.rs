@@ -1,10 +1,10 @@
 use crate::{error::KvsError, Result};
-use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BE};
 use std::{
     collections::HashMap,
     convert::TryFrom,
     fmt,
-    io::{BufReader, Read},
+    io::{self, BufReader, Read, Seek, SeekFrom},
 };
 
 #[repr(u8)]
@@ -46,6 +46,11 @@ impl Header {
     const LEN_WITHOUT_CHECKSUM: usize = Header::LEN - 4;
 }
 
+// keyとvalueをentryとしてencodeした場合のbytes数
+pub(crate) fn encoded_len(key: &str, value: &[u8]) -> usize {
+    Header::LEN + key.len() + value.len()
+}
+
 impl Entry {
     pub(crate) fn new<K: Into<String>>(key: K, value: Vec<u8>) -> Result<Self> {
         Entry::new_with_state(key.into(), value, State::Active)
@@ -89,23 +94,33 @@ impl Entry {
     }
     pub(crate) fn decode_with_check<R: ReadBytesExt>(r: R) -> Result<Self> {
         Entry::decode(r).and_then(|entry| {
-            if entry.header.checksum != entry.calc_checksum()? {
-                Err(KvsError::CorruptData)
-            } else {
+            if entry.is_valid()? {
                 Ok(entry)
+            } else {
+                Err(KvsError::CorruptData)
             }
         })
     }
 
+    // headerのchecksumがkeyとvalueから計算した値と一致するか
+    pub(crate) fn is_valid(&self) -> Result<bool> {
+        Ok(self.header.checksum == self.calc_checksum()?)
+    }
+
     pub(crate) fn decode<R: ReadBytesExt>(mut r: R) -> Result<Self> {
         let checksum = r.read_u32::<BE>()?;
         let state = State::try_from(r.read_u8()?)?;
         let key_len = r.read_u16::<BE>()?;
         let value_len = r.read_u32::<BE>()?;
 
+        // 壊れたheaderの長さでbufferを確保しないよう、読み込めた分だけ伸ばす
         let data_len = key_len as usize + value_len as usize;
-        let mut key_value = Vec::with_capacity(data_len);
+        let mut key_value = Vec::new();
         r.take(data_len as u64).read_to_end(&mut key_value)?;
+        // 書き込み途中で途切れたentryはUnexpectedEofとする
+        if key_value.len() < data_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
 
         let value = key_value.split_off(key_len as usize);
         let key = String::from_utf8(key_value).map_err(|err| KvsError::from(err.utf8_error()))?;
@@ -123,7 +138,7 @@ impl Entry {
     }
 
     pub(crate) fn len(&self) -> usize {
-        Header::LEN + self.key.len() + self.value.len()
+        encoded_len(&self.key, &self.value)
     }
 
     fn calc_checksum(&self) -> Result<u32> {
@@ -139,53 +154,111 @@ impl Entry {
     }
 }
 
+// Debugで表示するvalueのbytes数
+const VALUE_PREVIEW_BYTES: usize = 32;
+
 impl fmt::Debug for Entry {
+    // valueは先頭のみを表示し、制御文字はescapeする
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let preview = &self.value[..self.value.len().min(VALUE_PREVIEW_BYTES)];
         write!(
             f,
             "|crc32: {}|state: {:?}|key_len: {}|value_len: {}|\
-                key: {}|value: {}|",
+                key: {}|value: {:?}{}|",
             self.header.checksum,
             self.header.state,
             self.header.key_len,
             self.header.value_len,
             self.key,
-            String::from_utf8_lossy(self.value.as_slice())
+            String::from_utf8_lossy(preview),
+            if preview.len() < self.value.len() {
+                "..."
+            } else {
+                ""
+            }
         )
     }
 }
 
+// keyごとの最新のentryのfile上の位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Location {
+    pub(crate) offset: usize,
+    pub(crate) len: usize,
+}
+
 #[derive(Debug)]
-pub(crate) struct KeyIndex(pub HashMap<String, usize>);
+pub(crate) struct KeyIndex(pub HashMap<String, Location>);
 
 impl KeyIndex {
-    pub(crate) fn construct_from<R: Read>(r: R) -> Result<Self> {
+    // indexと、最後まで読み込めたentryの末尾のoffsetを返す
+    // 書き込み途中でcrashした場合、offsetより後ろに不完全なentryが残っている
+    pub(crate) fn construct_from<R: Read + Seek>(r: R) -> Result<(Self, usize)> {
         let mut r = BufReader::new(r);
+        let start = r.stream_position()?;
         let mut h = HashMap::new();
         let mut position = 0;
         let err = loop {
-            if let Err(err) = Entry::decode(r.by_ref()).map(|entry| {
-                let entry_len = entry.len();
-                if entry.is_deleted() {
-                    // 削除されているentryは明示的にindexから削除しておかないと
-                    // 削除前のentryがindexに残ってしまう
-                    h.remove(entry.key.as_str());
-                } else {
-                    h.insert(entry.key, position);
-                }
-                position += entry_len;
-            }) {
-                break err;
+            let entry = match Entry::decode_with_check(r.by_ref()) {
+                Ok(entry) => entry,
+                Err(err) => break err,
+            };
+            let entry_len = entry.len();
+            if entry.is_deleted() {
+                // 削除されているentryは明示的にindexから削除しておかないと
+                // 削除前のentryがindexに残ってしまう
+                h.remove(entry.key.as_str());
+            } else {
+                h.insert(
+                    entry.key,
+                    Location {
+                        offset: position,
+                        len: entry_len,
+                    },
+                );
             }
+            position += entry_len;
         };
-        if err.is_eof() {
-            Ok(Self(h))
-        } else {
-            Err(err)
+        check_tail(&mut r, start + position as u64, err)?;
+        Ok((Self(h), position))
+    }
+}
+
+// positionのentryのdecodeに失敗した際に、書き込み途中でcrashした末尾のentryか確認する
+// 途中のentryが壊れている場合は、後ろのentryを切り詰めて失わないようCorruptDataを返す
+pub(crate) fn check_tail<R: Read + Seek>(r: &mut R, position: u64, err: KvsError) -> Result<()> {
+    match err {
+        err if err.is_eof() => {
+            let mut tail = Vec::new();
+            r.seek(SeekFrom::Start(position))?;
+            r.read_to_end(&mut tail)?;
+            if is_torn_tail(&tail) {
+                Ok(())
+            } else {
+                Err(KvsError::CorruptData)
+            }
         }
+        err @ KvsError::Io { .. } => Err(err),
+        _ => Err(KvsError::CorruptData),
     }
 }
 
+// 読み込めなかったentryから末尾までが、書き込み途中の1つのentryか
+// 後ろに完全なentryが見つかる場合は、途中のentryの長さが壊れている
+fn is_torn_tail(tail: &[u8]) -> bool {
+    (1..tail.len()).all(|i| !is_complete_entry(&tail[i..]))
+}
+
+// bufの先頭からchecksumが一致するentryをdecodeできるか
+// 壊れたheaderの長さでdecodeしないよう、bufに収まることを先に確認する
+fn is_complete_entry(buf: &[u8]) -> bool {
+    if buf.len() < Header::LEN || State::try_from(buf[4]).is_err() {
+        return false;
+    }
+    let data_len = BE::read_u16(&buf[5..7]) as usize + BE::read_u32(&buf[7..11]) as usize;
+    Header::LEN + data_len <= buf.len() && Entry::decode_with_check(buf).is_ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,17 +295,17 @@ mod tests {
         // key
         let mut buff = [0_u8; 1];
         cursor.read_exact(&mut buff)?;
-        assert_eq!(&buff, &[b'1'], "key does not match");
+        assert_eq!(&buff, b"1", "key does not match");
 
         // value
         let mut buff = [0_u8; 1];
         cursor.read_exact(&mut buff)?;
-        assert_eq!(&buff, &[b'1'], "value does not match");
+        assert_eq!(&buff, b"1", "value does not match");
 
         cursor.seek(SeekFrom::Start(0))?;
         let decoded = Entry::decode(&mut cursor)?;
         assert_eq!(decoded, entry, "decoded entry does not match");
-        assert_eq!(cursor.seek(SeekFrom::Current(0))?, decoded.len() as u64);
+        assert_eq!(cursor.stream_position()?, decoded.len() as u64);
 
         Ok(())
     }
@@ -264,18 +337,28 @@ mod tests {
         // key
         let mut buff = [0_u8; 1];
         cursor.read_exact(&mut buff)?;
-        assert_eq!(&buff, &[b'1'], "key does not match");
+        assert_eq!(&buff, b"1", "key does not match");
 
         // value is empty
 
         cursor.seek(SeekFrom::Start(0))?;
         let decoded = Entry::decode(&mut cursor)?;
         assert_eq!(decoded, deleted, "decoded entry does not match");
-        assert_eq!(cursor.seek(SeekFrom::Current(0))?, decoded.len() as u64);
+        assert_eq!(cursor.stream_position()?, decoded.len() as u64);
 
         Ok(())
     }
 
+    #[test]
+    fn decode_truncated() -> StdResult<(), Error> {
+        // headerの長さ(value 4GiB)に満たないentryはbufferを確保せずUnexpectedEofとする
+        let mut buf = Vec::new();
+        Entry::new("1", vec![b'1'])?.encode(&mut buf)?;
+        buf[7..11].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Entry::decode(Cursor::new(buf)).unwrap_err().is_eof());
+        Ok(())
+    }
+
     #[test]
     fn key_index_from() -> StdResult<(), Error> {
         let entries = vec![
@@ -290,13 +373,33 @@ mod tests {
         });
         cursor.seek(SeekFrom::Start(0))?;
 
-        let index = KeyIndex::construct_from(&mut cursor)?;
+        let (index, end) = KeyIndex::construct_from(&mut cursor)?;
         let mut position: usize = 0;
         for entry in entries {
-            let offset = index.0.get(entry.key.as_str()).unwrap();
-            assert_eq!(*offset, position);
+            let location = index.0.get(entry.key.as_str()).unwrap();
+            assert_eq!(location.offset, position);
+            assert_eq!(location.len, entry.len());
             position += entry.len();
         }
+        assert_eq!(end, position);
+
+        // 末尾の不完全なentryは読み込まない
+        let mut buf = cursor.into_inner();
+        buf.extend_from_slice(&[0, 0]);
+        let (index, end) = KeyIndex::construct_from(Cursor::new(buf.clone()))?;
+        assert_eq!(index.0.len(), 3);
+        assert_eq!(end, position);
+
+        // 途中のentryの長さが壊れている場合は、後ろのentryを読み飛ばさずにエラーにする
+        buf.truncate(position);
+        buf[7..11].copy_from_slice(&[0xFF; 4]);
+        let err = KeyIndex::construct_from(Cursor::new(buf.clone())).unwrap_err();
+        assert!(err.is_data_corrupt());
+        // checksumが一致しない場合も同様
+        buf[7..11].copy_from_slice(&[0, 0, 0, 1]);
+        buf[11] = b'x';
+        let err = KeyIndex::construct_from(Cursor::new(buf)).unwrap_err();
+        assert!(err.is_data_corrupt());
 
         Ok(())
     }
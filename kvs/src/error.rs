@@ -13,6 +13,16 @@ pub enum KvsError {
         #[from]
         source: bincode::Error,
     },
+    #[error("http: {}", .source)]
+    Http {
+        #[from]
+        source: hyper::Error,
+    },
+    #[error("readline: {}", .source)]
+    Readline {
+        #[from]
+        source: rustyline::error::ReadlineError,
+    },
     #[error("max key bytes({}) exceeded", crate::MAX_KEY_BYTES)]
     MaxKeyBytes,
     #[error("max value bytes({}) exceeded", crate::MAX_VALUE_BYTES)]
@@ -25,6 +35,64 @@ pub enum KvsError {
     CorruptData,
     #[error("invalid key {}", .source)]
     InvalidKey { source: std::str::Utf8Error },
+    #[error("invalid payload kind {}", .0)]
+    InvalidPayloadKind(u8),
+    #[error("invalid magic word {:#x}", .0)]
+    InvalidMagicWord(u8),
+    #[error("payload bytes {} exceeds max payload bytes({})", .0, crate::protocol::message::MAX_PAYLOAD_BYTES)]
+    MaxPayloadBytes(u64),
+    #[error("resp protocol: {}", .0)]
+    RespProtocol(String),
+    #[error("invalid protocol {}. expected kvs or resp", .0)]
+    InvalidProtocol(String),
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("invalid acl config: {}", .0)]
+    InvalidAcl(String),
+    #[error("read only")]
+    ReadOnly,
+    #[error("{} is locked by another process", .0.display())]
+    Locked(std::path::PathBuf),
+    #[error("invalid log offset {}", .0)]
+    InvalidOffset(u64),
+    #[error("replication: {}", .0)]
+    Replication(String),
+    #[error("not leader.{}", .0.as_ref().map(|leader| format!(" leader is {}", leader)).unwrap_or_default())]
+    NotLeader(Option<String>),
+    #[error("invalid cluster config: {}", .0)]
+    InvalidCluster(String),
+    #[error("raft: {}", .0)]
+    Raft(String),
+    #[error("invalid format {}. expected jsonl or csv", .0)]
+    InvalidFormat(String),
+    #[error("invalid codec {}. expected string, json or raw", .0)]
+    InvalidCodec(String),
+    #[error("invalid value format {}. expected string, raw, json or hex", .0)]
+    InvalidValueFormat(String),
+    #[error("invalid value: {}", .0)]
+    InvalidValue(String),
+    #[error("invalid record: {}", .0)]
+    InvalidRecord(String),
+    #[error("invalid log format {}. expected text or json", .0)]
+    InvalidLogFormat(String),
+    #[error("invalid otel endpoint {}", .0)]
+    InvalidOtelEndpoint(String),
+    #[error("index {} not found", .0)]
+    IndexNotFound(String),
+    #[error("index {} has different value type", .0)]
+    IndexTypeMismatch(String),
+    #[error("invalid engine {}. expected log or lsm", .0)]
+    InvalidEngine(String),
+    #[error("{} is not supported by lsm engine", .0)]
+    Unsupported(&'static str),
+    #[error("log is not writable after a failed write. reopen to recover")]
+    WriteFailed,
+    #[error("kvs engine thread stopped")]
+    EngineStopped,
+    #[error("server: {}", .0)]
+    Server(String),
+    #[error("invalid tls config: {}", .0)]
+    TlsConfig(String),
     #[error(transparent)]
     Unknown(#[from] anyhow::Error),
 }
@@ -39,24 +107,31 @@ impl KvsError {
     }
 
     pub fn is_not_found(&self) -> bool {
-        match self {
-            KvsError::NotFound => true,
-            _ => false,
-        }
+        matches!(self, KvsError::NotFound)
+    }
+
+    pub fn is_permission_denied(&self) -> bool {
+        matches!(self, KvsError::PermissionDenied)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, KvsError::ReadOnly)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        matches!(self, KvsError::Locked(_))
+    }
+
+    pub fn is_not_leader(&self) -> bool {
+        matches!(self, KvsError::NotLeader(_))
     }
 
     pub fn is_data_corrupt(&self) -> bool {
-        match self {
-            KvsError::CorruptData => true,
-            _ => false,
-        }
+        matches!(self, KvsError::CorruptData)
     }
 
     pub fn is_serialize(&self) -> bool {
-        match self {
-            KvsError::Serialize { .. } => true,
-            _ => false,
-        }
+        matches!(self, KvsError::Serialize { .. })
     }
 }
 
@@ -0,0 +1,187 @@
+// valueから取り出した値でkeyを検索するためのsecondary index
+// indexはmemory上にのみ持ち、Kvs::create_indexの際にfileの全てのvalueから構築する
+use crate::{KvsError, Result};
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::RangeBounds,
+};
+
+// 型の異なるindexをまとめて扱うためのtrait
+trait SecondaryIndex: Send + Sync {
+    fn insert(&mut self, key: &str, value: &[u8]);
+    fn remove(&mut self, key: &str);
+    fn as_any(&self) -> &dyn Any;
+}
+
+type Extract<I> = Box<dyn Fn(&[u8]) -> Option<I> + Send + Sync>;
+
+struct Index<I> {
+    // valueからindexの値を取り出す. 対象の型でないvalueはNoneを返し、indexに含めない
+    extract: Extract<I>,
+    entries: BTreeMap<I, BTreeSet<String>>,
+    // 更新/削除時に古い値をentriesから除くため、keyごとの現在の値を保持する
+    keys: HashMap<String, I>,
+}
+
+impl<I> SecondaryIndex for Index<I>
+where
+    I: Ord + Clone + Send + Sync + 'static,
+{
+    fn insert(&mut self, key: &str, value: &[u8]) {
+        self.remove(key);
+        if let Some(indexed) = (self.extract)(value) {
+            self.entries
+                .entry(indexed.clone())
+                .or_default()
+                .insert(key.to_owned());
+            self.keys.insert(key.to_owned(), indexed);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(indexed) = self.keys.remove(key) {
+            if let Some(keys) = self.entries.get_mut(&indexed) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&indexed);
+                }
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Indexes(HashMap<String, Box<dyn SecondaryIndex>>);
+
+impl Indexes {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // 同じ名前のindexがある場合は置き換える
+    pub(crate) fn create<I, F>(&mut self, name: String, extract: F)
+    where
+        I: Ord + Clone + Send + Sync + 'static,
+        F: Fn(&[u8]) -> Option<I> + Send + Sync + 'static,
+    {
+        let index = Index {
+            extract: Box::new(extract),
+            entries: BTreeMap::new(),
+            keys: HashMap::new(),
+        };
+        self.0.insert(name, Box::new(index));
+    }
+
+    pub(crate) fn drop(&mut self, name: &str) -> bool {
+        self.0.remove(name).is_some()
+    }
+
+    // nameのindexのみを更新する. 構築時に利用する
+    pub(crate) fn insert_into(&mut self, name: &str, key: &str, value: &[u8]) {
+        if let Some(index) = self.0.get_mut(name) {
+            index.insert(key, value);
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &str, value: &[u8]) {
+        self.0
+            .values_mut()
+            .for_each(|index| index.insert(key, value));
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        self.0.values_mut().for_each(|index| index.remove(key));
+    }
+
+    // indexの値がrangeに含まれるkeyを、indexの値、keyの順に並べて返す
+    pub(crate) fn range<I, R>(&self, name: &str, range: R) -> Result<Vec<String>>
+    where
+        I: Ord + 'static,
+        R: RangeBounds<I>,
+    {
+        Ok(self
+            .get::<I>(name)?
+            .entries
+            .range(range)
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect())
+    }
+
+    pub(crate) fn lookup<I>(&self, name: &str, value: &I) -> Result<Vec<String>>
+    where
+        I: Ord + 'static,
+    {
+        Ok(self
+            .get::<I>(name)?
+            .entries
+            .get(value)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn get<I: 'static>(&self, name: &str) -> Result<&Index<I>> {
+        self.0
+            .get(name)
+            .ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?
+            .as_any()
+            .downcast_ref::<Index<I>>()
+            .ok_or_else(|| KvsError::IndexTypeMismatch(name.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+
+    // valueの先頭のbyteをindexの値とする. 空のvalueはindexに含めない
+    fn indexes() -> Indexes {
+        let mut indexes = Indexes::default();
+        indexes.create("first".to_owned(), |value: &[u8]| value.first().copied());
+        indexes
+    }
+
+    #[test]
+    fn insert_remove() -> StdResult<(), Error> {
+        let mut indexes = indexes();
+        indexes.insert("a", &[2]);
+        indexes.insert("b", &[1]);
+        indexes.insert("c", &[2]);
+        indexes.insert("d", &[]);
+
+        assert_eq!(indexes.lookup("first", &2_u8)?, vec!["a", "c"]);
+        assert_eq!(indexes.range::<u8, _>("first", ..)?, vec!["b", "a", "c"]);
+        assert_eq!(indexes.range("first", 2_u8..)?, vec!["a", "c"]);
+
+        // 更新すると古い値からは引けなくなる
+        indexes.insert("a", &[3]);
+        assert_eq!(indexes.lookup("first", &2_u8)?, vec!["c"]);
+        indexes.remove("c");
+        indexes.remove("not-exist");
+        assert!(indexes.lookup("first", &2_u8)?.is_empty());
+        assert_eq!(indexes.range("first", 1_u8..=3)?, vec!["b", "a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_index() {
+        let mut indexes = indexes();
+        assert!(matches!(
+            indexes.lookup("none", &1_u8),
+            Err(KvsError::IndexNotFound(_))
+        ));
+        assert!(matches!(
+            indexes.range("first", 1_u64..),
+            Err(KvsError::IndexTypeMismatch(_))
+        ));
+        assert!(indexes.drop("first"));
+        assert!(!indexes.drop("first"));
+        assert!(indexes.is_empty());
+    }
+}
@@ -1,17 +1,43 @@
+mod acl;
+mod async_kvs;
+mod bulk;
+mod cache;
 pub mod cli;
+mod client;
+mod dump;
 mod engine;
 mod entry;
 mod error;
+mod index;
+mod lsm;
+mod metrics;
 mod protocol;
+mod raft;
 mod server;
+mod shard;
+mod storage;
 mod store;
+mod telemetry;
+mod tls;
+mod value;
 
-pub use engine::Keys;
+pub use acl::{Acl, Operation};
+pub use async_kvs::AsyncKvs;
+pub use bulk::{export, import, Codec, Format, Report, DEFAULT_BATCH_SIZE};
+pub use client::Client;
+pub use dump::{dump, DumpFilter, DumpSummary};
+pub use engine::{Keys, OwnedKeys, Version};
 pub use error::KvsError;
-pub use server::Server;
-pub use store::Kvs;
+pub use metrics::Stats;
+pub use raft::{Cluster, Member, NodeId, RaftConfig};
+pub use server::{Protocol, Server};
+pub use shard::{rebalance, Migration, Ring, ShardedClient, DEFAULT_VNODES};
+pub use storage::Storage;
+pub use store::{EngineKind, History, Kvs};
+pub use tls::TlsConfig;
+pub use value::{inspect, ValueFormat};
 
-const MAX_KEY_BYTES: u16 = std::u16::MAX;
-const MAX_VALUE_BYTES: u32 = std::u32::MAX;
+const MAX_KEY_BYTES: u16 = u16::MAX;
+const MAX_VALUE_BYTES: u32 = u32::MAX;
 
 type Result<T> = std::result::Result<T, error::KvsError>;
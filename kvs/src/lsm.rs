@@ -0,0 +1,787 @@
+// memtableとSSTableによるengine. keyをすべてmemory上に持たないので、memoryより大きなkeyの集合を扱える
+//
+// 書き込みはwalに追記したうえでmemtable(BTreeMap)に反映する. walが上限を超えるとmemtableを
+// level 0のtableに書き出してwalを空にする. level 0のtableは互いにkeyの範囲が重なるので、数が上限を超えると
+// level 1の重なるtableとmergeする. level 1以降はlevel内でkeyの範囲が重ならず、合計の大きさが上限を超えると
+// tableを1つ選んで次のlevelの重なるtableとmergeする(leveled compaction)
+//
+// dir/
+//   wal.log      memtableの内容. logと同じformat
+//   000001.sst   table
+//   MANIFEST     levelごとのtableのid. 書き換えはrenameで置き換える
+mod bloom;
+mod merge;
+mod table;
+
+use crate::{
+    cache::Cache,
+    entry::{self, Entry},
+    metrics::{EngineMetrics, Stats},
+    KvsError, Result,
+};
+use merge::{Merge, Source};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Seek, SeekFrom::*, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use table::{Table, TableWriter, Value};
+use tracing::{debug, debug_span, error, field, warn};
+
+const WAL: &str = "wal.log";
+const MANIFEST: &str = "MANIFEST";
+
+pub(crate) struct LsmConfig {
+    // walがこのbytes数を超えるとmemtableをtableに書き出す
+    pub(crate) memtable_bytes: u64,
+    // level 0のtableの数の上限
+    pub(crate) l0_tables: usize,
+    // compactionで書き出すtableの大きさ
+    pub(crate) table_bytes: u64,
+    // level 1の合計の大きさの上限. level nはlevel_ratio^(n-1)倍になる
+    pub(crate) base_level_bytes: u64,
+    pub(crate) level_ratio: u64,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        Self {
+            memtable_bytes: 4 * 1024 * 1024,
+            l0_tables: 4,
+            table_bytes: 2 * 1024 * 1024,
+            base_level_bytes: 10 * 1024 * 1024,
+            level_ratio: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+pub(crate) struct LsmEngine {
+    dir: PathBuf,
+    config: LsmConfig,
+    wal: File,
+    wal_bytes: u64,
+    memtable: BTreeMap<String, Value>,
+    // level 0は新しい順に並べる. level 1以降はkeyの順に並べる
+    levels: Vec<Vec<Table>>,
+    next_id: u64,
+    // levelごとに前回compactionしたtableの最後のkey. 次はその後ろのtableを選び、keyの範囲を一巡させる
+    compact_pointers: Vec<String>,
+    // 有効な場合、getで読み込んだvalueを保持する. 書き込んだkeyは取り除く
+    cache: Option<Cache>,
+    metrics: EngineMetrics,
+    // walへの書き込みに失敗し、途中まで書いたbytesを取り除けなかった. 開き直すまで書き込みを拒否する
+    failed: bool,
+}
+
+impl LsmEngine {
+    // read onlyの場合はdirを変更しない. writerが書き込み中のtableやwalの末尾はそのまま残す
+    pub(crate) fn open(dir: &Path, config: LsmConfig, read_only: bool) -> Result<Self> {
+        if !read_only {
+            fs::create_dir_all(dir)?;
+        }
+        let manifest = match fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => bincode::deserialize::<Manifest>(&bytes)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|&id| Table::open(table::path(dir, id), id))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut wal = if read_only {
+            File::open(dir.join(WAL))?
+        } else {
+            remove_orphans(dir, &manifest)?;
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(dir.join(WAL))?
+        };
+        let (memtable, wal_bytes) = replay(&mut wal)?;
+        if !read_only {
+            let len = wal.seek(End(0))?;
+            if len > wal_bytes {
+                warn!(
+                    bytes = len - wal_bytes,
+                    "Truncate incomplete entry at the end of wal"
+                );
+                wal.set_len(wal_bytes)?;
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            config,
+            wal,
+            wal_bytes,
+            memtable,
+            levels,
+            next_id: manifest.next_id,
+            compact_pointers: Vec::new(),
+            cache: None,
+            metrics: EngineMetrics::default(),
+            failed: false,
+        })
+    }
+
+    pub(crate) fn put(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        let span = debug_span!(
+            "engine",
+            op = "put",
+            key = key.as_str(),
+            value_bytes = value.len()
+        );
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let result = Entry::new(key, value).and_then(|entry| self.write(vec![entry]));
+        self.metrics.put.observe(start.elapsed());
+        result
+    }
+
+    pub(crate) fn put_batch(&mut self, batch: Vec<(String, Vec<u8>)>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let span = debug_span!("engine", op = "put_batch", entries = batch.len());
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let n = batch.len() as u32;
+        let entries = batch
+            .into_iter()
+            .map(|(key, value)| Entry::new(key, value))
+            .collect::<Result<Vec<_>>>()?;
+        self.write(entries)?;
+
+        // entryごとのlatencyは均等に按分する
+        let elapsed = start.elapsed() / n;
+        (0..n).for_each(|_| self.metrics.put.observe(elapsed));
+        Ok(())
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Result<Vec<u8>> {
+        let span = debug_span!("engine", op = "get", key = key, value_bytes = field::Empty);
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let result = self.get_cached(key);
+        self.metrics.get.observe(start.elapsed());
+        if let Ok(value) = &result {
+            span.record("value_bytes", value.len());
+        }
+        result
+    }
+
+    pub(crate) fn delete(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let span = debug_span!("engine", op = "delete", key = key);
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let result = self.delete_entry(key);
+        self.metrics.delete.observe(start.elapsed());
+        result
+    }
+
+    // memtableにあるvalueは読み込みが不要なので、tableから読み込んだvalueのみを保持する
+    fn get_cached(&mut self, key: &str) -> Result<Vec<u8>> {
+        if let Some(cache) = self.cache.as_mut() {
+            if let Some(value) = cache.get(key) {
+                self.metrics.cache_hits.inc();
+                return Ok(value);
+            }
+            self.metrics.cache_misses.inc();
+        }
+        let in_memtable = self.memtable.contains_key(key);
+        let value = self.lookup(key)?.ok_or(KvsError::NotFound)?;
+        if let (Some(cache), false) = (self.cache.as_mut(), in_memtable) {
+            cache.insert(key, &value);
+        }
+        Ok(value)
+    }
+
+    // bytesが0の場合はcacheを無効にする. 変更すると保持していたvalueは捨てる
+    pub(crate) fn set_cache_capacity(&mut self, bytes: usize) {
+        self.cache = if bytes == 0 {
+            None
+        } else {
+            Some(Cache::new(bytes))
+        };
+    }
+
+    fn delete_entry(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let value = match self.lookup(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // 下のlevelにあるvalueを隠すため、tombstoneを書き込む
+        self.write(vec![Entry::new(key, Vec::new())?.mark_delete()?])?;
+        Ok(Some(value))
+    }
+
+    // getの件数には数えない. memtable、level 0の新しいtable、level 1以降の順に探す
+    // filterでkeyを含まないと判定したtableは読まず、filter_hitsに数える
+    pub(crate) fn lookup(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        for (n, level) in self.levels.iter().enumerate() {
+            let tables = if n == 0 {
+                level.as_slice()
+            } else {
+                // level内でkeyの範囲が重ならないので、keyを含みうるtableは1つ
+                let i = level.partition_point(|table| table.last_key() < key);
+                &level[i..level.len().min(i + 1)]
+            };
+            for table in tables {
+                if !table.may_contain(key) {
+                    self.metrics.filter_hits.inc();
+                    continue;
+                }
+                match table.get(key)? {
+                    Some(value) => return Ok(value),
+                    None => self.metrics.filter_false_positives.inc(),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        matches!(self.lookup(key), Ok(Some(_)))
+    }
+
+    // 全てのtableを読むので、keyの数に比例して時間がかかる
+    // 読み込めないtableがあった場合はwarnを出力して読み飛ばす
+    pub(crate) fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.merge().filter_map(|item| match item {
+            Ok((key, Some(_))) => Some(key),
+            Ok((_, None)) => None,
+            Err(err) => {
+                warn!("Failed to read table: {}", err);
+                None
+            }
+        })
+    }
+
+    // 上書きや削除されたentryはcompactionされるまで区別できないので、keysとlive_bytesは概算になる
+    // walはmemtableに残っているentryの分をliveとし、上書きや削除されたentryとtableのmetaはstaleとして数える
+    pub(crate) fn stats(&self) -> Stats {
+        let table_bytes = self.tables().map(|table| table.bytes).sum::<u64>();
+        let table_keys = self.tables().map(Table::live_entries).sum::<u64>();
+        let table_live_bytes = self.tables().map(Table::live_bytes).sum::<u64>();
+        let (memtable_keys, memtable_live_bytes) = self
+            .memtable
+            .iter()
+            .filter_map(|(key, value)| value.as_ref().map(|value| entry::encoded_len(key, value)))
+            .fold((0, 0), |(keys, bytes), len| (keys + 1, bytes + len as u64));
+        let file_bytes = self.wal_bytes + table_bytes;
+        let live_bytes = (memtable_live_bytes + table_live_bytes).min(file_bytes);
+        Stats {
+            file_bytes,
+            live_bytes,
+            stale_bytes: file_bytes - live_bytes,
+            keys: memtable_keys + table_keys,
+            gets: self.metrics.get.count(),
+            puts: self.metrics.put.count(),
+            deletes: self.metrics.delete.count(),
+            cache_hits: self.metrics.cache_hits.get(),
+            cache_misses: self.metrics.cache_misses.get(),
+            filter_hits: self.metrics.filter_hits.get(),
+            filter_false_positives: self.metrics.filter_false_positives.get(),
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> &EngineMetrics {
+        &self.metrics
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.wal.sync_all()?;
+        Ok(())
+    }
+
+    // walに1度の書き込みで追記してからmemtableに反映する
+    fn write(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let mut buf = Vec::new();
+        for entry in &entries {
+            entry.encode(&mut buf)?;
+        }
+        self.append_wal(&buf)?;
+        self.wal_bytes += buf.len() as u64;
+        for entry in entries {
+            if let Some(cache) = self.cache.as_mut() {
+                cache.remove(&entry.key);
+            }
+            insert(&mut self.memtable, entry);
+        }
+
+        if self.wal_bytes >= self.config.memtable_bytes {
+            self.flush()?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // 失敗した場合は途中まで書き込まれたbytesを切り詰める. 残すと以降のentryをreplayできなくなる
+    // walはappendで開いているので、切り詰めた後の書き込みはwal_bytesの位置から続く
+    fn append_wal(&mut self, buf: &[u8]) -> Result<()> {
+        if self.failed {
+            return Err(KvsError::WriteFailed);
+        }
+        let result = self.wal.write_all(buf).and_then(|_| self.wal.flush());
+        if let Err(err) = result {
+            if let Err(restore_err) = self.wal.set_len(self.wal_bytes) {
+                error!(%err, %restore_err, "Failed to truncate partially written wal entry");
+                self.failed = true;
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    // memtableをlevel 0のtableに書き出し、walを空にする
+    fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let id = self.next_id;
+        let mut w = TableWriter::create(table::path(&self.dir, id), id)?;
+        for (key, value) in &self.memtable {
+            w.add(key.clone(), value.clone())?;
+        }
+        let table = w.finish()?;
+        debug!(
+            table = id,
+            entries = self.memtable.len(),
+            bytes = table.bytes,
+            "Flush memtable"
+        );
+
+        self.next_id += 1;
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].insert(0, table);
+        self.save_manifest()?;
+        // manifestの保存後にcrashした場合、walの内容はtableと重複するが同じvalueになる
+        self.wal.set_len(0)?;
+        self.wal_bytes = 0;
+        self.memtable.clear();
+        Ok(())
+    }
+
+    // 上限を超えたlevelがなくなるまでcompactionを繰り返す
+    fn compact(&mut self) -> Result<()> {
+        while let Some(level) = self.pick_level() {
+            self.compact_level(level)?;
+        }
+        Ok(())
+    }
+
+    fn pick_level(&self) -> Option<usize> {
+        if self.levels.first()?.len() >= self.config.l0_tables {
+            return Some(0);
+        }
+        let mut max_bytes = self.config.base_level_bytes;
+        for (n, level) in self.levels.iter().enumerate().skip(1) {
+            if level.iter().map(|table| table.bytes).sum::<u64>() > max_bytes {
+                return Some(n);
+            }
+            max_bytes *= self.config.level_ratio;
+        }
+        None
+    }
+
+    // levelのtable(level 0の場合は全て)を次のlevelの重なるtableとmergeし、次のlevelに書き出す
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        let start = Instant::now();
+        if self.levels.len() <= level + 1 {
+            self.levels.push(Vec::new());
+        }
+        if self.compact_pointers.len() <= level {
+            self.compact_pointers.resize(level + 1, String::new());
+        }
+
+        let tables = &self.levels[level];
+        let inputs = if level == 0 {
+            tables.iter().collect::<Vec<_>>()
+        } else {
+            let pointer = self.compact_pointers[level].as_str();
+            let table = tables
+                .iter()
+                .find(|table| table.first_key() > pointer)
+                .unwrap_or(&tables[0]);
+            vec![table]
+        };
+        let first = inputs
+            .iter()
+            .map(|t| t.first_key())
+            .min()
+            .unwrap()
+            .to_owned();
+        let last = inputs
+            .iter()
+            .map(|t| t.last_key())
+            .max()
+            .unwrap()
+            .to_owned();
+        let overlaps = self.levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(&first, &last))
+            .collect::<Vec<_>>();
+        // 書き出すlevelより下にtableがなければ、tombstoneが隠すvalueはもう存在しない
+        let drop_tombstones = self.levels[level + 2..].iter().all(Vec::is_empty);
+
+        // inputsはoverlapsより新しい. level 0のinputsは新しい順に並んでいる
+        let mut sources = inputs
+            .iter()
+            .map(|&table| Box::new(table.iter()) as Source)
+            .collect::<Vec<_>>();
+        sources.push(Box::new(overlaps.iter().flat_map(|table| table.iter())));
+        let mut next_id = self.next_id;
+        let mut outputs = Vec::new();
+        let mut w: Option<TableWriter> = None;
+        for item in Merge::new(sources) {
+            let (key, value) = item?;
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+            if w.is_none() {
+                w = Some(TableWriter::create(
+                    table::path(&self.dir, next_id),
+                    next_id,
+                )?);
+                next_id += 1;
+            }
+            let writer = w.as_mut().unwrap();
+            writer.add(key, value)?;
+            if writer.bytes() >= self.config.table_bytes {
+                outputs.push(w.take().unwrap().finish()?);
+            }
+        }
+        if let Some(w) = w {
+            outputs.push(w.finish()?);
+        }
+        let input_ids = inputs.iter().map(|table| table.id).collect::<Vec<_>>();
+        let overlap_ids = overlaps.iter().map(|table| table.id).collect::<Vec<_>>();
+        debug!(
+            level,
+            inputs = input_ids.len(),
+            overlaps = overlap_ids.len(),
+            outputs = outputs.len(),
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "Compact tables"
+        );
+
+        // 新しいtableをmanifestに反映してから、mergeしたtableを削除する
+        let mut removed = take_tables(&mut self.levels[level], &input_ids);
+        removed.extend(take_tables(&mut self.levels[level + 1], &overlap_ids));
+        self.levels[level + 1].extend(outputs);
+        self.levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.compact_pointers[level] = last;
+        self.next_id = next_id;
+        self.save_manifest()?;
+        for table in removed {
+            if let Err(err) = table.remove() {
+                warn!("Failed to remove compacted table: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    // 新しい順に並べた全てのsourceをmergeする
+    fn merge(&self) -> Merge<'_> {
+        let mut sources: Vec<Source> = vec![Box::new(
+            self.memtable
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        )];
+        for (n, level) in self.levels.iter().enumerate() {
+            if n == 0 {
+                sources.extend(level.iter().map(|table| Box::new(table.iter()) as Source));
+            } else {
+                sources.push(Box::new(level.iter().flat_map(|table| table.iter())));
+            }
+        }
+        Merge::new(sources)
+    }
+
+    fn tables(&self) -> impl Iterator<Item = &Table> {
+        self.levels.iter().flatten()
+    }
+
+    fn save_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bincode::serialize(&manifest)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(MANIFEST))?;
+        Ok(())
+    }
+}
+
+fn insert(memtable: &mut BTreeMap<String, Value>, entry: Entry) {
+    let value = if entry.is_deleted() {
+        None
+    } else {
+        Some(entry.value)
+    };
+    memtable.insert(entry.key, value);
+}
+
+// walを読み込んでmemtableを構築する. 最後まで読み込めたentryの末尾のoffsetも返す
+// 途中のentryが壊れている場合はCorruptDataになり、walは切り詰めない
+fn replay(wal: &mut File) -> Result<(BTreeMap<String, Value>, u64)> {
+    let mut memtable = BTreeMap::new();
+    let mut position = 0;
+    let mut r = BufReader::new(wal);
+    let err = loop {
+        match Entry::decode_with_check(&mut r) {
+            Ok(entry) => {
+                position += entry.len() as u64;
+                insert(&mut memtable, entry);
+            }
+            Err(err) => break err,
+        }
+    };
+    entry::check_tail(&mut r, position, err)?;
+    Ok((memtable, position))
+}
+
+// flushやcompactionの途中でcrashして残った、manifestに含まれないtableを削除する
+fn remove_orphans(dir: &Path, manifest: &Manifest) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".sst"))
+            .and_then(|id| id.parse::<u64>().ok());
+        if let Some(id) = id {
+            if !manifest.levels.iter().any(|ids| ids.contains(&id)) {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn take_tables(level: &mut Vec<Table>, ids: &[u64]) -> Vec<Table> {
+    let (taken, kept) = std::mem::take(level)
+        .into_iter()
+        .partition(|table| ids.contains(&table.id));
+    *level = kept;
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::result::Result as StdResult;
+
+    // 少ない書き込みでflushとcompactionが起きる設定
+    fn small_config() -> LsmConfig {
+        LsmConfig {
+            memtable_bytes: 1024,
+            l0_tables: 2,
+            table_bytes: 1024,
+            base_level_bytes: 4 * 1024,
+            level_ratio: 2,
+        }
+    }
+
+    fn assert_matches(engine: &mut LsmEngine, model: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+        assert_eq!(
+            engine.keys().collect::<Vec<_>>(),
+            model.keys().cloned().collect::<Vec<_>>()
+        );
+        for (key, value) in model {
+            assert_eq!(&engine.get(key)?, value, "key {}", key);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn flush_and_compact() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let dir = tmp_dir.path().join("test.lsm");
+        let mut engine = LsmEngine::open(&dir, small_config(), false)?;
+        let mut model = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(1);
+        for i in 0..5000 {
+            let key = format!("key/{:03}", rng.gen_range(0, 300));
+            if rng.gen_range(0, 4) == 0 {
+                assert_eq!(engine.delete(&key)?, model.remove(&key));
+            } else {
+                let value = vec![b'x'; rng.gen_range(0, 64)];
+                engine.put(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+            if i % 1000 == 0 {
+                assert_matches(&mut engine, &model)?;
+            }
+        }
+        assert!(engine.levels.len() >= 3, "levels {}", engine.levels.len());
+        assert!(engine.levels[0].len() < 2);
+        assert_matches(&mut engine, &model)?;
+        assert!(engine.get("key/999").unwrap_err().is_not_found());
+        assert!(!engine.contains_key("key/999"));
+        assert_eq!(engine.delete("key/999")?, None);
+
+        // level 1以降はlevel内でkeyの範囲が重ならない
+        for level in &engine.levels[1..] {
+            for pair in level.windows(2) {
+                assert!(pair[0].last_key() < pair[1].first_key());
+            }
+        }
+
+        let tables = engine.tables().count();
+        drop(engine);
+        let mut engine = LsmEngine::open(&dir, small_config(), false)?;
+        assert_eq!(engine.tables().count(), tables);
+        assert_matches(&mut engine, &model)?;
+        assert_eq!(engine.stats().gets, model.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn filter() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let dir = tmp_dir.path().join("test.lsm");
+        let mut engine = LsmEngine::open(&dir, small_config(), false)?;
+        for i in 0..300 {
+            engine.put(format!("key/{:03}", i), vec![b'x'; 32])?;
+        }
+        assert!(engine.tables().count() > 1);
+
+        // tableのkeyの範囲に含まれるが、存在しないkeyはfilterでtableを読まない
+        for i in 0..300 {
+            assert!(engine
+                .get(&format!("key/{:03}x", i))
+                .unwrap_err()
+                .is_not_found());
+        }
+        let stats = engine.stats();
+        assert!(
+            stats.filter_hits >= 300,
+            "filter hits {}",
+            stats.filter_hits
+        );
+        assert!(
+            stats.filter_false_positives * 10 < stats.filter_hits,
+            "false positives {}",
+            stats.filter_false_positives
+        );
+
+        // 存在するkeyはfilterで除外されない
+        for i in 0..300 {
+            assert_eq!(engine.get(&format!("key/{:03}", i))?, vec![b'x'; 32]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recover() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let dir = tmp_dir.path().join("test.lsm");
+        let mut engine = LsmEngine::open(&dir, LsmConfig::default(), false)?;
+        engine.put_batch(vec![("a".to_owned(), vec![1]), ("b".to_owned(), vec![2])])?;
+        engine.delete("a")?;
+        engine.flush()?;
+        engine.put("c".to_owned(), vec![3])?;
+        drop(engine);
+
+        // 書き込み途中のwalと、manifestに含まれないtable
+        let mut wal = OpenOptions::new().append(true).open(dir.join(WAL))?;
+        wal.write_all(&[0, 1, 2])?;
+        File::create(table::path(&dir, 99))?;
+
+        // read onlyの場合は何も変更しない
+        let mut reader = LsmEngine::open(&dir, LsmConfig::default(), true)?;
+        assert_eq!(reader.get("c")?, vec![3]);
+        assert!(table::path(&dir, 99).exists());
+
+        let mut engine = LsmEngine::open(&dir, LsmConfig::default(), false)?;
+        assert!(!table::path(&dir, 99).exists());
+        assert!(engine.get("a").unwrap_err().is_not_found());
+        assert_eq!(engine.get("b")?, vec![2]);
+        assert_eq!(engine.get("c")?, vec![3]);
+        engine.put("d".to_owned(), vec![4])?;
+        drop(engine);
+
+        let mut engine = LsmEngine::open(&dir, LsmConfig::default(), false)?;
+        assert_eq!(engine.keys().collect::<Vec<_>>(), vec!["b", "c", "d"]);
+        let stats = engine.stats();
+        assert_eq!(stats.keys, 3);
+        // tableのbとwalのc, d. aのtombstoneとtableのmetaはstaleになる
+        assert_eq!(stats.live_bytes, 3 * entry::encoded_len("b", &[2]) as u64);
+        assert_eq!(stats.stale_bytes, stats.file_bytes - stats.live_bytes);
+        assert!(stats.stale_bytes > 0);
+
+        // tableから読み込んだvalueのみcacheに保持し、書き込んだkeyは取り除く
+        engine.set_cache_capacity(1024);
+        assert_eq!(engine.get("b")?, vec![2]);
+        assert_eq!(engine.get("b")?, vec![2]);
+        engine.put("b".to_owned(), vec![5])?;
+        assert_eq!(engine.get("b")?, vec![5]);
+        assert_eq!(engine.get("b")?, vec![5]);
+        let stats = engine.stats();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 3));
+
+        assert!(LsmEngine::open(&tmp_dir.path().join("none"), LsmConfig::default(), true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_wal() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let dir = tmp_dir.path().join("test.lsm");
+        let mut engine = LsmEngine::open(&dir, LsmConfig::default(), false)?;
+        engine.put("a".to_owned(), vec![1])?;
+        engine.put("b".to_owned(), vec![2])?;
+        drop(engine);
+
+        // 途中のentryのvalue_lenが壊れている場合は、後ろのentryを切り詰めずに失敗する
+        let mut bytes = fs::read(dir.join(WAL))?;
+        bytes[7..11].copy_from_slice(&[0xFF; 4]);
+        fs::write(dir.join(WAL), &bytes)?;
+        let err = LsmEngine::open(&dir, LsmConfig::default(), false)
+            .err()
+            .unwrap();
+        assert!(err.is_data_corrupt(), "{:?}", err);
+        assert_eq!(fs::read(dir.join(WAL))?, bytes);
+
+        // lsm engineのKvsはkeyの参照を返せない
+        fs::remove_dir_all(&dir)?;
+        let kvs = crate::Kvs::with_engine(&dir, crate::EngineKind::Lsm)?;
+        assert!(matches!(kvs.keys(), Err(KvsError::Unsupported("keys"))));
+        Ok(())
+    }
+}
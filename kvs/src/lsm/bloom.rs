@@ -0,0 +1,79 @@
+// tableごとのBloom filter. tableに含まれないkeyの大半を、blockを読まずに除外する
+// filterはtableのmetaとして永続化されるので、hashにはRustのversionで変わりうるstdのHasherではなくFNV-1aを使う
+use serde::{Deserialize, Serialize};
+
+// keyあたりのbit数とhashの数. false positiveはおよそ1%になる
+const BITS_PER_KEY: usize = 10;
+const HASHES: u32 = 7;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    // tableに追加したkeyのhashから作る
+    pub(crate) fn from_hashes(hashes: &[u64]) -> Self {
+        let words = (hashes.len() * BITS_PER_KEY).div_ceil(64).max(1);
+        let mut filter = Self {
+            bits: vec![0; words],
+            hashes: HASHES,
+        };
+        for &hash in hashes {
+            for bit in filter.positions(hash) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    // falseの場合keyは含まれない. trueでも含まれない場合がある(false positive)
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.positions(hash(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // 2つのhashの線形結合でhashesの数のbitの位置を求める(double hashing)
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 64) as u64;
+        let (h1, h2) = (hash & 0xFFFF_FFFF, hash >> 32);
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    // bitsが空のfilterは壊れたmetaから読んだもの. bitの位置を求められない
+    pub(crate) fn is_valid(&self) -> bool {
+        !self.bits.is_empty()
+    }
+}
+
+// FNV-1a(64bit)
+pub(crate) fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xCBF2_9CE4_8422_2325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn false_positive_rate() {
+        let keys = (0..10_000)
+            .map(|i| format!("key/{}", i))
+            .collect::<Vec<_>>();
+        let filter =
+            BloomFilter::from_hashes(&keys.iter().map(|key| hash(key)).collect::<Vec<_>>());
+        // 追加したkeyは必ず含まれる
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(&format!("missing/{}", i)))
+            .count();
+        assert!(false_positives < 300, "false positives {}", false_positives);
+
+        // 空のtableのfilterはkeyを含まない
+        assert!(!BloomFilter::from_hashes(&[]).may_contain("key"));
+    }
+}
@@ -0,0 +1,103 @@
+// keyの順に並んだ複数のsourceを1つのkeyの順の列にまとめる
+// 同じkeyが複数のsourceにある場合は、先に渡したsource(新しいもの)のvalueのみを返す
+use super::table::Value;
+use crate::Result;
+
+pub(crate) type Source<'a> = Box<dyn Iterator<Item = Result<(String, Value)>> + 'a>;
+
+pub(crate) struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    // sourceごとの次のentry. Noneは読み終えたsource
+    heads: Vec<Option<(String, Value)>>,
+    started: bool,
+}
+
+impl<'a> Merge<'a> {
+    pub(crate) fn new(sources: Vec<Source<'a>>) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        Self {
+            sources,
+            heads,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, Value)>> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.sources.len() {
+                self.advance(i)?;
+            }
+        }
+        // keyが同じ場合はmin_byが最初のsourceを返す
+        let i = match self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|(key, _)| (i, key)))
+            .min_by(|a, b| a.1.cmp(b.1))
+        {
+            Some((i, _)) => i,
+            None => return Ok(None),
+        };
+        let head = self.heads[i].take();
+        if let Some((key, _)) = &head {
+            // 古いsourceの同じkeyは読み飛ばす
+            for j in 0..self.heads.len() {
+                if self.heads[j].as_ref().is_some_and(|(k, _)| k == key) {
+                    self.advance(j)?;
+                }
+            }
+        }
+        self.advance(i)?;
+        Ok(head)
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<(String, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(entries: &[(&str, Option<u8>)]) -> Source<'static> {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| Ok((key.to_string(), value.map(|v| vec![v]))))
+            .collect::<Vec<_>>();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn newer_source_wins() {
+        let merged = Merge::new(vec![
+            source(&[("b", Some(2)), ("d", None)]),
+            source(&[("a", Some(1)), ("b", Some(1)), ("d", Some(1))]),
+            source(&[("b", Some(0)), ("c", Some(0)), ("e", Some(0))]),
+        ])
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(
+            merged,
+            vec![
+                ("a".to_owned(), Some(vec![1])),
+                ("b".to_owned(), Some(vec![2])),
+                ("c".to_owned(), Some(vec![0])),
+                ("d".to_owned(), None),
+                ("e".to_owned(), Some(vec![0])),
+            ]
+        );
+        assert_eq!(Merge::new(Vec::new()).count(), 0);
+    }
+}
@@ -0,0 +1,348 @@
+// keyの順に並べたentryを書き込んだfile(SSTable)
+//
+// | entry | entry | ... | meta(bincode) | metaのoffset(u64 BE) |
+//
+// entryはlogと同じformat. 削除はtombstoneのentryとして書き込み、下のlevelにある古いvalueを隠す
+// metaにはblockごとの先頭のkeyとoffset(sparse index)を持ち、getはblockを1つだけ読む
+// metaのBloom filterで含まれないkeyを判定できた場合、getはblockを読まない
+use super::bloom::{self, BloomFilter};
+use crate::{entry::Entry, KvsError, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom::*, Write},
+    path::{Path, PathBuf},
+};
+
+// sparse indexに登録する間隔. getで読むbytes数はおよそこの大きさになる
+const BLOCK_BYTES: u64 = 4 * 1024;
+
+// Noneは削除されたkey(tombstone)
+pub(crate) type Value = Option<Vec<u8>>;
+
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    // blockの先頭のkeyとoffset
+    index: Vec<(String, u64)>,
+    last_key: String,
+    entries: u64,
+    tombstones: u64,
+    // tombstoneを除いたentryのbytes数
+    live_bytes: u64,
+    // tombstoneを含む全てのkeyのfilter
+    filter: BloomFilter,
+}
+
+pub(crate) struct Table {
+    pub(crate) id: u64,
+    path: PathBuf,
+    file: File,
+    // entryが占める範囲. metaはこの後ろに続く
+    data_len: u64,
+    // metaを含むfileの大きさ
+    pub(crate) bytes: u64,
+    meta: Meta,
+}
+
+impl Table {
+    pub(crate) fn open(path: PathBuf, id: u64) -> Result<Self> {
+        let mut file = File::open(&path)?;
+        let bytes = file.seek(End(0))?;
+        // 途中までしか書かれていない、または壊れたtableの長さでbufferを確保しない
+        if bytes < 8 {
+            return Err(KvsError::CorruptData);
+        }
+        file.seek(End(-8))?;
+        let data_len = file.read_u64::<BE>()?;
+        let meta_len = data_len
+            .checked_add(8)
+            .and_then(|len| bytes.checked_sub(len))
+            .ok_or(KvsError::CorruptData)?;
+        file.seek(Start(data_len))?;
+        let mut buf = vec![0; meta_len as usize];
+        file.read_exact(&mut buf)?;
+        let meta: Meta = bincode::deserialize(&buf).map_err(|_| KvsError::CorruptData)?;
+        if meta.index.is_empty() || !meta.filter.is_valid() {
+            return Err(KvsError::CorruptData);
+        }
+        Ok(Self {
+            id,
+            path,
+            file,
+            data_len,
+            bytes,
+            meta,
+        })
+    }
+
+    // tableは空では書き出さないので、indexには必ず1つ以上のblockがある
+    pub(crate) fn first_key(&self) -> &str {
+        &self.meta.index[0].0
+    }
+
+    pub(crate) fn last_key(&self) -> &str {
+        &self.meta.last_key
+    }
+
+    pub(crate) fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key() <= last && first <= self.last_key()
+    }
+
+    // tombstoneを除いたentryの数. 下のlevelのentryと重複している場合も数える
+    pub(crate) fn live_entries(&self) -> u64 {
+        self.meta.entries - self.meta.tombstones
+    }
+
+    // tombstoneを除いたentryのbytes数. live_entriesと同様に下のlevelとの重複も数える
+    pub(crate) fn live_bytes(&self) -> u64 {
+        self.meta.live_bytes
+    }
+
+    // falseの場合keyを含まない. getの前に呼び、blockの読み込みを省く
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.overlaps(key, key) && self.meta.filter.may_contain(key)
+    }
+
+    // keyを含まない場合はNone. 削除されている場合はSome(None)を返す
+    pub(crate) fn get(&self, key: &str) -> Result<Option<Value>> {
+        if key < self.first_key() || self.last_key() < key {
+            return Ok(None);
+        }
+        let block = self
+            .meta
+            .index
+            .partition_point(|(first, _)| first.as_str() <= key);
+        let offset = self.meta.index[block - 1].1;
+        for item in self.iter_from(offset) {
+            let (k, value) = item?;
+            match k.as_str().cmp(key) {
+                Ordering::Less => continue,
+                Ordering::Equal => return Ok(Some(value)),
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_> {
+        self.iter_from(0)
+    }
+
+    fn iter_from(&self, offset: u64) -> Iter<'_> {
+        Iter {
+            r: BufReader::new(ReadAt {
+                file: &self.file,
+                position: offset,
+            }),
+            position: offset,
+            end: self.data_len,
+        }
+    }
+
+    pub(crate) fn remove(self) -> Result<()> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+// 同じfileを複数のiteratorから読めるよう、読み込みごとに自身の位置へseekする
+struct ReadAt<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+impl<'a> Read for ReadAt<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.seek(Start(self.position))?;
+        let n = self.file.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+pub(crate) struct Iter<'a> {
+    r: BufReader<ReadAt<'a>>,
+    position: u64,
+    end: u64,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(String, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.end {
+            return None;
+        }
+        match Entry::decode_with_check(&mut self.r) {
+            Ok(entry) => {
+                self.position += entry.len() as u64;
+                let value = if entry.is_deleted() {
+                    None
+                } else {
+                    Some(entry.value)
+                };
+                Some(Ok((entry.key, value)))
+            }
+            Err(err) => {
+                // 壊れた位置以降は読まない
+                self.position = self.end;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+// keyの順にentryを追加してtableを書き出す
+pub(crate) struct TableWriter {
+    id: u64,
+    path: PathBuf,
+    w: BufWriter<File>,
+    position: u64,
+    meta: Meta,
+    // finishでfilterを作るために追加したkeyのhashを持つ
+    key_hashes: Vec<u64>,
+}
+
+impl TableWriter {
+    pub(crate) fn create(path: PathBuf, id: u64) -> Result<Self> {
+        let file = File::create(&path)?;
+        Ok(Self {
+            id,
+            path,
+            w: BufWriter::new(file),
+            position: 0,
+            meta: Meta {
+                index: Vec::new(),
+                last_key: String::new(),
+                entries: 0,
+                tombstones: 0,
+                live_bytes: 0,
+                filter: BloomFilter::from_hashes(&[]),
+            },
+            key_hashes: Vec::new(),
+        })
+    }
+
+    // keyは前回追加したkeyより大きいこと
+    pub(crate) fn add(&mut self, key: String, value: Value) -> Result<()> {
+        debug_assert!(self.meta.entries == 0 || self.meta.last_key < key);
+        let entry = match value {
+            Some(value) => {
+                let entry = Entry::new(key, value)?;
+                self.meta.live_bytes += entry.len() as u64;
+                entry
+            }
+            None => {
+                self.meta.tombstones += 1;
+                Entry::new(key, Vec::new())?.mark_delete()?
+            }
+        };
+        let block_start = self.meta.index.last().map(|(_, offset)| *offset);
+        if block_start.is_none_or(|start| self.position - start >= BLOCK_BYTES) {
+            self.meta.index.push((entry.key.clone(), self.position));
+        }
+        self.position += entry.encode(&mut self.w)? as u64;
+        self.meta.entries += 1;
+        self.key_hashes.push(bloom::hash(&entry.key));
+        self.meta.last_key = entry.key;
+        Ok(())
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.position
+    }
+
+    // 永続化したうえで読み込み用に開き直す
+    pub(crate) fn finish(mut self) -> Result<Table> {
+        debug_assert!(self.meta.entries > 0, "empty table");
+        self.meta.filter = BloomFilter::from_hashes(&self.key_hashes);
+        self.w.write_all(&bincode::serialize(&self.meta)?)?;
+        self.w.write_u64::<BE>(self.position)?;
+        let file = self.w.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Table::open(self.path, self.id)
+    }
+}
+
+pub(crate) fn path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+
+    #[test]
+    fn write_and_read() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut w = TableWriter::create(path(tmp_dir.path(), 1), 1)?;
+        // 複数のblockに分かれる大きさにする
+        for i in 0..1000 {
+            let value = if i % 10 == 0 {
+                None
+            } else {
+                Some(vec![b'x'; i])
+            };
+            w.add(format!("{:04}", i), value)?;
+        }
+        let table = w.finish()?;
+
+        assert_eq!(table.first_key(), "0000");
+        assert_eq!(table.last_key(), "0999");
+        assert!(table.meta.index.len() > 1);
+        assert_eq!(table.live_entries(), 900);
+        let live_bytes = (1..1000)
+            .filter(|i| i % 10 != 0)
+            .map(|i| crate::entry::encoded_len("0000", &vec![b'x'; i]) as u64)
+            .sum::<u64>();
+        assert_eq!(table.live_bytes(), live_bytes);
+        assert_eq!(table.get("0123")?, Some(Some(vec![b'x'; 123])));
+        assert_eq!(table.get("0120")?, Some(None));
+        assert_eq!(table.get("0123x")?, None);
+        assert_eq!(table.get("1000")?, None);
+        // tombstoneのkeyもfilterに含まれる
+        assert!((0..1000).all(|i| table.may_contain(&format!("{:04}", i))));
+        assert!(!table.may_contain("1000"));
+
+        // 読み込み中にgetしても位置はずれない
+        let mut iter = table.iter();
+        assert_eq!(iter.next().unwrap()?.0, "0000");
+        assert_eq!(table.get("0999")?, Some(Some(vec![b'x'; 999])));
+        assert_eq!(iter.next().unwrap()?.0, "0001");
+        assert_eq!(iter.count(), 998);
+
+        let reopened = Table::open(path(tmp_dir.path(), 1), 1)?;
+        assert_eq!(reopened.bytes, table.bytes);
+        assert!(reopened.overlaps("0500", "2000"));
+        assert!(!reopened.overlaps("1000", "2000"));
+        Ok(())
+    }
+
+    #[test]
+    fn open_corrupt() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut w = TableWriter::create(path(tmp_dir.path(), 1), 1)?;
+        w.add("a".to_owned(), Some(vec![1]))?;
+        w.finish()?;
+        let bytes = std::fs::read(path(tmp_dir.path(), 1))?;
+
+        // 末尾のdata_lenが壊れている、または途中で途切れたtable
+        let mut corrupted = bytes.clone();
+        let len = corrupted.len();
+        corrupted[len - 8..].copy_from_slice(&u64::MAX.to_be_bytes());
+        for data in &[
+            corrupted,
+            bytes[..bytes.len() - 1].to_vec(),
+            bytes[..4].to_vec(),
+        ] {
+            std::fs::write(path(tmp_dir.path(), 2), data)?;
+            let err = Table::open(path(tmp_dir.path(), 2), 2).err().unwrap();
+            assert!(err.is_data_corrupt(), "{:?}", err);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+// kvsのmetricsを集計し、Prometheusのtext formatで書き出す
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+use std::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// latencyのhistogramのbucketの上限(秒)
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    // bucketごとの件数. 累積はencode時に計算する
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub(crate) fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+// engineの操作ごとの件数とlatency
+#[derive(Debug, Default)]
+pub(crate) struct EngineMetrics {
+    pub(crate) get: Histogram,
+    pub(crate) put: Histogram,
+    pub(crate) delete: Histogram,
+    // cacheを有効にしている場合のgetの内訳
+    pub(crate) cache_hits: Counter,
+    pub(crate) cache_misses: Counter,
+    // lsm engineのtableのBloom filterの判定. hitはtableを読まずに済んだ件数、
+    // false positiveはtableを読んだがkeyがなかった件数
+    pub(crate) filter_hits: Counter,
+    pub(crate) filter_false_positives: Counter,
+}
+
+// serverが受け付けたconnectionとrequest
+#[derive(Debug, Default)]
+pub(crate) struct ServerMetrics {
+    pub(crate) connections: Counter,
+    pub(crate) active_connections: Gauge,
+    pub(crate) requests: Counter,
+    pub(crate) request_errors: Counter,
+}
+
+// Kvs::statsで返すfileとindexの状態、およびopen以降の操作の件数
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub file_bytes: u64,
+    // 最新のentryが占めるbytes
+    pub live_bytes: u64,
+    // 上書きや削除で参照されなくなったentryのbytes. compactionで回収できる
+    pub stale_bytes: u64,
+    pub keys: u64,
+    pub gets: u64,
+    pub puts: u64,
+    pub deletes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub filter_hits: u64,
+    pub filter_false_positives: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "file_bytes: {}", self.file_bytes)?;
+        writeln!(f, "live_bytes: {}", self.live_bytes)?;
+        writeln!(f, "stale_bytes: {}", self.stale_bytes)?;
+        write!(f, "keys: {}", self.keys)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Encoder {
+    buf: String,
+}
+
+impl Encoder {
+    pub(crate) fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        self.sample(name, &[], value as f64);
+    }
+
+    pub(crate) fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "gauge");
+        self.sample(name, &[], value as f64);
+    }
+
+    // opのlabelごとに件数とlatencyを書き出す
+    pub(crate) fn operations(&mut self, metrics: &EngineMetrics) {
+        let ops = [
+            ("get", &metrics.get),
+            ("put", &metrics.put),
+            ("delete", &metrics.delete),
+        ];
+
+        let name = "kvs_operations_total";
+        self.header(name, "Number of operations on kvs file.", "counter");
+        for (op, histogram) in ops.iter() {
+            self.sample(name, &[("op", op)], histogram.count() as f64);
+        }
+
+        let name = "kvs_operation_duration_seconds";
+        self.header(name, "Latency of operations on kvs file.", "histogram");
+        for (op, histogram) in ops.iter() {
+            let bucket = format!("{}_bucket", name);
+            let mut cumulative = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += n.load(Ordering::Relaxed);
+                let le = le.to_string();
+                self.sample(&bucket, &[("op", op), ("le", &le)], cumulative as f64);
+            }
+            let count = histogram.count();
+            self.sample(&bucket, &[("op", op), ("le", "+Inf")], count as f64);
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            self.sample(&format!("{}_sum", name), &[("op", op)], sum);
+            self.sample(&format!("{}_count", name), &[("op", op)], count as f64);
+        }
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.buf
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.buf, "# HELP {} {}", name, help);
+        let _ = writeln!(self.buf, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.buf.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v))
+                .collect::<Vec<_>>();
+            let _ = write!(self.buf, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.buf, " {}", value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let metrics = EngineMetrics::default();
+        metrics.get.observe(Duration::from_micros(20));
+        metrics.get.observe(Duration::from_millis(2));
+        metrics.put.observe(Duration::from_secs(10));
+
+        let mut encoder = Encoder::default();
+        encoder.gauge("kvs_keys", "Number of keys.", 3);
+        encoder.operations(&metrics);
+        let text = encoder.finish();
+
+        assert!(text.contains("# TYPE kvs_keys gauge\nkvs_keys 3\n"));
+        assert!(text.contains("kvs_operations_total{op=\"get\"} 2\n"));
+        assert!(
+            text.contains("kvs_operation_duration_seconds_bucket{op=\"get\",le=\"0.00001\"} 0\n")
+        );
+        assert!(
+            text.contains("kvs_operation_duration_seconds_bucket{op=\"get\",le=\"0.00005\"} 1\n")
+        );
+        assert!(text.contains("kvs_operation_duration_seconds_bucket{op=\"get\",le=\"0.005\"} 2\n"));
+        // bucketの上限を超えたものは+Infのみに数える
+        assert!(text.contains("kvs_operation_duration_seconds_bucket{op=\"put\",le=\"5\"} 0\n"));
+        assert!(text.contains("kvs_operation_duration_seconds_bucket{op=\"put\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("kvs_operation_duration_seconds_sum{op=\"put\"} 10\n"));
+        assert!(text.contains("kvs_operation_duration_seconds_count{op=\"delete\"} 0\n"));
+    }
+}
@@ -1,9 +1,17 @@
-use crate::Result;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+use crate::{KvsError, Result, Stats};
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use std::{
+    convert::TryFrom,
+    io::{Cursor, Read},
 };
-use tracing::info;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::trace;
+
+const MAGIC_WORD: u8 = 0xFF;
+// 受信するpayloadの上限. 最大のkeyとvalueを持つentryに、長さやoffsetなどのfieldの分を加えたもの
+// 上限自体は大きいので、payloadはheaderの値でbufferを確保せず、受信した分だけ伸ばす
+pub(crate) const MAX_PAYLOAD_BYTES: u64 =
+    crate::MAX_KEY_BYTES as u64 + crate::MAX_VALUE_BYTES as u64 + 64;
 
 pub(crate) struct Message {
     header: Header,
@@ -12,33 +20,26 @@ pub(crate) struct Message {
 
 impl Message {
     pub(crate) fn from_payload(payload: Payload) -> Result<Self> {
-        // TODO: rewrite
-        match &payload {
-            Payload::EchoRequest { message, .. } => {
-                let mut buff = Vec::with_capacity(message.len());
-                std::io::Write::write_all(&mut buff, message.as_bytes())?;
-                Ok(Self {
-                    header: Header {
-                        magic_word: 0xFF,
-                        payload_kind: payload.kind(),
-                        payload_bytes: message.len() as u64,
-                    },
-                    encoded_payload: buff,
-                })
-            }
-            Payload::EchoResponse { message, .. } => {
-                let mut buff = Vec::with_capacity(message.len());
-                std::io::Write::write_all(&mut buff, message.as_bytes())?;
-                Ok(Self {
-                    header: Header {
-                        magic_word: 0xFF,
-                        payload_kind: payload.kind(),
-                        payload_bytes: message.len() as u64,
-                    },
-                    encoded_payload: buff,
-                })
-            }
-        }
+        let encoded_payload = payload.encode()?;
+        Ok(Self {
+            header: Header {
+                magic_word: MAGIC_WORD,
+                payload_kind: payload.kind(),
+                payload_bytes: encoded_payload.len() as u64,
+            },
+            encoded_payload,
+        })
+    }
+
+    // headerを個別にwriteすると小さいsegmentに分かれてNagleとdelayed ackで遅延するので
+    // bufferにまとめてから書き込む
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buff = Vec::with_capacity(1 + 1 + 8 + self.encoded_payload.len());
+        buff.write_u8(self.header.magic_word.to_be())?;
+        buff.write_u8((self.header.payload_kind as u8).to_be())?;
+        buff.write_u64::<BE>(self.header.payload_bytes.to_be())?;
+        buff.extend_from_slice(self.encoded_payload.as_slice());
+        Ok(buff)
     }
 }
 
@@ -49,20 +50,127 @@ pub(crate) struct Header {
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum PayloadKind {
     EchoRequest = 100,
     EchoResponse = 101,
+    AuthRequest = 110,
+    AuthResponse = 111,
+    GetRequest = 120,
+    GetResponse = 121,
+    PutRequest = 122,
+    PutResponse = 123,
+    DeleteRequest = 124,
+    DeleteResponse = 125,
+    KeysRequest = 126,
+    KeysResponse = 127,
+    StatsRequest = 128,
+    StatsResponse = 129,
+    ReplicateRequest = 130,
+    ReplicateResponse = 131,
+    RaftMessage = 140,
+    ErrorResponse = 199,
+}
+
+impl TryFrom<u8> for PayloadKind {
+    type Error = KvsError;
+
+    fn try_from(n: u8) -> std::result::Result<Self, Self::Error> {
+        use PayloadKind::*;
+        match n {
+            100 => Ok(EchoRequest),
+            101 => Ok(EchoResponse),
+            110 => Ok(AuthRequest),
+            111 => Ok(AuthResponse),
+            120 => Ok(GetRequest),
+            121 => Ok(GetResponse),
+            122 => Ok(PutRequest),
+            123 => Ok(PutResponse),
+            124 => Ok(DeleteRequest),
+            125 => Ok(DeleteResponse),
+            126 => Ok(KeysRequest),
+            127 => Ok(KeysResponse),
+            128 => Ok(StatsRequest),
+            129 => Ok(StatsResponse),
+            130 => Ok(ReplicateRequest),
+            131 => Ok(ReplicateResponse),
+            140 => Ok(RaftMessage),
+            199 => Ok(ErrorResponse),
+            _ => Err(KvsError::InvalidPayloadKind(n)),
+        }
+    }
+}
+
+// ErrorResponseでclientに返すエラーの種別
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    NotFound = 1,
+    PermissionDenied = 2,
+    ReadOnly = 3,
+    NotLeader = 4,
+    Internal = 255,
+}
+
+impl ErrorKind {
+    fn from_u8(n: u8) -> Self {
+        match n {
+            1 => ErrorKind::NotFound,
+            2 => ErrorKind::PermissionDenied,
+            3 => ErrorKind::ReadOnly,
+            4 => ErrorKind::NotLeader,
+            _ => ErrorKind::Internal,
+        }
+    }
+
+    // client側でKvsErrorに戻す
+    pub(crate) fn into_error(self, message: String) -> KvsError {
+        match self {
+            ErrorKind::NotFound => KvsError::NotFound,
+            ErrorKind::PermissionDenied => KvsError::PermissionDenied,
+            ErrorKind::ReadOnly => KvsError::ReadOnly,
+            // messageにはleaderのaddressが入っている
+            ErrorKind::NotLeader => KvsError::NotLeader(Some(message).filter(|m| !m.is_empty())),
+            ErrorKind::Internal => KvsError::Server(message),
+        }
+    }
+}
+
+impl From<&KvsError> for ErrorKind {
+    fn from(err: &KvsError) -> Self {
+        match err {
+            KvsError::NotFound => ErrorKind::NotFound,
+            KvsError::PermissionDenied => ErrorKind::PermissionDenied,
+            KvsError::ReadOnly => ErrorKind::ReadOnly,
+            KvsError::NotLeader(_) => ErrorKind::NotLeader,
+            _ => ErrorKind::Internal,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub(crate) enum Payload {
-    EchoRequest {
-        message: String,
-    },
-    #[allow(dead_code)]
-    EchoResponse {
-        message: String,
-    },
+    EchoRequest { message: String },
+    EchoResponse { message: String },
+    AuthRequest { token: String },
+    AuthResponse,
+    GetRequest { key: String },
+    GetResponse { value: Vec<u8> },
+    PutRequest { key: String, value: Vec<u8> },
+    PutResponse,
+    DeleteRequest { key: String },
+    DeleteResponse { value: Option<Vec<u8>> },
+    KeysRequest { prefix: String },
+    KeysResponse { keys: Vec<String> },
+    StatsRequest,
+    StatsResponse { stats: Stats },
+    // followerがleaderにoffset以降のlogを要求する
+    ReplicateRequest { offset: u64 },
+    // leaderはoffsetから始まるlogを追記されるたびに送り続ける
+    ReplicateResponse { offset: u64, log: Vec<u8> },
+    // Raftのnode間のmessage. 応答は別のRaftMessageとして送られる
+    RaftMessage { message: Vec<u8> },
+    ErrorResponse { kind: ErrorKind, message: String },
 }
 
 impl Payload {
@@ -70,57 +178,433 @@ impl Payload {
         match self {
             Payload::EchoRequest { .. } => PayloadKind::EchoRequest,
             Payload::EchoResponse { .. } => PayloadKind::EchoResponse,
+            Payload::AuthRequest { .. } => PayloadKind::AuthRequest,
+            Payload::AuthResponse => PayloadKind::AuthResponse,
+            Payload::GetRequest { .. } => PayloadKind::GetRequest,
+            Payload::GetResponse { .. } => PayloadKind::GetResponse,
+            Payload::PutRequest { .. } => PayloadKind::PutRequest,
+            Payload::PutResponse => PayloadKind::PutResponse,
+            Payload::DeleteRequest { .. } => PayloadKind::DeleteRequest,
+            Payload::DeleteResponse { .. } => PayloadKind::DeleteResponse,
+            Payload::KeysRequest { .. } => PayloadKind::KeysRequest,
+            Payload::KeysResponse { .. } => PayloadKind::KeysResponse,
+            Payload::StatsRequest => PayloadKind::StatsRequest,
+            Payload::StatsResponse { .. } => PayloadKind::StatsResponse,
+            Payload::ReplicateRequest { .. } => PayloadKind::ReplicateRequest,
+            Payload::ReplicateResponse { .. } => PayloadKind::ReplicateResponse,
+            Payload::RaftMessage { .. } => PayloadKind::RaftMessage,
+            Payload::ErrorResponse { .. } => PayloadKind::ErrorResponse,
+        }
+    }
+
+    // requestのspanに記録する操作名
+    pub(crate) fn op(&self) -> &'static str {
+        match self {
+            Payload::EchoRequest { .. } => "echo",
+            Payload::AuthRequest { .. } => "auth",
+            Payload::GetRequest { .. } => "get",
+            Payload::PutRequest { .. } => "put",
+            Payload::DeleteRequest { .. } => "delete",
+            Payload::KeysRequest { .. } => "keys",
+            Payload::StatsRequest => "stats",
+            Payload::ReplicateRequest { .. } => "replicate",
+            Payload::RaftMessage { .. } => "raft",
+            _ => "unknown",
+        }
+    }
+
+    // KeysRequestの場合はprefixを返す
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Payload::GetRequest { key }
+            | Payload::PutRequest { key, .. }
+            | Payload::DeleteRequest { key } => Some(key),
+            Payload::KeysRequest { prefix } => Some(prefix),
+            _ => None,
+        }
+    }
+
+    // requestで送る、またはresponseで返すvalueのbytes
+    pub(crate) fn value_bytes(&self) -> Option<usize> {
+        match self {
+            Payload::PutRequest { value, .. } | Payload::GetResponse { value } => Some(value.len()),
+            Payload::DeleteResponse { value } => value.as_ref().map(Vec::len),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn error(err: &KvsError) -> Self {
+        let message = match err {
+            KvsError::NotLeader(leader) => leader.clone().unwrap_or_default(),
+            _ => err.to_string(),
+        };
+        Payload::ErrorResponse {
+            kind: ErrorKind::from(err),
+            message,
+        }
+    }
+
+    // 可変長のfieldが1つの場合はそのまま書き込み、複数ある場合は先頭のfieldに長さをつける
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buff = Vec::new();
+        match self {
+            Payload::EchoRequest { message } | Payload::EchoResponse { message } => {
+                buff.extend_from_slice(message.as_bytes())
+            }
+            Payload::AuthRequest { token } => buff.extend_from_slice(token.as_bytes()),
+            Payload::GetRequest { key } | Payload::DeleteRequest { key } => {
+                buff.extend_from_slice(key.as_bytes())
+            }
+            Payload::KeysRequest { prefix } => buff.extend_from_slice(prefix.as_bytes()),
+            Payload::KeysResponse { keys } => {
+                for key in keys {
+                    buff.write_u16::<BE>(key.len() as u16)?;
+                    buff.extend_from_slice(key.as_bytes());
+                }
+            }
+            Payload::GetResponse { value } => buff.extend_from_slice(value),
+            Payload::PutRequest { key, value } => {
+                if key.len() > crate::MAX_KEY_BYTES as usize {
+                    return Err(KvsError::MaxKeyBytes);
+                }
+                buff.write_u16::<BE>(key.len() as u16)?;
+                buff.extend_from_slice(key.as_bytes());
+                buff.extend_from_slice(value);
+            }
+            Payload::DeleteResponse { value } => match value {
+                Some(value) => {
+                    buff.write_u8(1)?;
+                    buff.extend_from_slice(value);
+                }
+                None => buff.write_u8(0)?,
+            },
+            Payload::StatsResponse { stats } => {
+                for n in &[
+                    stats.file_bytes,
+                    stats.live_bytes,
+                    stats.stale_bytes,
+                    stats.keys,
+                    stats.gets,
+                    stats.puts,
+                    stats.deletes,
+                    stats.cache_hits,
+                    stats.cache_misses,
+                    stats.filter_hits,
+                    stats.filter_false_positives,
+                ] {
+                    buff.write_u64::<BE>(*n)?;
+                }
+            }
+            Payload::ReplicateRequest { offset } => buff.write_u64::<BE>(*offset)?,
+            Payload::RaftMessage { message } => buff.extend_from_slice(message),
+            Payload::ReplicateResponse { offset, log } => {
+                buff.write_u64::<BE>(*offset)?;
+                buff.extend_from_slice(log);
+            }
+            Payload::ErrorResponse { kind, message } => {
+                buff.write_u8(*kind as u8)?;
+                buff.extend_from_slice(message.as_bytes());
+            }
+            Payload::AuthResponse | Payload::PutResponse | Payload::StatsRequest => (),
         }
+        Ok(buff)
+    }
+
+    fn decode(kind: PayloadKind, buff: Vec<u8>) -> Result<Self> {
+        let string = |buff: Vec<u8>| String::from_utf8(buff).map_err(|err| err.utf8_error());
+        let payload = match kind {
+            PayloadKind::EchoRequest => Payload::EchoRequest {
+                message: string(buff)?,
+            },
+            PayloadKind::EchoResponse => Payload::EchoResponse {
+                message: string(buff)?,
+            },
+            PayloadKind::AuthRequest => Payload::AuthRequest {
+                token: string(buff)?,
+            },
+            PayloadKind::AuthResponse => Payload::AuthResponse,
+            PayloadKind::GetRequest => Payload::GetRequest { key: string(buff)? },
+            PayloadKind::GetResponse => Payload::GetResponse { value: buff },
+            PayloadKind::PutRequest => {
+                let mut r = Cursor::new(buff);
+                let key_len = r.read_u16::<BE>()?;
+                let mut key = vec![0; key_len as usize];
+                r.read_exact(&mut key)?;
+                let mut value = Vec::new();
+                r.read_to_end(&mut value)?;
+                Payload::PutRequest {
+                    key: string(key)?,
+                    value,
+                }
+            }
+            PayloadKind::PutResponse => Payload::PutResponse,
+            PayloadKind::DeleteRequest => Payload::DeleteRequest { key: string(buff)? },
+            PayloadKind::DeleteResponse => {
+                let mut r = Cursor::new(buff);
+                let value = match r.read_u8()? {
+                    0 => None,
+                    _ => {
+                        let mut value = Vec::new();
+                        r.read_to_end(&mut value)?;
+                        Some(value)
+                    }
+                };
+                Payload::DeleteResponse { value }
+            }
+            PayloadKind::KeysRequest => Payload::KeysRequest {
+                prefix: string(buff)?,
+            },
+            PayloadKind::KeysResponse => {
+                let len = buff.len() as u64;
+                let mut r = Cursor::new(buff);
+                let mut keys = Vec::new();
+                while r.position() < len {
+                    let mut key = vec![0; r.read_u16::<BE>()? as usize];
+                    r.read_exact(&mut key)?;
+                    keys.push(string(key)?);
+                }
+                Payload::KeysResponse { keys }
+            }
+            PayloadKind::StatsRequest => Payload::StatsRequest,
+            PayloadKind::StatsResponse => {
+                let mut r = Cursor::new(buff);
+                Payload::StatsResponse {
+                    stats: Stats {
+                        file_bytes: r.read_u64::<BE>()?,
+                        live_bytes: r.read_u64::<BE>()?,
+                        stale_bytes: r.read_u64::<BE>()?,
+                        keys: r.read_u64::<BE>()?,
+                        gets: r.read_u64::<BE>()?,
+                        puts: r.read_u64::<BE>()?,
+                        deletes: r.read_u64::<BE>()?,
+                        cache_hits: r.read_u64::<BE>()?,
+                        cache_misses: r.read_u64::<BE>()?,
+                        filter_hits: r.read_u64::<BE>()?,
+                        filter_false_positives: r.read_u64::<BE>()?,
+                    },
+                }
+            }
+            PayloadKind::ReplicateRequest => Payload::ReplicateRequest {
+                offset: Cursor::new(buff).read_u64::<BE>()?,
+            },
+            PayloadKind::ReplicateResponse => {
+                let mut r = Cursor::new(buff);
+                let offset = r.read_u64::<BE>()?;
+                let mut log = Vec::new();
+                r.read_to_end(&mut log)?;
+                Payload::ReplicateResponse { offset, log }
+            }
+            PayloadKind::RaftMessage => Payload::RaftMessage { message: buff },
+            PayloadKind::ErrorResponse => {
+                let mut r = Cursor::new(buff);
+                let kind = ErrorKind::from_u8(r.read_u8()?);
+                let mut message = Vec::new();
+                r.read_to_end(&mut message)?;
+                Payload::ErrorResponse {
+                    kind,
+                    message: string(message)?,
+                }
+            }
+        };
+        Ok(payload)
     }
 }
 
+// OperatorがやりとりするstreamでTcpStreamとTlsStreamを同様に扱うためのtrait
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S> Stream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
 pub(crate) struct Operator {
-    conn: TcpStream,
+    conn: Box<dyn Stream>,
 }
 
 impl Operator {
-    pub(crate) fn with_stream(stream: TcpStream) -> Result<Self> {
-        Ok(Self { conn: stream })
+    pub(crate) fn new(conn: Box<dyn Stream>) -> Self {
+        Self { conn }
+    }
+
+    pub(crate) fn with_stream<S: Stream + 'static>(stream: S) -> Result<Self> {
+        Ok(Self::new(Box::new(stream)))
     }
 
     pub(crate) async fn send(&mut self, message: Message) -> Result<()> {
-        self.conn
-            .write_u8(message.header.magic_word.to_be())
-            .await?;
-        self.conn
-            .write_u8((message.header.payload_kind as u8).to_be())
-            .await?;
-        self.conn
-            .write_u64(message.header.payload_bytes.to_be())
-            .await?;
-        self.conn
-            .write_all(message.encoded_payload.as_slice())
-            .await?;
+        // byteorderのWriteBytesExtと衝突するのでここでimportする
+        use tokio::io::AsyncWriteExt;
+
+        self.conn.write_all(message.to_bytes()?.as_slice()).await?;
+        // TLSの場合、sessionにbufferされたままになるのでflushする
+        self.conn.flush().await?;
         Ok(())
     }
 
     pub(crate) async fn receive(&mut self) -> Result<Payload> {
+        use tokio::io::AsyncReadExt;
+
         let magic_word = u8::from_be(self.conn.read_u8().await?);
-        info!("read magic_word {}", magic_word);
+        if magic_word != MAGIC_WORD {
+            return Err(KvsError::InvalidMagicWord(magic_word));
+        }
+        let payload_kind = PayloadKind::try_from(u8::from_be(self.conn.read_u8().await?))?;
+        let payload_bytes = u64::from_be(self.conn.read_u64().await?);
+        trace!(magic_word, ?payload_kind, payload_bytes, "Read header");
+        if payload_bytes > MAX_PAYLOAD_BYTES {
+            return Err(KvsError::MaxPayloadBytes(payload_bytes));
+        }
 
-        let payload_kind = u8::from_be(self.conn.read_u8().await?);
-        // TODO: try_from定義する
-        info!("read payload_kind {}", payload_kind);
+        // headerの長さのまま確保すると、認証前のclientがheaderだけで巨大なallocationを起こせる
+        let mut buff = Vec::new();
+        (&mut self.conn)
+            .take(payload_bytes)
+            .read_to_end(&mut buff)
+            .await?;
+        if (buff.len() as u64) < payload_bytes {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
 
-        let payload_bytes = u64::from_be(self.conn.read_u64().await?);
-        info!("read payload_bytes {}", payload_bytes);
+        Payload::decode(payload_kind, buff)
+    }
+}
 
-        // Vecでbuffを確保して、conn.take().read_to_end()を行おうとしたが
-        // as_ref()が使えずmoveを回避できない
-        let mut buff = bytes::BytesMut::with_capacity(payload_bytes as usize);
-        self.conn.read_buf(&mut buff).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
 
-        let message = String::from_utf8(buff.to_vec()).map_err(anyhow::Error::from)?;
+    fn encode_decode(payload: Payload) -> StdResult<Payload, Error> {
+        let kind = payload.kind();
+        Ok(Payload::decode(kind, payload.encode()?)?)
+    }
 
-        match payload_kind {
-            100 => Ok(Payload::EchoRequest { message }),
-            101 => Ok(Payload::EchoResponse { message }),
-            _ => panic!("unexpected..."),
+    #[test]
+    fn put_request() -> StdResult<(), Error> {
+        match encode_decode(Payload::PutRequest {
+            key: "key1".to_owned(),
+            value: vec![1, 2, 3],
+        })? {
+            Payload::PutRequest { key, value } => {
+                assert_eq!(key, "key1");
+                assert_eq!(value, vec![1, 2, 3]);
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delete_response() -> StdResult<(), Error> {
+        match encode_decode(Payload::DeleteResponse {
+            value: Some(vec![]),
+        })? {
+            Payload::DeleteResponse { value } => assert_eq!(value, Some(vec![])),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        match encode_decode(Payload::DeleteResponse { value: None })? {
+            Payload::DeleteResponse { value } => assert_eq!(value, None),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keys_response() -> StdResult<(), Error> {
+        let keys = vec!["a".to_owned(), "".to_owned(), "あ".to_owned()];
+        match encode_decode(Payload::KeysResponse { keys: keys.clone() })? {
+            Payload::KeysResponse { keys: decoded } => assert_eq!(decoded, keys),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stats_response() -> StdResult<(), Error> {
+        let stats = Stats {
+            file_bytes: 100,
+            live_bytes: 60,
+            stale_bytes: 40,
+            keys: 3,
+            gets: 1,
+            puts: 4,
+            deletes: u64::MAX,
+            cache_hits: 5,
+            cache_misses: 6,
+            filter_hits: 7,
+            filter_false_positives: 8,
+        };
+        match encode_decode(Payload::StatsResponse {
+            stats: stats.clone(),
+        })? {
+            Payload::StatsResponse { stats: decoded } => assert_eq!(decoded, stats),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replicate_response() -> StdResult<(), Error> {
+        match encode_decode(Payload::ReplicateResponse {
+            offset: 10,
+            log: vec![1, 2],
+        })? {
+            Payload::ReplicateResponse { offset, log } => {
+                assert_eq!(offset, 10);
+                assert_eq!(log, vec![1, 2]);
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn error_response() -> StdResult<(), Error> {
+        match encode_decode(Payload::error(&KvsError::PermissionDenied))? {
+            Payload::ErrorResponse { kind, message } => {
+                assert!(kind.into_error(message).is_permission_denied())
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn not_leader() -> StdResult<(), Error> {
+        let err = KvsError::NotLeader(Some("127.0.0.1:4101".to_owned()));
+        match encode_decode(Payload::error(&err))? {
+            Payload::ErrorResponse { kind, message } => {
+                assert_eq!(kind.into_error(message).to_string(), err.to_string())
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_payload_kind() {
+        assert!(PayloadKind::try_from(0).is_err());
+    }
+
+    // 不正なheaderや途切れたpayloadは、headerの長さのbufferを確保せずにエラーとする
+    #[tokio::test]
+    async fn reject_invalid_header() {
+        let header = |magic_word: u8, payload_bytes: u64| {
+            let mut buff = vec![magic_word, PayloadKind::PutRequest as u8];
+            buff.write_u64::<BE>(payload_bytes.to_be()).unwrap();
+            Operator::with_stream(Cursor::new(buff)).unwrap()
+        };
+
+        match header(0x00, 1).receive().await {
+            Err(KvsError::InvalidMagicWord(0x00)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match header(MAGIC_WORD, u64::MAX).receive().await {
+            Err(KvsError::MaxPayloadBytes(u64::MAX)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        // 上限以下でも、headerの長さのbufferは確保せず受信できた分で判断する
+        match header(MAGIC_WORD, MAX_PAYLOAD_BYTES).receive().await {
+            Err(err) if err.is_eof() => (),
+            result => panic!("unexpected result {:?}", result),
         }
     }
 }
@@ -0,0 +1,246 @@
+// Redis serialization protocol(RESP2)
+// https://redis.io/topics/protocol
+use crate::{protocol::message::Stream, KvsError, Result};
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+// 1 requestで受け付けるarrayの要素数とbulk stringのbytesの上限
+// bulk stringはRedisのproto-max-bulk-lenのdefaultに合わせる
+const MAX_ARRAY_LEN: i64 = 1024 * 1024;
+const MAX_BULK_BYTES: i64 = 512 * 1024 * 1024;
+// inline commandやarray, bulk stringの長さを表す1行のbytesの上限
+const MAX_LINE_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub(crate) fn ok() -> Self {
+        Value::Simple("OK".to_owned())
+    }
+
+    pub(crate) fn encode(&self, buff: &mut Vec<u8>) {
+        match self {
+            Value::Simple(s) => {
+                buff.push(b'+');
+                buff.extend_from_slice(s.as_bytes());
+            }
+            Value::Error(s) => {
+                buff.push(b'-');
+                buff.extend_from_slice(s.as_bytes());
+            }
+            Value::Integer(n) => {
+                buff.push(b':');
+                buff.extend_from_slice(n.to_string().as_bytes());
+            }
+            Value::Bulk(bytes) => {
+                buff.push(b'$');
+                buff.extend_from_slice(bytes.len().to_string().as_bytes());
+                buff.extend_from_slice(b"\r\n");
+                buff.extend_from_slice(bytes);
+            }
+            Value::Null => buff.extend_from_slice(b"$-1"),
+            Value::Array(values) => {
+                buff.push(b'*');
+                buff.extend_from_slice(values.len().to_string().as_bytes());
+                buff.extend_from_slice(b"\r\n");
+                values.iter().for_each(|value| value.encode(buff));
+                // 要素の末尾でCRLFを書いているので、array自体のCRLFは不要
+                return;
+            }
+        }
+        buff.extend_from_slice(b"\r\n");
+    }
+}
+
+fn protocol_error<M: Into<String>>(message: M) -> KvsError {
+    KvsError::RespProtocol(message.into())
+}
+
+pub(crate) struct Connection {
+    conn: BufReader<Box<dyn Stream>>,
+}
+
+impl Connection {
+    pub(crate) fn new(conn: Box<dyn Stream>) -> Self {
+        Self {
+            conn: BufReader::new(conn),
+        }
+    }
+
+    // commandとその引数を返す
+    // redis-cliやclient libraryが送るarray形式と、telnet等で送られるinline形式の両方を受け付ける
+    pub(crate) async fn read_command(&mut self) -> Result<Vec<Vec<u8>>> {
+        loop {
+            let line = self.read_line().await?;
+            match line.first() {
+                Some(b'*') => {
+                    let len = parse_integer(&line[1..])?;
+                    if !(0..=MAX_ARRAY_LEN).contains(&len) {
+                        return Err(protocol_error("invalid multibulk length"));
+                    }
+                    // headerの長さで確保せず、受信した引数の分だけ伸ばす
+                    let mut args = Vec::new();
+                    for _ in 0..len {
+                        args.push(self.read_bulk().await?);
+                    }
+                    if !args.is_empty() {
+                        return Ok(args);
+                    }
+                }
+                Some(_) => {
+                    let args = line
+                        .split(|b| b.is_ascii_whitespace())
+                        .filter(|arg| !arg.is_empty())
+                        .map(<[u8]>::to_vec)
+                        .collect::<Vec<_>>();
+                    if !args.is_empty() {
+                        return Ok(args);
+                    }
+                }
+                // 空行は無視する
+                None => (),
+            }
+        }
+    }
+
+    pub(crate) async fn write_value(&mut self, value: &Value) -> Result<()> {
+        let mut buff = Vec::new();
+        value.encode(&mut buff);
+        self.conn.get_mut().write_all(&buff).await?;
+        self.conn.get_mut().flush().await?;
+        Ok(())
+    }
+
+    async fn read_bulk(&mut self) -> Result<Vec<u8>> {
+        let line = self.read_line().await?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line)
+            )));
+        }
+        let len = parse_integer(&line[1..])?;
+        if !(0..=MAX_BULK_BYTES).contains(&len) {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        // 長さだけを送って本体を送らないclientに大きなbufferを確保させないよう、受信した分だけ伸ばす
+        let mut bulk = Vec::new();
+        (&mut self.conn)
+            .take(len as u64 + 2)
+            .read_to_end(&mut bulk)
+            .await?;
+        if bulk.len() < len as usize + 2 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if !bulk.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string must end with CRLF"));
+        }
+        bulk.truncate(len as usize);
+        Ok(bulk)
+    }
+
+    // CRLF(LF)を除いた1行を返す. connectionが閉じられた場合はEOFのエラーを返す
+    async fn read_line(&mut self) -> Result<Vec<u8>> {
+        let mut line = Vec::new();
+        let n = (&mut self.conn)
+            .take(MAX_LINE_BYTES)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if n as u64 == MAX_LINE_BYTES && line.last() != Some(&b'\n') {
+            return Err(protocol_error("too big line"));
+        }
+        while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(line)
+    }
+}
+
+fn parse_integer(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| protocol_error("invalid integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn connection(input: &[u8]) -> Connection {
+        Connection::new(Box::new(Cursor::new(input.to_vec())))
+    }
+
+    #[tokio::test]
+    async fn read_array_command() -> Result<()> {
+        let mut conn = connection(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nva\r\nl\r\n");
+        assert_eq!(
+            conn.read_command().await?,
+            vec![b"SET".to_vec(), b"key".to_vec(), b"va\r\nl".to_vec()]
+        );
+        assert!(conn.read_command().await.unwrap_err().is_eof());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_inline_command() -> Result<()> {
+        let mut conn = connection(b"\r\nGET  key\r\nPING\n");
+        assert_eq!(
+            conn.read_command().await?,
+            vec![b"GET".to_vec(), b"key".to_vec()]
+        );
+        assert_eq!(conn.read_command().await?, vec![b"PING".to_vec()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_bulk() {
+        let mut conn = connection(b"*1\r\n+GET\r\n");
+        assert!(conn.read_command().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let mut conn = connection(format!("*1\r\n${}\r\n", MAX_BULK_BYTES + 1).as_bytes());
+        assert!(conn.read_command().await.is_err());
+
+        // 長さに満たないbulk stringはEOF
+        let mut conn = connection(b"*1\r\n$1000000\r\nGET\r\n");
+        assert!(conn.read_command().await.unwrap_err().is_eof());
+
+        let mut conn = connection(&vec![b'a'; MAX_LINE_BYTES as usize + 1]);
+        match conn.read_command().await {
+            Err(KvsError::RespProtocol(message)) => assert_eq!(message, "too big line"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn encode() {
+        let mut buff = Vec::new();
+        Value::Array(vec![
+            Value::ok(),
+            Value::Error("ERR x".to_owned()),
+            Value::Integer(-1),
+            Value::Bulk(b"v".to_vec()),
+            Value::Null,
+        ])
+        .encode(&mut buff);
+        assert_eq!(
+            buff.as_slice(),
+            &b"*5\r\n+OK\r\n-ERR x\r\n:-1\r\n$1\r\nv\r\n$-1\r\n"[..]
+        );
+    }
+}
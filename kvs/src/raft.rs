@@ -0,0 +1,143 @@
+// Raftによるkvs clusterの合意
+// https://raft.github.io/raft.pdf
+// kvsへの書き込みはkvsのentryをRaftのlogとして合意したうえで、各nodeのkvsに適用する
+mod node;
+mod storage;
+
+pub(crate) use node::{Command, Envelope, LogEntry, Node};
+pub(crate) use storage::Storage;
+
+use crate::{KvsError, Result};
+use serde::Deserialize;
+use std::{collections::HashSet, fs, path::Path, path::PathBuf};
+
+pub type NodeId = u64;
+
+// clusterを構成するnode
+//
+// ```toml
+// [[nodes]]
+// id = 1
+// addr = "127.0.0.1:4101"
+//
+// [[nodes]]
+// id = 2
+// addr = "127.0.0.1:4102"
+// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct Cluster {
+    nodes: Vec<Member>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Member {
+    pub id: NodeId,
+    pub addr: String,
+}
+
+impl Cluster {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Cluster::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self> {
+        let cluster: Cluster = toml::from_str(s).map_err(|err| invalid(err.to_string()))?;
+        if cluster.nodes.is_empty() {
+            return Err(invalid("cluster requires at least one node"));
+        }
+        let mut ids = HashSet::new();
+        if let Some(member) = cluster.nodes.iter().find(|member| !ids.insert(member.id)) {
+            return Err(invalid(format!("duplicate node id {}", member.id)));
+        }
+        Ok(cluster)
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.nodes
+    }
+
+    pub(crate) fn addr(&self, id: NodeId) -> Option<&str> {
+        self.nodes
+            .iter()
+            .find(|member| member.id == id)
+            .map(|member| member.addr.as_str())
+    }
+}
+
+// serverをRaft clusterのnodeとして起動する際の設定
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub id: NodeId,
+    pub cluster: Cluster,
+    // Raftのlogと状態を保存するdirectory
+    pub dir: PathBuf,
+}
+
+impl RaftConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self.cluster.addr(self.id) {
+            Some(_) => Ok(()),
+            None => Err(invalid(format!(
+                "node {} is not a member of cluster",
+                self.id
+            ))),
+        }
+    }
+
+    pub(crate) fn peers(&self) -> Vec<NodeId> {
+        self.cluster
+            .nodes
+            .iter()
+            .map(|member| member.id)
+            .filter(|&id| id != self.id)
+            .collect()
+    }
+}
+
+fn invalid<M: Into<String>>(message: M) -> KvsError {
+    KvsError::InvalidCluster(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster() -> Result<()> {
+        let cluster = Cluster::from_toml(
+            r#"
+[[nodes]]
+id = 1
+addr = "127.0.0.1:4101"
+
+[[nodes]]
+id = 2
+addr = "127.0.0.1:4102"
+"#,
+        )?;
+        assert_eq!(cluster.addr(2), Some("127.0.0.1:4102"));
+
+        let config = RaftConfig {
+            id: 1,
+            cluster: cluster.clone(),
+            dir: PathBuf::new(),
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.peers(), vec![2]);
+        assert!(RaftConfig { id: 3, ..config }.validate().is_err());
+
+        assert!(Cluster::from_toml("nodes = []").is_err());
+        assert!(Cluster::from_toml(
+            r#"
+[[nodes]]
+id = 1
+addr = "a"
+[[nodes]]
+id = 1
+addr = "b"
+"#
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,793 @@
+// Raftのstate machine
+// networkやtimerには依存せず、tick()とstep()で状態を進め、送信するmessageをoutboxに溜める
+use super::{
+    storage::{HardState, Storage},
+    NodeId,
+};
+use crate::{KvsError, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+// followerがleaderを待つtick数の範囲. nodeごとにrandomに決める
+const ELECTION_TIMEOUT_TICKS: (u32, u32) = (10, 20);
+const HEARTBEAT_TICKS: u32 = 3;
+// 1度のAppendEntriesで送るentryの上限
+const MAX_APPEND_ENTRIES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    pub(crate) term: u64,
+    pub(crate) command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Command {
+    // leaderに選出された際に追加し、以前のtermのentryをcommitする
+    Noop,
+    // encodeされたkvsのentry
+    Kvs(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub(crate) from: NodeId,
+    pub(crate) to: NodeId,
+    pub(crate) term: u64,
+    pub(crate) message: Message,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Message {
+    RequestVote {
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        granted: bool,
+    },
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+        // leadershipの確認(ReadIndex)に利用する
+        read_ctx: u64,
+    },
+    AppendEntriesResponse {
+        success: bool,
+        // 成功した場合は一致したlogの末尾. 失敗した場合はleaderが次に試すindexの手がかり
+        match_index: u64,
+        read_ctx: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    next: u64,
+    matched: u64,
+}
+
+// leadershipが確認できるまで待機しているread
+struct ReadIndex {
+    ctx: u64,
+    index: u64,
+    acks: HashSet<NodeId>,
+}
+
+pub(crate) struct Node {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    storage: Storage,
+    role: Role,
+    leader: Option<NodeId>,
+    commit_index: u64,
+    applied: u64,
+    election_elapsed: u32,
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    votes: HashSet<NodeId>,
+    progress: HashMap<NodeId, Progress>,
+    read_ctx: u64,
+    reads: Vec<ReadIndex>,
+    ready_reads: Vec<(u64, u64)>,
+    outbox: Vec<Envelope>,
+}
+
+impl Node {
+    pub(crate) fn new(id: NodeId, peers: Vec<NodeId>, storage: Storage) -> Self {
+        let applied = storage.state().applied;
+        let mut node = Self {
+            id,
+            peers,
+            storage,
+            role: Role::Follower,
+            leader: None,
+            commit_index: 0,
+            applied,
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            read_ctx: 0,
+            reads: Vec::new(),
+            ready_reads: Vec::new(),
+            outbox: Vec::new(),
+        };
+        node.reset_election_timeout();
+        node
+    }
+
+    pub(crate) fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.role
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub(crate) fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub(crate) fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    pub(crate) fn term(&self) -> u64 {
+        self.storage.state().term
+    }
+
+    pub(crate) fn tick(&mut self) -> Result<()> {
+        match self.role {
+            Role::Leader => {
+                self.heartbeat_elapsed += 1;
+                if self.heartbeat_elapsed >= HEARTBEAT_TICKS {
+                    self.heartbeat_elapsed = 0;
+                    self.broadcast_append();
+                }
+                Ok(())
+            }
+            Role::Follower | Role::Candidate => {
+                self.election_elapsed += 1;
+                if self.election_elapsed >= self.election_timeout {
+                    self.campaign()
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    // clientからの書き込みをlogに追加し、そのindexとtermを返す
+    pub(crate) fn propose(&mut self, command: Command) -> Result<(u64, u64)> {
+        if !self.is_leader() {
+            return Err(KvsError::NotLeader(None));
+        }
+        let term = self.term();
+        self.storage.append(&[LogEntry { term, command }])?;
+        let index = self.storage.last_index();
+        self.maybe_commit();
+        self.broadcast_append();
+        Ok((index, term))
+    }
+
+    // linearizableなreadのためにReadIndexを開始し、識別子を返す
+    // leadershipが確認できるとready_readsでreadしてよいindexが返る
+    pub(crate) fn read_index(&mut self) -> Result<u64> {
+        if !self.is_leader() {
+            return Err(KvsError::NotLeader(None));
+        }
+        self.read_ctx += 1;
+        let mut acks = HashSet::new();
+        acks.insert(self.id);
+        self.reads.push(ReadIndex {
+            ctx: self.read_ctx,
+            index: self.commit_index,
+            acks,
+        });
+        self.broadcast_append();
+        self.maybe_ready_reads();
+        Ok(self.read_ctx)
+    }
+
+    pub(crate) fn step(&mut self, envelope: Envelope) -> Result<()> {
+        if envelope.to != self.id {
+            return Ok(());
+        }
+        let term = self.term();
+        if envelope.term > term {
+            let leader = match envelope.message {
+                Message::AppendEntries { .. } => Some(envelope.from),
+                _ => None,
+            };
+            self.become_follower(envelope.term, leader)?;
+        } else if envelope.term < term {
+            // 古いtermのnodeには現在のtermを伝えて追従させる
+            match envelope.message {
+                Message::RequestVote { .. } => {
+                    self.send(envelope.from, Message::Vote { granted: false })
+                }
+                Message::AppendEntries { .. } => self.send(
+                    envelope.from,
+                    Message::AppendEntriesResponse {
+                        success: false,
+                        match_index: 0,
+                        read_ctx: 0,
+                    },
+                ),
+                _ => (),
+            }
+            return Ok(());
+        }
+
+        match envelope.message {
+            Message::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => self.handle_request_vote(envelope.from, last_log_index, last_log_term),
+            Message::Vote { granted } => self.handle_vote(envelope.from, granted),
+            Message::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                commit,
+                read_ctx,
+            } => self.handle_append_entries(
+                envelope.from,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                commit,
+                read_ctx,
+            ),
+            Message::AppendEntriesResponse {
+                success,
+                match_index,
+                read_ctx,
+            } => {
+                self.handle_append_response(envelope.from, success, match_index, read_ctx);
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn take_messages(&mut self) -> Vec<Envelope> {
+        mem::take(&mut self.outbox)
+    }
+
+    // leadershipが確認できたreadの(識別子, index)
+    pub(crate) fn take_ready_reads(&mut self) -> Vec<(u64, u64)> {
+        mem::take(&mut self.ready_reads)
+    }
+
+    // commitされたがまだkvsに適用していないentry. 適用したentryはset_appliedで反映する
+    pub(crate) fn committed(&self) -> Vec<(u64, LogEntry)> {
+        let from = self.applied + 1;
+        (from..=self.commit_index)
+            .filter_map(|index| {
+                self.storage
+                    .entry(index)
+                    .map(|entry| (index, entry.clone()))
+            })
+            .collect()
+    }
+
+    // indexまでのentryをkvsに適用した
+    pub(crate) fn set_applied(&mut self, index: u64) {
+        self.applied = self.applied.max(index.min(self.commit_index));
+    }
+
+    // kvsへの適用が完了したindexを永続化する
+    pub(crate) fn persist_applied(&mut self) -> Result<()> {
+        if self.storage.state().applied == self.applied {
+            return Ok(());
+        }
+        let state = HardState {
+            applied: self.applied,
+            ..self.storage.state().clone()
+        };
+        self.storage.set_state(state)
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        let term = self.term() + 1;
+        self.set_hard_state(term, Some(self.id))?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.reset_election_timeout();
+        self.votes.clear();
+        self.votes.insert(self.id);
+
+        let (last_log_index, last_log_term) = (self.storage.last_index(), self.storage.last_term());
+        for peer in self.peers.clone() {
+            self.send(
+                peer,
+                Message::RequestVote {
+                    last_log_index,
+                    last_log_term,
+                },
+            );
+        }
+        // 1 nodeのclusterではそのままleaderになる
+        if self.has_quorum(&self.votes) {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term != self.term() {
+            self.set_hard_state(term, None)?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reads.clear();
+        self.reset_election_timeout();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        let next = self.storage.last_index() + 1;
+        self.progress = self
+            .peers
+            .iter()
+            .map(|&peer| (peer, Progress { next, matched: 0 }))
+            .collect();
+        // 自身のtermのentryがcommitされるまで以前のtermのentryはcommitできない
+        self.propose(Command::Noop).map(|_| ())
+    }
+
+    fn handle_request_vote(
+        &mut self,
+        candidate: NodeId,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<()> {
+        let voted_for = self.storage.state().voted_for;
+        let up_to_date = (last_log_term, last_log_index)
+            >= (self.storage.last_term(), self.storage.last_index());
+        let granted = (voted_for.is_none() || voted_for == Some(candidate)) && up_to_date;
+        if granted {
+            self.set_hard_state(self.term(), Some(candidate))?;
+            self.election_elapsed = 0;
+        }
+        self.send(candidate, Message::Vote { granted });
+        Ok(())
+    }
+
+    fn handle_vote(&mut self, from: NodeId, granted: bool) -> Result<()> {
+        if self.role != Role::Candidate || !granted {
+            return Ok(());
+        }
+        self.votes.insert(from);
+        if self.has_quorum(&self.votes) {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn handle_append_entries(
+        &mut self,
+        leader: NodeId,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+        read_ctx: u64,
+    ) -> Result<()> {
+        if self.role != Role::Follower || self.leader != Some(leader) {
+            self.become_follower(self.term(), Some(leader))?;
+        }
+        self.election_elapsed = 0;
+
+        if self.storage.term(prev_log_index) != Some(prev_log_term) {
+            // leaderはmatch_index + 1から再送する
+            let match_index = self
+                .storage
+                .last_index()
+                .min(prev_log_index.saturating_sub(1));
+            self.send(
+                leader,
+                Message::AppendEntriesResponse {
+                    success: false,
+                    match_index,
+                    read_ctx,
+                },
+            );
+            return Ok(());
+        }
+
+        // 既存のentryと矛盾する箇所以降を置き換える
+        let mut index = prev_log_index;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            index += 1;
+            if !new_entries.is_empty() {
+                new_entries.push(entry);
+                continue;
+            }
+            match self.storage.term(index) {
+                Some(term) if term == entry.term => (),
+                Some(_) => {
+                    self.storage.truncate(index)?;
+                    new_entries.push(entry);
+                }
+                None => new_entries.push(entry),
+            }
+        }
+        if !new_entries.is_empty() {
+            self.storage.append(&new_entries)?;
+        }
+
+        if commit > self.commit_index {
+            self.commit_index = commit.min(index);
+        }
+        self.send(
+            leader,
+            Message::AppendEntriesResponse {
+                success: true,
+                match_index: index,
+                read_ctx,
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_append_response(
+        &mut self,
+        from: NodeId,
+        success: bool,
+        match_index: u64,
+        read_ctx: u64,
+    ) {
+        if !self.is_leader() {
+            return;
+        }
+        // 同じtermのleaderとして応答されたのでleadershipの確認になる
+        for read in self.reads.iter_mut().filter(|read| read.ctx <= read_ctx) {
+            read.acks.insert(from);
+        }
+
+        let last_index = self.storage.last_index();
+        if let Some(progress) = self.progress.get_mut(&from) {
+            if success {
+                progress.matched = progress.matched.max(match_index);
+                progress.next = progress.matched + 1;
+            } else {
+                progress.next = (match_index + 1)
+                    .min(progress.next.saturating_sub(1))
+                    .max(1);
+            }
+            let retry = !success || progress.next <= last_index;
+            self.maybe_commit();
+            if retry {
+                self.send_append(from, 0);
+            }
+        }
+        self.maybe_ready_reads();
+    }
+
+    // 過半数に複製された自身のtermのentryまでcommitする
+    fn maybe_commit(&mut self) {
+        let mut matched = self
+            .progress
+            .values()
+            .map(|progress| progress.matched)
+            .collect::<Vec<_>>();
+        matched.push(self.storage.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit_index && self.storage.term(index) == Some(self.term()) {
+            self.commit_index = index;
+        }
+    }
+
+    fn maybe_ready_reads(&mut self) {
+        // 自身のtermのentryがcommitされるまでは、commit_indexが最新とは限らない
+        if self.storage.term(self.commit_index) != Some(self.term()) {
+            return;
+        }
+        let quorum = self.quorum();
+        let commit_index = self.commit_index;
+        let (ready, pending) = mem::take(&mut self.reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|read| read.acks.len() >= quorum);
+        self.reads = pending;
+        self.ready_reads.extend(
+            ready
+                .into_iter()
+                .map(|read| (read.ctx, read.index.max(commit_index))),
+        );
+    }
+
+    fn broadcast_append(&mut self) {
+        let read_ctx = self.read_ctx;
+        for peer in self.peers.clone() {
+            self.send_append(peer, read_ctx);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId, read_ctx: u64) {
+        let next = match self.progress.get(&peer) {
+            Some(progress) => progress.next,
+            None => return,
+        };
+        let prev_log_index = next - 1;
+        let message = Message::AppendEntries {
+            prev_log_index,
+            prev_log_term: self.storage.term(prev_log_index).unwrap_or(0),
+            entries: self.storage.entries(next, MAX_APPEND_ENTRIES),
+            commit: self.commit_index,
+            read_ctx,
+        };
+        self.send(peer, message);
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.outbox.push(Envelope {
+            from: self.id,
+            to,
+            term: self.term(),
+            message,
+        });
+    }
+
+    fn set_hard_state(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<()> {
+        self.storage.set_state(HardState {
+            term,
+            voted_for,
+            applied: self.storage.state().applied,
+        })
+    }
+
+    fn reset_election_timeout(&mut self) {
+        self.election_elapsed = 0;
+        self.election_timeout =
+            rand::thread_rng().gen_range(ELECTION_TIMEOUT_TICKS.0, ELECTION_TIMEOUT_TICKS.1);
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn has_quorum(&self, votes: &HashSet<NodeId>) -> bool {
+        votes.len() >= self.quorum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use std::{collections::BTreeMap, result::Result as StdResult};
+    use tempdir::TempDir;
+
+    // messageの配送を制御できるin-processのcluster
+    struct Cluster {
+        _dir: TempDir,
+        nodes: BTreeMap<NodeId, Node>,
+        // 分断されたnode. messageの送受信ができない
+        isolated: HashSet<NodeId>,
+    }
+
+    impl Cluster {
+        fn new(n: u64) -> StdResult<Self, Error> {
+            let dir = TempDir::new("")?;
+            let ids = (1..=n).collect::<Vec<_>>();
+            let mut nodes = BTreeMap::new();
+            for &id in &ids {
+                let peers = ids.iter().copied().filter(|&peer| peer != id).collect();
+                let storage = Storage::open(dir.path().join(id.to_string()))?;
+                nodes.insert(id, Node::new(id, peers, storage));
+            }
+            Ok(Self {
+                _dir: dir,
+                nodes,
+                isolated: HashSet::new(),
+            })
+        }
+
+        fn node(&mut self, id: NodeId) -> &mut Node {
+            self.nodes.get_mut(&id).unwrap()
+        }
+
+        // 送信待ちのmessageがなくなるまで配送する
+        fn deliver(&mut self) -> Result<()> {
+            loop {
+                let mut messages = Vec::new();
+                for node in self.nodes.values_mut() {
+                    messages.extend(node.take_messages());
+                }
+                if messages.is_empty() {
+                    return Ok(());
+                }
+                for envelope in messages {
+                    if self.isolated.contains(&envelope.from)
+                        || self.isolated.contains(&envelope.to)
+                    {
+                        continue;
+                    }
+                    self.node(envelope.to).step(envelope)?;
+                }
+            }
+        }
+
+        // leaderが選出されるまでtickを進める
+        fn elect(&mut self) -> Result<NodeId> {
+            for _ in 0..1000 {
+                for node in self.nodes.values_mut() {
+                    node.tick()?;
+                }
+                self.deliver()?;
+                let leaders = self
+                    .nodes
+                    .values()
+                    .filter(|node| node.is_leader() && !self.isolated.contains(&node.id))
+                    .map(|node| node.id)
+                    .collect::<Vec<_>>();
+                if leaders.len() == 1 {
+                    return Ok(leaders[0]);
+                }
+            }
+            panic!("leader was not elected");
+        }
+
+        fn committed(&mut self, id: NodeId) -> Vec<Command> {
+            let node = self.node(id);
+            let committed = node.committed();
+            if let Some((index, _)) = committed.last() {
+                node.set_applied(*index);
+            }
+            committed
+                .into_iter()
+                .map(|(_, entry)| entry.command)
+                .filter(|command| *command != Command::Noop)
+                .collect()
+        }
+    }
+
+    fn kvs(n: u8) -> Command {
+        Command::Kvs(vec![n])
+    }
+
+    #[test]
+    fn single_node() -> StdResult<(), Error> {
+        let mut cluster = Cluster::new(1)?;
+        let leader = cluster.elect()?;
+        cluster.node(leader).propose(kvs(1))?;
+        assert_eq!(cluster.committed(leader), vec![kvs(1)]);
+
+        let ctx = cluster.node(leader).read_index()?;
+        assert_eq!(cluster.node(leader).take_ready_reads(), vec![(ctx, 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn replicate_and_commit() -> StdResult<(), Error> {
+        let mut cluster = Cluster::new(3)?;
+        let leader = cluster.elect()?;
+        let follower = (1..=3).find(|&id| id != leader).unwrap();
+
+        assert!(cluster
+            .node(follower)
+            .propose(kvs(1))
+            .unwrap_err()
+            .is_not_leader());
+        cluster.node(leader).propose(kvs(1))?;
+        cluster.node(leader).propose(kvs(2))?;
+        cluster.deliver()?;
+        assert_eq!(cluster.committed(leader), vec![kvs(1), kvs(2)]);
+
+        // followerにはcommit_indexが次のAppendEntriesで伝わる
+        for _ in 0..HEARTBEAT_TICKS {
+            cluster.node(leader).tick()?;
+        }
+        cluster.deliver()?;
+        for id in 1..=3 {
+            if id != leader {
+                assert_eq!(cluster.committed(id), vec![kvs(1), kvs(2)]);
+            }
+        }
+
+        let ctx = cluster.node(leader).read_index()?;
+        assert!(cluster.node(leader).take_ready_reads().is_empty());
+        cluster.deliver()?;
+        assert_eq!(cluster.node(leader).take_ready_reads(), vec![(ctx, 3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn leader_failure() -> StdResult<(), Error> {
+        let mut cluster = Cluster::new(3)?;
+        let old_leader = cluster.elect()?;
+        cluster.node(old_leader).propose(kvs(1))?;
+        cluster.deliver()?;
+
+        // 分断されたleaderへの書き込みはcommitされない
+        cluster.isolated.insert(old_leader);
+        cluster.node(old_leader).propose(kvs(2))?;
+        let ctx = cluster.node(old_leader).read_index()?;
+        cluster.deliver()?;
+        assert_eq!(cluster.committed(old_leader), vec![kvs(1)]);
+        assert!(cluster.node(old_leader).take_ready_reads().is_empty());
+        assert_ne!(ctx, 0);
+
+        let new_leader = cluster.elect()?;
+        assert_ne!(new_leader, old_leader);
+        cluster.node(new_leader).propose(kvs(3))?;
+        cluster.deliver()?;
+
+        // 復帰した旧leaderはfollowerになり、commitされていないentryは置き換えられる
+        cluster.isolated.clear();
+        for _ in 0..HEARTBEAT_TICKS {
+            cluster.node(new_leader).tick()?;
+        }
+        cluster.deliver()?;
+        for _ in 0..HEARTBEAT_TICKS {
+            cluster.node(new_leader).tick()?;
+        }
+        cluster.deliver()?;
+        assert_eq!(cluster.node(old_leader).role(), Role::Follower);
+        assert_eq!(cluster.node(old_leader).leader(), Some(new_leader));
+        assert_eq!(cluster.committed(old_leader), vec![kvs(3)]);
+        assert_eq!(
+            cluster.node(old_leader).storage.last_index(),
+            cluster.node(new_leader).storage.last_index()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn restart() -> StdResult<(), Error> {
+        let dir = TempDir::new("")?;
+        let mut node = Node::new(1, vec![], Storage::open(dir.path())?);
+        while !node.is_leader() {
+            node.tick()?;
+        }
+        node.propose(kvs(1))?;
+        let committed = node.committed();
+        assert_eq!(committed.len(), 2);
+        node.set_applied(committed[1].0);
+        node.persist_applied()?;
+        let term = node.term();
+        drop(node);
+
+        // 適用済みのentryは再度適用しない
+        let mut node = Node::new(1, vec![], Storage::open(dir.path())?);
+        assert_eq!(node.term(), term);
+        while !node.is_leader() {
+            node.tick()?;
+        }
+        assert!(node.term() > term);
+        node.propose(kvs(2))?;
+        let committed = node.committed();
+        assert_eq!(
+            committed
+                .into_iter()
+                .map(|(_, entry)| entry.command)
+                .collect::<Vec<_>>(),
+            vec![Command::Noop, kvs(2)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+// Raftのlogと永続化が必要な状態(term, voted_for)をfileに保存する
+// kvsのlogのentryはterm/indexを持たず、追記のみでreplicationのoffsetにも使われる.
+// Raftは未commitのentryを切り詰める必要があるため、kvsのlogとは別のfileに保存し、
+// commit済みのentryだけをkvsのlogに適用する
+use super::{LogEntry, NodeId};
+use crate::{KvsError, Result};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BE};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const LOG_FILE: &str = "raft.log";
+const STATE_FILE: &str = "raft.state";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HardState {
+    pub(crate) term: u64,
+    pub(crate) voted_for: Option<NodeId>,
+    // kvsに適用済みのlogのindex
+    pub(crate) applied: u64,
+}
+
+pub(crate) struct Storage {
+    dir: PathBuf,
+    file: File,
+    // indexは1から始まるので、entries[index - 1]がindexのentryになる
+    entries: Vec<LogEntry>,
+    // entryごとのfile上の開始位置. logを切り詰める際に利用する
+    offsets: Vec<u64>,
+    state: HardState,
+}
+
+impl Storage {
+    pub(crate) fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(bytes) => bincode::deserialize(&bytes)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOG_FILE))?;
+        let (entries, offsets, end) = read_records(&mut file)?;
+        // 適用済みのentryがlogに残っていない場合は、commitされたentryを失っている
+        if state.applied > entries.len() as u64 {
+            return Err(KvsError::CorruptData);
+        }
+        // 書き込み途中でcrashした場合の末尾の不完全なrecordは捨てる
+        file.set_len(end)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            dir,
+            file,
+            entries,
+            offsets,
+            state,
+        })
+    }
+
+    pub(crate) fn state(&self) -> &HardState {
+        &self.state
+    }
+
+    // 一時fileに書き込んでからrenameして置き換える
+    pub(crate) fn set_state(&mut self, state: HardState) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bincode::serialize(&state)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(STATE_FILE))?;
+        self.state = state;
+        Ok(())
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    // index 0はentryが存在しない位置としてterm 0を返す
+    pub(crate) fn term(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            _ => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.entries.last().map(|entry| entry.term).unwrap_or(0)
+    }
+
+    pub(crate) fn entry(&self, index: u64) -> Option<&LogEntry> {
+        index
+            .checked_sub(1)
+            .and_then(|i| self.entries.get(i as usize))
+    }
+
+    // from以降(from含む)のentryを最大max件返す
+    pub(crate) fn entries(&self, from: u64, max: usize) -> Vec<LogEntry> {
+        self.entries
+            .iter()
+            .skip(from.saturating_sub(1) as usize)
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn append(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let start = self.file.seek(SeekFrom::End(0))?;
+        let mut position = start;
+        let mut buff = Vec::new();
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let record = bincode::serialize(entry)?;
+            buff.write_u32::<BE>(record.len() as u32)?;
+            buff.write_u32::<BE>(checksum(&record))?;
+            buff.extend_from_slice(&record);
+            offsets.push(position);
+            position += 8 + record.len() as u64;
+        }
+        // 途中まで書いたrecordを残すと、以降に追記したrecordが途中の壊れたrecordの後ろになるので取り除く
+        if let Err(err) = self
+            .file
+            .write_all(&buff)
+            .and_then(|_| self.file.sync_data())
+        {
+            let _ = self.file.set_len(start);
+            return Err(err.into());
+        }
+        self.offsets.extend(offsets);
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    // from以降(from含む)のentryを削除する
+    pub(crate) fn truncate(&mut self, from: u64) -> Result<()> {
+        let i = from.saturating_sub(1) as usize;
+        if let Some(&offset) = self.offsets.get(i) {
+            self.file.set_len(offset)?;
+            self.file.sync_data()?;
+            self.offsets.truncate(i);
+            self.entries.truncate(i);
+        }
+        Ok(())
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(bytes);
+    h.finalize()
+}
+
+// 正常に読み込めたentryと、その終端の位置を返す
+// 途中のrecordが壊れている場合は、後ろのcommit済みのentryを失わないようCorruptDataを返す
+fn read_records(file: &mut File) -> Result<(Vec<LogEntry>, Vec<u64>, u64)> {
+    let mut r = BufReader::new(file);
+    let (mut entries, mut offsets, mut position) = (Vec::new(), Vec::new(), 0_u64);
+    loop {
+        match read_record(&mut r) {
+            Ok(Some((entry, len))) => {
+                entries.push(entry);
+                offsets.push(position);
+                position += len;
+            }
+            Ok(None) => break,
+            Err(err) if err.is_eof() => {
+                let mut tail = Vec::new();
+                r.seek(SeekFrom::Start(position))?;
+                r.read_to_end(&mut tail)?;
+                if !is_torn_tail(&tail) {
+                    return Err(KvsError::CorruptData);
+                }
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((entries, offsets, position))
+}
+
+// 読み込めなかったrecordから末尾までが、書き込み途中の1つのrecordか
+// 後ろに完全なrecordが見つかる場合は、途中のrecordの長さが壊れている
+fn is_torn_tail(tail: &[u8]) -> bool {
+    (1..tail.len()).all(|i| !is_complete_record(&tail[i..]))
+}
+
+fn is_complete_record(buf: &[u8]) -> bool {
+    if buf.len() < 8 {
+        return false;
+    }
+    let len = BE::read_u32(&buf[..4]) as usize;
+    let record = match buf[8..].get(..len) {
+        Some(record) => record,
+        None => return false,
+    };
+    checksum(record) == BE::read_u32(&buf[4..8]) && bincode::deserialize::<LogEntry>(record).is_ok()
+}
+
+fn read_record<R: Read>(r: &mut R) -> Result<Option<(LogEntry, u64)>> {
+    let len = match r.read_u32::<BE>() {
+        Ok(len) => len,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let expected = r.read_u32::<BE>()?;
+    // 壊れたheaderの長さでbufferを確保しないよう、読み込めた分だけ伸ばす
+    let mut record = Vec::new();
+    r.take(len as u64).read_to_end(&mut record)?;
+    // 書き込み途中で途切れたrecordはUnexpectedEofとする
+    if record.len() < len as usize {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    if checksum(&record) != expected {
+        return Err(KvsError::CorruptData);
+    }
+    Ok(Some((bincode::deserialize(&record)?, 8 + len as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::Command;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+
+    fn entry(term: u64) -> LogEntry {
+        LogEntry {
+            term,
+            command: Command::Noop,
+        }
+    }
+
+    #[test]
+    fn append_truncate_reopen() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+
+        let mut storage = Storage::open(tmp_dir.path())?;
+        storage.append(&[entry(1), entry(1)])?;
+        storage.append(&[entry(2)])?;
+        storage.truncate(3)?;
+        storage.append(&[entry(3)])?;
+        storage.set_state(HardState {
+            term: 3,
+            voted_for: Some(2),
+            applied: 1,
+        })?;
+        assert_eq!(storage.last_index(), 3);
+        assert_eq!(storage.term(3), Some(3));
+        assert_eq!(storage.entries(2, 10).len(), 2);
+
+        // 書き込み途中のrecordは無視される
+        OpenOptions::new()
+            .append(true)
+            .open(tmp_dir.path().join(LOG_FILE))?
+            .write_all(&[0, 0, 0, 10, 1])?;
+
+        let mut storage = Storage::open(tmp_dir.path())?;
+        assert_eq!(storage.last_index(), 3);
+        assert_eq!(storage.term(0), Some(0));
+        assert_eq!(storage.term(2), Some(1));
+        assert_eq!(storage.term(3), Some(3));
+        assert_eq!(storage.state().voted_for, Some(2));
+
+        storage.append(&[entry(4)])?;
+        let storage = Storage::open(tmp_dir.path())?;
+        assert_eq!(storage.last_term(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_record() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut storage = Storage::open(tmp_dir.path())?;
+        storage.append(&[entry(1), entry(1), entry(2)])?;
+        drop(storage);
+
+        // 途中のrecordが壊れている場合は、後ろのrecordを切り詰めずに失敗する
+        let path = tmp_dir.path().join(LOG_FILE);
+        let bytes = fs::read(&path)?;
+        for i in [0, 4, 8] {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0xFF;
+            fs::write(&path, &corrupted)?;
+            assert!(Storage::open(tmp_dir.path())
+                .err()
+                .unwrap()
+                .is_data_corrupt());
+            assert_eq!(fs::read(&path)?, corrupted);
+        }
+
+        // 適用済みのentryがlogに残っていない
+        fs::write(&path, &bytes)?;
+        let mut storage = Storage::open(tmp_dir.path())?;
+        storage.set_state(HardState {
+            applied: 4,
+            ..HardState::default()
+        })?;
+        drop(storage);
+        assert!(Storage::open(tmp_dir.path())
+            .err()
+            .unwrap()
+            .is_data_corrupt());
+        Ok(())
+    }
+
+    #[test]
+    fn read_record_huge_len() {
+        // 長さが壊れていても、その長さのbufferは確保せずに途切れたrecordとして扱う
+        let mut r: &[u8] = &[0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 1, 2, 3];
+        assert!(read_record(&mut r).unwrap_err().is_eof());
+    }
+}
@@ -1,72 +1,753 @@
+mod http;
+mod metrics;
+mod raft;
+mod replication;
+mod resp;
+
 use crate::{
-    protocol::message::{self, Operator},
-    Result,
+    acl::{Acl, Grant, Operation},
+    metrics::ServerMetrics,
+    protocol::{
+        message::{Message, Operator, Payload},
+        resp::Connection,
+    },
+    tls::{Acceptor, TlsConfig},
+    EngineKind, Kvs, KvsError, RaftConfig, Result,
+};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{broadcast, mpsc, Mutex},
 };
-use std::{fmt, net::SocketAddr};
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tracing::{error, info};
+use tracing::{error, field, info, info_span, warn, Span};
+use tracing_futures::Instrument;
+
+// shutdown時にworkerの終了を待つ時間のdefault
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// serverがclientとやりとりするprotocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    // kvs独自のprotocol
+    Kvs,
+    // Redis(RESP2)互換. redis-cliやRedisのclient libraryから利用できる
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(KvsError::InvalidProtocol(s.to_owned())),
+        }
+    }
+}
 
-pub struct Server {}
+pub struct Server {
+    kvs: Arc<Mutex<Kvs>>,
+    shutdown_timeout: Duration,
+    tls: Option<TlsConfig>,
+    acl: Option<Arc<Acl>>,
+    protocol: Protocol,
+    expirations: Arc<Mutex<resp::Expirations>>,
+    http_addr: Option<SocketAddr>,
+    http_max_body_bytes: u64,
+    // followerとして起動する場合のleaderのaddress
+    replica_of: Option<String>,
+    replica_token: Option<String>,
+    raft: Option<RaftConfig>,
+    metrics_addr: Option<SocketAddr>,
+    metrics: Arc<ServerMetrics>,
+}
 
 impl Server {
-    pub fn new() -> Self {
-        Self {} // clippyにdefaultいわれるかも
+    pub fn new(kvs: Kvs) -> Self {
+        Self {
+            kvs: Arc::new(Mutex::new(kvs)),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            tls: None,
+            acl: None,
+            protocol: Protocol::Kvs,
+            expirations: Arc::new(Mutex::new(resp::Expirations::default())),
+            http_addr: None,
+            http_max_body_bytes: http::DEFAULT_MAX_BODY_BYTES,
+            replica_of: None,
+            replica_token: None,
+            raft: None,
+            metrics_addr: None,
+            metrics: Arc::new(ServerMetrics::default()),
+        }
+    }
+
+    // 指定するとGET /metricsでPrometheusのtext formatのmetricsを返す
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    // 指定するとleaderのfollowerとして起動し、leaderのlogを複製する
+    // followerはread-onlyとなり、put/deleteはエラーを返す
+    pub fn replica_of<S: Into<String>>(mut self, leader: S) -> Self {
+        self.replica_of = Some(leader.into());
+        self
+    }
+
+    // 指定するとRaft clusterのnodeとして起動し、書き込みはclusterで合意してから適用する
+    // leader以外のnodeへの要求はleaderのaddressとともにNotLeaderを返す
+    pub fn raft(mut self, config: RaftConfig) -> Self {
+        self.raft = Some(config);
+        self
+    }
+
+    // 他のnode(replicationのleaderやRaftのpeer)にACLが設定されている場合に認証に利用するtoken
+    // replicationでは全てのkeyのget, Raftでは全てのkeyのputが許可されている必要がある
+    pub fn replica_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.replica_token = Some(token.into());
+        self
+    }
+
+    // 指定するとHTTP/JSON gatewayも起動する. gatewayは同じKvsとACLを共有する
+    pub fn http(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    // gatewayがPUTで受け付けるbodyの上限. 超える場合は413を返す
+    pub fn http_max_body_bytes(mut self, bytes: u64) -> Self {
+        self.http_max_body_bytes = bytes;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    // 指定するとclientにAuthによる認証を要求し、tokenごとに操作を制限する
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    // 指定するとconnectionをTLSで受け付ける
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    // shutdown開始後、処理中のworkerの完了を待つ時間を指定する
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
     }
 
     pub async fn run<A: ToSocketAddrs + fmt::Debug>(self, addr: A) -> Result<()> {
+        self.run_until(addr, std::future::pending()).await
+    }
+
+    // shutdownがcompleteするまでconnectionを処理する
+    // shutdown後は新規のacceptを止め、処理中のworkerの完了を待ってからstoreをfsyncして返る
+    pub async fn run_until<A, F>(self, addr: A, shutdown: F) -> Result<()>
+    where
+        A: ToSocketAddrs + fmt::Debug,
+        F: Future<Output = ()>,
+    {
+        // 証明書の誤りはbind前に検知する
+        Acceptor::new(self.tls.as_ref())?;
+
         info!(?addr, "Binding...",);
-        let mut listener = TcpListener::bind(addr).await?;
+        let listener = TcpListener::bind(addr).await?;
+        self.serve(listener, shutdown).await
+    }
+
+    // bind済みのlistenerでrun_untilと同様にconnectionを処理する
+    // port 0でbindしたlistenerのaddressを取得してから起動する場合に利用する
+    pub async fn serve<F>(self, listener: TcpListener, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let http_listener = self
+            .http_addr
+            .map(std::net::TcpListener::bind)
+            .transpose()?;
+        let metrics_listener = self.bind_metrics()?;
+
+        self.serve_with(Some(listener), http_listener, metrics_listener, shutdown)
+            .await
+    }
+
+    // kvs protocolのlistenerは起動せず、HTTP/JSON gatewayのみ起動する
+    pub async fn run_http_until<F>(self, addr: SocketAddr, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        Acceptor::new(self.tls.as_ref())?;
+
+        let http_listener = std::net::TcpListener::bind(addr)?;
+        let metrics_listener = self.bind_metrics()?;
+        self.serve_with(None, Some(http_listener), metrics_listener, shutdown)
+            .await
+    }
+
+    fn bind_metrics(&self) -> Result<Option<std::net::TcpListener>> {
+        Ok(self
+            .metrics_addr
+            .map(std::net::TcpListener::bind)
+            .transpose()?)
+    }
+
+    pub(crate) async fn serve_with<F>(
+        self,
+        mut listener: Option<TcpListener>,
+        http_listener: Option<std::net::TcpListener>,
+        metrics_listener: Option<std::net::TcpListener>,
+        shutdown: F,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let acceptor = Acceptor::new(self.tls.as_ref())?;
+        if self.raft.is_some()
+            && (self.protocol != Protocol::Kvs || http_listener.is_some() || self.is_read_only())
+        {
+            return Err(KvsError::InvalidCluster(
+                "raft supports only kvs protocol without http gateway and replica".to_owned(),
+            ));
+        }
+        // replicationとRaftはlogのoffsetを前提とする
+        if self.kvs.lock().await.engine_kind() == EngineKind::Lsm {
+            if self.raft.is_some() {
+                return Err(KvsError::Unsupported("raft"));
+            }
+            if self.is_read_only() {
+                return Err(KvsError::Unsupported("replication"));
+            }
+        }
+        // workerにshutdownを通知する
+        let (notify_shutdown, _) = broadcast::channel::<()>(1);
+        // 各workerがSenderを保持し、全workerがdropするとrecv()がNoneを返す
+        let (worker_done_tx, mut worker_done_rx) = mpsc::channel::<()>(1);
+
+        let raft = match &self.raft {
+            Some(config) => Some(raft::start(
+                config.clone(),
+                Arc::clone(&self.kvs),
+                raft::Transport {
+                    tls: self.tls.clone(),
+                    token: self.replica_token.clone(),
+                },
+                notify_shutdown.clone(),
+                worker_done_tx.clone(),
+            )?),
+            None => None,
+        };
+
+        if let Some(http_listener) = http_listener {
+            let gateway = http::Gateway::new(
+                Arc::clone(&self.kvs),
+                Arc::clone(&self.expirations),
+                self.acl.clone(),
+                self.is_read_only(),
+                self.http_max_body_bytes,
+                Arc::clone(&self.metrics),
+            );
+            let acceptor = acceptor.clone();
+            let mut shutdown = notify_shutdown.subscribe();
+            let done = worker_done_tx.clone();
+            tokio::task::spawn(async move {
+                let _done = done;
+                let shutdown = async move {
+                    let _ = shutdown.recv().await;
+                };
+                if let Err(err) = gateway.serve(http_listener, acceptor, shutdown).await {
+                    error!("{}", err);
+                }
+            });
+        }
+
+        if let Some(metrics_listener) = metrics_listener {
+            let exporter = metrics::Exporter::new(Arc::clone(&self.kvs), Arc::clone(&self.metrics));
+            let mut shutdown = notify_shutdown.subscribe();
+            let done = worker_done_tx.clone();
+            tokio::task::spawn(async move {
+                let _done = done;
+                let shutdown = async move {
+                    let _ = shutdown.recv().await;
+                };
+                if let Err(err) = exporter.serve(metrics_listener, shutdown).await {
+                    error!("{}", err);
+                }
+            });
+        }
+
+        if let Some(leader) = &self.replica_of {
+            let follower = replication::Follower {
+                leader: leader.clone(),
+                tls: self.tls.clone(),
+                token: self.replica_token.clone(),
+                kvs: Arc::clone(&self.kvs),
+            };
+            let shutdown = notify_shutdown.subscribe();
+            let done = worker_done_tx.clone();
+            tokio::task::spawn(async move {
+                let _done = done;
+                follower.run(shutdown).await;
+            });
+        }
+
+        tokio::pin!(shutdown);
 
         loop {
-            match listener.accept().await {
-                Ok((conn, remote)) => {
-                    info!(?remote, "Accept new connection");
-                    let worker = Worker::new(conn, remote)?;
-                    tokio::task::spawn(async move {
-                        if let Err(err) = worker.dispatch().await {
-                            error!("{}", err);
+            tokio::select! {
+                accepted = accept(&mut listener) => match accepted {
+                    Ok((conn, remote)) => {
+                        info!(?remote, "Accept new connection");
+                        self.metrics.connections.inc();
+                        self.metrics.active_connections.inc();
+                        let metrics = Arc::clone(&self.metrics);
+                        let acceptor = acceptor.clone();
+                        let protocol = self.protocol;
+                        let span = info_span!("connection", %remote, ?protocol);
+                        let context = Context {
+                            kvs: Arc::clone(&self.kvs),
+                            session: Session::new(self.acl.clone(), self.is_read_only()),
+                            expirations: Arc::clone(&self.expirations),
+                            raft: raft.clone(),
+                            metrics: Arc::clone(&self.metrics),
+                            shutdown: notify_shutdown.subscribe(),
+                            _done: worker_done_tx.clone(),
                         };
-                    });
-                }
-                Err(err) => {
-                    error!("{:?}", err);
+                        tokio::task::spawn(async move {
+                            // TLS handshakeでacceptをblockしないようにtask内で行う
+                            let result = match (acceptor.accept(conn).await, protocol) {
+                                (Ok(stream), Protocol::Kvs) => {
+                                    Worker::new(Operator::new(stream), remote, context)
+                                        .dispatch()
+                                        .await
+                                }
+                                (Ok(stream), Protocol::Resp) => {
+                                    resp::Worker::new(Connection::new(stream), remote, context)
+                                        .dispatch()
+                                        .await
+                                }
+                                (Err(err), _) => Err(err),
+                            };
+                            if let Err(err) = result {
+                                error!(?remote, "{}", err);
+                            };
+                            metrics.active_connections.dec();
+                        }.instrument(span));
+                    }
+                    Err(err) => {
+                        error!("{:?}", err);
+                    }
+                },
+                _ = &mut shutdown => {
+                    info!("Shutdown signal received");
+                    break;
                 }
             }
         }
+
+        // 新規connectionの受付を止める
+        drop(listener);
+
+        let _ = notify_shutdown.send(());
+        drop(worker_done_tx);
+
+        match tokio::time::timeout(self.shutdown_timeout, worker_done_rx.recv()).await {
+            Ok(_) => info!("All workers finished"),
+            Err(_) => warn!(
+                timeout=?self.shutdown_timeout,
+                "Workers did not finish within the shutdown timeout"
+            ),
+        }
+
+        self.kvs.lock().await.sync()?;
+        info!("Shutdown completed");
+
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.replica_of.is_some()
     }
 }
 
 impl Default for Server {
+    // 永続化しないin-memoryのKvsで起動する
     fn default() -> Self {
-        Self::new()
+        Self::new(Kvs::in_memory().expect("Failed to open in-memory kvs"))
+    }
+}
+
+// listenerがない場合はacceptしない
+async fn accept(listener: &mut Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// requestごとのspan. responseのvalueのbytesと処理時間は処理後に記録する
+fn request_span(payload: &Payload) -> Span {
+    let span = info_span!(
+        "request",
+        op = payload.op(),
+        key = field::Empty,
+        value_bytes = field::Empty,
+        duration_us = field::Empty,
+    );
+    if let Some(key) = payload.key() {
+        span.record("key", key);
+    }
+    if let Some(n) = payload.value_bytes() {
+        span.record("value_bytes", n as u64);
+    }
+    span
+}
+
+// workerがserverから引き継ぐstate
+struct Context {
+    kvs: Arc<Mutex<Kvs>>,
+    session: Session,
+    // RESPのEXPIREで設定された有効期限. kvs protocolでの読み書きにも反映する
+    expirations: Arc<Mutex<resp::Expirations>>,
+    // Raft clusterのnodeとして起動している場合、書き込みとreadはRaftを経由する
+    raft: Option<raft::Raft>,
+    metrics: Arc<ServerMetrics>,
+    shutdown: broadcast::Receiver<()>,
+    // dropされることでserverにworkerの終了を通知する
+    _done: mpsc::Sender<()>,
+}
+
+// connection(HTTPの場合はrequest)ごとの認証状態
+struct Session {
+    acl: Option<Arc<Acl>>,
+    // Authで認証されたtokenの権限
+    grant: Option<Grant>,
+    // followerの場合はput/deleteを受け付けない
+    read_only: bool,
+}
+
+impl Session {
+    fn new(acl: Option<Arc<Acl>>, read_only: bool) -> Self {
+        Self {
+            acl,
+            grant: None,
+            read_only,
+        }
+    }
+
+    fn authenticate(&mut self, token: &str) -> Result<()> {
+        match &self.acl {
+            Some(acl) => {
+                self.grant = Some(acl.authenticate(token)?);
+                Ok(())
+            }
+            // ACLが設定されていなければ認証は不要
+            None => Ok(()),
+        }
+    }
+
+    fn authorize(&self, operation: Operation, key: &str) -> Result<()> {
+        if self.read_only && operation != Operation::Get {
+            return Err(KvsError::ReadOnly);
+        }
+        match (&self.acl, &self.grant) {
+            (None, _) => Ok(()),
+            (Some(_), Some(grant)) => grant.authorize(operation, key),
+            (Some(_), None) => Err(KvsError::PermissionDenied),
+        }
     }
 }
 
 struct Worker {
     remote: SocketAddr,
     operator: Operator,
+    context: Context,
 }
 
 impl Worker {
-    fn new(stream: TcpStream, remote: SocketAddr) -> Result<Self> {
-        Ok(Self {
+    fn new(operator: Operator, remote: SocketAddr, context: Context) -> Self {
+        Self {
             remote,
-            operator: Operator::with_stream(stream)?,
-        })
+            operator,
+            context,
+        }
     }
+
     async fn dispatch(mut self) -> Result<()> {
         info!(remote=?self.remote, "Worker dispatched");
 
-        let payload = self.operator.receive().await?;
-        info!("Receive! {:?}", payload);
-        if let message::Payload::EchoRequest { message, .. } = payload {
-            self.operator
-                .send(message::Message::from_payload(
-                    message::Payload::EchoResponse { message },
-                )?)
-                .await?;
+        loop {
+            // request待ちの間にshutdownされた場合はそのまま終了する
+            let payload = tokio::select! {
+                payload = self.operator.receive() => match payload {
+                    Ok(payload) => payload,
+                    Err(err) if err.is_eof() => {
+                        info!(remote=?self.remote, "Connection closed");
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                },
+                _ = self.context.shutdown.recv() => {
+                    info!(remote=?self.remote, "Worker shutdown before receiving request");
+                    return Ok(());
+                }
+            };
+            // 以降はconnectionをreplicationに利用する
+            if let Payload::ReplicateRequest { offset } = payload {
+                return replication::ship(&mut self.operator, &mut self.context, offset).await;
+            }
+            // Raftのmessageには応答しない. エラーの場合はconnectionを閉じる
+            if let Payload::RaftMessage { message } = &payload {
+                if let Err(err) = self.receive_raft(message).await {
+                    let _ = self
+                        .operator
+                        .send(Message::from_payload(Payload::error(&err))?)
+                        .await;
+                    return Err(err);
+                }
+                continue;
+            }
+
+            self.context.metrics.requests.inc();
+            let span = request_span(&payload);
+            let start = Instant::now();
+            let response = match self.handle(payload).instrument(span.clone()).await {
+                Ok(response) => response,
+                Err(err) => {
+                    span.in_scope(|| warn!("{}", err));
+                    self.context.metrics.request_errors.inc();
+                    Payload::error(&err)
+                }
+            };
+            if let Some(n) = response.value_bytes() {
+                span.record("value_bytes", n as u64);
+            }
+            span.record("duration_us", start.elapsed().as_micros() as u64);
+            span.in_scope(|| info!("Request handled"));
+            self.operator.send(Message::from_payload(response)?).await?;
+        }
+    }
+
+    async fn handle(&mut self, payload: Payload) -> Result<Payload> {
+        match payload {
+            Payload::EchoRequest { message } => Ok(Payload::EchoResponse { message }),
+            Payload::AuthRequest { token } => {
+                self.context.session.authenticate(&token)?;
+                Ok(Payload::AuthResponse)
+            }
+            Payload::GetRequest { key } => {
+                self.context.session.authorize(Operation::Get, &key)?;
+                if let Some(raft) = &self.context.raft {
+                    raft.read_barrier().await?;
+                }
+                let mut kvs = self.context.kvs.lock().await;
+                self.context
+                    .expirations
+                    .lock()
+                    .await
+                    .purge(&mut kvs, &key)?;
+                let value = kvs.get_raw(&key)?;
+                Ok(Payload::GetResponse { value })
+            }
+            Payload::PutRequest { key, value } => {
+                self.context.session.authorize(Operation::Put, &key)?;
+                match &self.context.raft {
+                    Some(raft) => raft.put(key, value).await?,
+                    None => {
+                        let mut kvs = self.context.kvs.lock().await;
+                        kvs.put_raw(key.as_str(), value)?;
+                        self.context.expirations.lock().await.clear(&key);
+                    }
+                }
+                Ok(Payload::PutResponse)
+            }
+            Payload::DeleteRequest { key } => {
+                self.context.session.authorize(Operation::Delete, &key)?;
+                let value = match &self.context.raft {
+                    Some(raft) => raft.delete(key).await?,
+                    None => {
+                        let mut kvs = self.context.kvs.lock().await;
+                        let mut expirations = self.context.expirations.lock().await;
+                        expirations.purge(&mut kvs, &key)?;
+                        expirations.clear(&key);
+                        kvs.delete_raw(&key)?
+                    }
+                };
+                Ok(Payload::DeleteResponse { value })
+            }
+            Payload::KeysRequest { prefix } => {
+                if let Some(raft) = &self.context.raft {
+                    raft.read_barrier().await?;
+                }
+                let session = &self.context.session;
+                let mut kvs = self.context.kvs.lock().await;
+                self.context.expirations.lock().await.purge_all(&mut kvs)?;
+                let mut keys = kvs
+                    .owned_keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .filter(|key| session.authorize(Operation::Get, key).is_ok())
+                    .collect::<Vec<_>>();
+                keys.sort();
+                Ok(Payload::KeysResponse { keys })
+            }
+            // file全体の状態を返すので、全てのkeyのgetを要求する
+            Payload::StatsRequest => {
+                self.context.session.authorize(Operation::Get, "")?;
+                let stats = self.context.kvs.lock().await.stats();
+                Ok(Payload::StatsResponse { stats })
+            }
+            payload => Err(KvsError::InvalidPayloadKind(payload.kind() as u8)),
+        }
+    }
+
+    // 他のnodeからのmessageはclusterの状態を変更できるので、全てのkeyのputを要求する
+    async fn receive_raft(&mut self, message: &[u8]) -> Result<()> {
+        self.context.session.authorize(Operation::Put, "")?;
+        match &self.context.raft {
+            Some(raft) => raft.receive(message).await,
+            None => Err(KvsError::Raft("server is not a raft node".to_owned())),
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Client;
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn run_until_shutdown() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            Server::new(kvs)
+                .shutdown_timeout(Duration::from_secs(5))
+                .serve(listener, async {
+                    shutdown_rx.await.ok();
+                }),
+        );
+
+        // requestを送ってこないconnectionがshutdownを妨げないこと
+        let _idle = TcpStream::connect(addr).await?;
+
+        // 処理済みのconnectionが開いたままでもshutdownできること
+        let mut client = Client::connect(&addr.to_string(), None).await?;
+        assert_eq!(client.echo("hello").await?, "hello");
+
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+
+        // shutdown後は新規connectionを受け付けない
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn acl() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+        let acl = Acl::from_toml(
+            r#"
+[[grants]]
+token = "todo"
+operations = ["get", "put", "delete"]
+prefixes = ["todo/"]
+"#,
+        )?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(Server::new(kvs).acl(acl).serve(listener, async {
+            shutdown_rx.await.ok();
+        }));
+
+        // 認証前はechoのみ許可される
+        let mut client = Client::connect(&addr, None).await?;
+        assert_eq!(client.echo("hello").await?, "hello");
+        assert!(client
+            .put::<_, String>("todo/1", &"task".to_owned())
+            .await
+            .unwrap_err()
+            .is_permission_denied());
+        assert!(client
+            .auth("invalid")
+            .await
+            .unwrap_err()
+            .is_permission_denied());
+
+        client.auth("todo").await?;
+        client
+            .put::<_, String>("todo/1", &"task".to_owned())
+            .await?;
+        assert_eq!(client.get::<String>("todo/1").await?, "task");
+        assert!(client
+            .get::<String>("todo/2")
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert!(client
+            .get::<String>("other/1")
+            .await
+            .unwrap_err()
+            .is_permission_denied());
+        assert_eq!(
+            client.delete::<String>("todo/1").await?,
+            Some("task".to_owned())
+        );
+        assert_eq!(client.delete::<String>("todo/1").await?, None);
+
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lsm_rejects_replication() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let lsm = || Kvs::with_engine(tmp_dir.path().join("test.lsm"), EngineKind::Lsm);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let result = Server::new(lsm()?)
+            .replica_of("127.0.0.1:1")
+            .serve(listener, std::future::pending())
+            .await;
+        assert!(matches!(result, Err(KvsError::Unsupported("replication"))));
 
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = RaftConfig {
+            id: 1,
+            cluster: crate::Cluster::from_toml("[[nodes]]\nid = 1\naddr = \"127.0.0.1:1\"\n")?,
+            dir: tmp_dir.path().join("raft"),
+        };
+        let result = Server::new(lsm()?)
+            .raft(config)
+            .serve(listener, std::future::pending())
+            .await;
+        assert!(matches!(result, Err(KvsError::Unsupported("raft"))));
         Ok(())
     }
 }
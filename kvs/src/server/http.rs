@@ -0,0 +1,572 @@
+// HTTP/JSON gateway
+// GET/PUT/DELETE /keys/{key}, GET /keys?prefix={prefix}
+// valueはbodyのbytesをそのまま格納する. Content-Type: application/jsonの場合はJSONとしてvalidateする
+// ACLが設定されている場合はAuthorization: Bearer {token}で認証する
+// serverにTLSが設定されている場合はgatewayもTLS(https)で受け付ける
+use super::{resp::Expirations, Session};
+use crate::{
+    acl::{Acl, Operation},
+    metrics::ServerMetrics,
+    protocol::message::ErrorKind,
+    tls::Acceptor,
+    Kvs, KvsError, Result,
+};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::{borrow::Cow, convert::Infallible, future::Future, sync::Arc, time::Instant};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, Mutex},
+};
+use tracing::{field, info, info_span, warn};
+use tracing_futures::Instrument;
+
+// PUTで受け付けるbodyのdefaultの上限. bodyはmemoryに読み込んでから書き込むので、valueの上限より小さくする
+pub(super) const DEFAULT_MAX_BODY_BYTES: u64 = 8 * 1024 * 1024;
+
+#[derive(Clone)]
+pub(super) struct Gateway {
+    kvs: Arc<Mutex<Kvs>>,
+    // RESPのEXPIREで設定された有効期限. 期限切れのkeyは返さず、書き込んだkeyの有効期限は取り除く
+    expirations: Arc<Mutex<Expirations>>,
+    acl: Option<Arc<Acl>>,
+    read_only: bool,
+    max_body_bytes: u64,
+    metrics: Arc<ServerMetrics>,
+}
+
+#[derive(Serialize)]
+struct KeysResponse {
+    keys: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl Gateway {
+    pub(super) fn new(
+        kvs: Arc<Mutex<Kvs>>,
+        expirations: Arc<Mutex<Expirations>>,
+        acl: Option<Arc<Acl>>,
+        read_only: bool,
+        max_body_bytes: u64,
+        metrics: Arc<ServerMetrics>,
+    ) -> Self {
+        Self {
+            kvs,
+            expirations,
+            acl,
+            read_only,
+            max_body_bytes,
+            metrics,
+        }
+    }
+
+    // shutdownがcompleteすると処理中のrequestの完了を待って返る
+    pub(super) async fn serve<F>(
+        self,
+        listener: std::net::TcpListener,
+        acceptor: Acceptor,
+        shutdown: F,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener)?;
+        info!(addr=?listener.local_addr()?, "Http gateway listening...");
+
+        // connectionごとのtaskにshutdownを通知し、全taskがSenderをdropするまで待つ
+        let (notify_shutdown, _) = broadcast::channel::<()>(1);
+        let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+        tokio::pin!(shutdown);
+
+        loop {
+            let (conn, remote) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("{}", err);
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            let gateway = self.clone();
+            let acceptor = acceptor.clone();
+            let mut shutdown = notify_shutdown.subscribe();
+            let done = done_tx.clone();
+            // TLSのhandshakeもtask内で行い、遅いclientが他のconnectionのacceptを妨げないようにする
+            tokio::task::spawn(async move {
+                let _done = done;
+                let stream = match acceptor.accept(conn).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!(?remote, "Failed to accept http connection: {}", err);
+                        return;
+                    }
+                };
+                let service = service_fn(move |req| {
+                    let gateway = gateway.clone();
+                    async move { Ok::<_, Infallible>(gateway.handle(req).await) }
+                });
+                let conn = Http::new().serve_connection(stream, service);
+                tokio::pin!(conn);
+                let result = tokio::select! {
+                    result = &mut conn => result,
+                    _ = shutdown.recv() => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(err) = result {
+                    warn!(?remote, "{}", err);
+                }
+            });
+        }
+
+        drop(listener);
+        let _ = notify_shutdown.send(());
+        drop(done_tx);
+        done_rx.recv().await;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let (method, path) = (req.method().clone(), req.uri().path().to_owned());
+        self.metrics.requests.inc();
+        let span = info_span!(
+            "request",
+            %method,
+            %path,
+            status = field::Empty,
+            duration_us = field::Empty,
+        );
+        let start = Instant::now();
+        let response = match self.route(req).instrument(span.clone()).await {
+            Ok(response) => response,
+            Err(err) => {
+                span.in_scope(|| warn!("{}", err));
+                self.metrics.request_errors.inc();
+                error_response(&err)
+            }
+        };
+        span.record("status", response.status().as_u16());
+        span.record("duration_us", start.elapsed().as_micros() as u64);
+        span.in_scope(|| info!("Request handled"));
+        response
+    }
+
+    async fn route(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let session = self.session(&req)?;
+        let path = req.uri().path().to_owned();
+
+        if path == "/keys" {
+            return match *req.method() {
+                Method::GET => self.list(&session, &req).await,
+                _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+            };
+        }
+        let key = match path.strip_prefix("/keys/") {
+            Some(key) if !key.is_empty() => percent_decode_str(key).decode_utf8()?.into_owned(),
+            _ => return Ok(status(StatusCode::NOT_FOUND)),
+        };
+        match *req.method() {
+            Method::GET => self.get(&session, &req, key).await,
+            Method::PUT => self.put(&session, req, key).await,
+            Method::DELETE => self.delete(&session, key).await,
+            _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+        }
+    }
+
+    async fn get(
+        &self,
+        session: &Session,
+        req: &Request<Body>,
+        key: String,
+    ) -> Result<Response<Body>> {
+        session.authorize(Operation::Get, &key)?;
+        let value = {
+            let mut kvs = self.kvs.lock().await;
+            self.expirations.lock().await.purge(&mut kvs, &key)?;
+            kvs.get_raw(&key)?
+        };
+
+        if accepts_json(req) {
+            if serde_json::from_slice::<serde_json::Value>(&value).is_err() {
+                return Ok(status(StatusCode::NOT_ACCEPTABLE));
+            }
+            Ok(with_content_type(value, mime::APPLICATION_JSON))
+        } else {
+            Ok(with_content_type(value, mime::APPLICATION_OCTET_STREAM))
+        }
+    }
+
+    async fn put(
+        &self,
+        session: &Session,
+        req: Request<Body>,
+        key: String,
+    ) -> Result<Response<Body>> {
+        session.authorize(Operation::Put, &key)?;
+        let is_json = is_json(&req);
+        let value = read_value(req, self.max_body_bytes).await?;
+        if is_json {
+            if let Err(err) = serde_json::from_slice::<serde_json::Value>(&value) {
+                return Ok(json(
+                    StatusCode::BAD_REQUEST,
+                    &ErrorResponse {
+                        error: err.to_string(),
+                    },
+                ));
+            }
+        }
+
+        let mut kvs = self.kvs.lock().await;
+        kvs.put_raw(key.as_str(), value)?;
+        self.expirations.lock().await.clear(&key);
+        Ok(status(StatusCode::NO_CONTENT))
+    }
+
+    // 削除したvalueを返す
+    async fn delete(&self, session: &Session, key: String) -> Result<Response<Body>> {
+        session.authorize(Operation::Delete, &key)?;
+        let mut kvs = self.kvs.lock().await;
+        let mut expirations = self.expirations.lock().await;
+        expirations.purge(&mut kvs, &key)?;
+        expirations.clear(&key);
+        match kvs.delete_raw(&key)? {
+            Some(value) => Ok(with_content_type(value, mime::APPLICATION_OCTET_STREAM)),
+            None => Err(KvsError::NotFound),
+        }
+    }
+
+    // prefixにmatchし、getが許可されているkeyをsortして返す
+    async fn list(&self, session: &Session, req: &Request<Body>) -> Result<Response<Body>> {
+        let prefix = req
+            .uri()
+            .query()
+            .and_then(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(name, _)| name == "prefix")
+                    .map(|(_, value)| value)
+            })
+            .unwrap_or(Cow::Borrowed(""));
+
+        let mut kvs = self.kvs.lock().await;
+        self.expirations.lock().await.purge_all(&mut kvs)?;
+        let mut keys = kvs
+            .owned_keys()
+            .filter(|key| key.starts_with(prefix.as_ref()))
+            .filter(|key| session.authorize(Operation::Get, key).is_ok())
+            .collect::<Vec<_>>();
+        keys.sort();
+
+        Ok(json(StatusCode::OK, &KeysResponse { keys }))
+    }
+
+    // ACLが設定されている場合はAuthorization headerのtokenで認証する
+    fn session(&self, req: &Request<Body>) -> Result<Session> {
+        let mut session = Session::new(self.acl.clone(), self.read_only);
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let Some(token) = token {
+            session.authenticate(token.trim())?;
+        }
+        Ok(session)
+    }
+}
+
+// bodyをvalueとして読み込む. 上限を超える場合はContent-Lengthで、なければ読み込みながら打ち切る
+async fn read_value(req: Request<Body>, max_body_bytes: u64) -> Result<Vec<u8>> {
+    let max = max_body_bytes.min(crate::MAX_VALUE_BYTES as u64);
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > max) {
+        return Err(KvsError::MaxValueBytes);
+    }
+
+    let mut body = req.into_body();
+    let mut value = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (value.len() + chunk.len()) as u64 > max {
+            return Err(KvsError::MaxValueBytes);
+        }
+        value.extend_from_slice(&chunk);
+    }
+    Ok(value)
+}
+
+fn accepts_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains(mime::APPLICATION_JSON.as_ref()))
+        .unwrap_or(false)
+}
+
+fn is_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with(mime::APPLICATION_JSON.as_ref()))
+        .unwrap_or(false)
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::default();
+    *response.status_mut() = status;
+    response
+}
+
+fn with_content_type(value: Vec<u8>, mime: mime::Mime) -> Response<Body> {
+    let mut response = Response::new(Body::from(value));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(mime.as_ref()).unwrap(),
+    );
+    response
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    let mut response = with_content_type(
+        serde_json::to_vec(body).expect("serialize json response"),
+        mime::APPLICATION_JSON,
+    );
+    *response.status_mut() = status;
+    response
+}
+
+// kvs protocolのErrorResponseと同じ分類でstatus codeを決める
+fn error_response(err: &KvsError) -> Response<Body> {
+    let status = match ErrorKind::from(err) {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnly => StatusCode::FORBIDDEN,
+        ErrorKind::NotLeader => StatusCode::MISDIRECTED_REQUEST,
+        ErrorKind::Internal => match err {
+            KvsError::InvalidKey { .. } => StatusCode::BAD_REQUEST,
+            KvsError::MaxValueBytes => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    };
+    json(
+        status,
+        &ErrorResponse {
+            error: err.to_string(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{protocol::resp::Value, Acl, Client, Kvs, Protocol, Server};
+    use anyhow::Error;
+    use hyper::{header, Body, Client as HttpClient, Method, Request, StatusCode};
+    use std::result::Result as StdResult;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    };
+
+    async fn request(
+        method: Method,
+        uri: String,
+        token: Option<&str>,
+        content_type: Option<&str>,
+        body: &str,
+    ) -> StdResult<(StatusCode, String), Error> {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        if let Some(content_type) = content_type {
+            req = req
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::ACCEPT, content_type);
+        }
+        let response = HttpClient::new()
+            .request(req.body(Body::from(body.to_owned()))?)
+            .await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok((status, String::from_utf8(body.to_vec())?))
+    }
+
+    #[tokio::test]
+    async fn gateway() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+        let acl = Acl::from_toml(
+            r#"
+[[grants]]
+token = "app"
+operations = ["get", "put", "delete"]
+prefixes = ["app/"]
+"#,
+        )?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let tcp_addr = listener.local_addr()?.to_string();
+        let http_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let http_listener_addr = http_listener.local_addr()?;
+        let http = format!("http://{}", http_listener_addr);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            Server::new(kvs)
+                .acl(acl)
+                .http_max_body_bytes(1024)
+                .serve_with(Some(listener), Some(http_listener), None, async {
+                    shutdown_rx.await.ok();
+                }),
+        );
+
+        let key = |key: &str| format!("{}/keys/{}", http, key);
+        let (status, _) = request(Method::PUT, key("app%2F1"), None, None, "v").await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = request(Method::PUT, key("app%2F1"), Some("app"), None, "v1").await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = request(Method::GET, key("app%2F1"), Some("app"), None, "").await?;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "v1"));
+
+        // JSON body
+        let json = Some("application/json");
+        let (status, _) = request(Method::PUT, key("app/2"), Some("app"), json, "{").await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) =
+            request(Method::PUT, key("app/2"), Some("app"), json, r#"{"a":1}"#).await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = request(Method::GET, key("app/2"), Some("app"), json, "").await?;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, r#"{"a":1}"#));
+        let (status, _) = request(Method::GET, key("app/1"), Some("app"), json, "").await?;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+
+        // TCP serverと同じKvsを共有している
+        let mut client = Client::connect(&tcp_addr, None).await?;
+        client.auth("app").await?;
+        client.put_raw("app/3", b"from tcp".to_vec()).await?;
+        client
+            .put_raw("other", b"hidden".to_vec())
+            .await
+            .unwrap_err();
+        let (status, body) = request(Method::GET, key("app/3"), Some("app"), None, "").await?;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "from tcp"));
+
+        let (status, body) = request(
+            Method::GET,
+            format!("{}/keys?prefix=app%2F", http),
+            Some("app"),
+            None,
+            "",
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"keys":["app/1","app/2","app/3"]}"#);
+
+        let (status, body) = request(Method::DELETE, key("app/1"), Some("app"), None, "").await?;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "v1"));
+        let (status, _) = request(Method::DELETE, key("app/1"), Some("app"), None, "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = request(Method::GET, key("app/1"), Some("app"), None, "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let large = "x".repeat(1025);
+        let (status, _) = request(Method::PUT, key("app/4"), Some("app"), None, &large).await?;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let (status, _) =
+            request(Method::PUT, key("app/4"), Some("app"), None, &large[1..]).await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        // 上限を超えるContent-Lengthはbodyを読まずに拒否する
+        let mut conn = tokio::net::TcpStream::connect(http_listener_addr).await?;
+        conn.write_all(
+            format!(
+                "PUT /keys/app%2F4 HTTP/1.1\r\nhost: localhost\r\nauthorization: Bearer app\r\ncontent-length: {}\r\n\r\n",
+                1025
+            )
+            .as_bytes(),
+        )
+        .await?;
+        let mut response = [0_u8; 12];
+        conn.read_exact(&mut response).await?;
+        assert_eq!(&response, b"HTTP/1.1 413");
+
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+
+    // RESPで設定した有効期限は、HTTP gatewayでの読み書きにも反映される
+    #[tokio::test]
+    async fn expirations() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let resp_addr = listener.local_addr()?;
+        let http_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let http = format!("http://{}", http_listener.local_addr()?);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(Server::new(kvs).protocol(Protocol::Resp).serve_with(
+            Some(listener),
+            Some(http_listener),
+            None,
+            async {
+                shutdown_rx.await.ok();
+            },
+        ));
+
+        let mut conn = TcpStream::connect(resp_addr).await?;
+        let command = |args: &[&str]| {
+            let mut buff = Vec::new();
+            Value::Array(
+                args.iter()
+                    .map(|arg| Value::Bulk(arg.as_bytes().to_vec()))
+                    .collect(),
+            )
+            .encode(&mut buff);
+            buff
+        };
+        conn.write_all(&command(&["SET", "1", "v1", "PX", "50"]))
+            .await?;
+        conn.write_all(&command(&["SET", "2", "v2", "PX", "50"]))
+            .await?;
+        let mut reply = [0_u8; 10];
+        conn.read_exact(&mut reply).await?;
+        assert_eq!(&reply, b"+OK\r\n+OK\r\n");
+
+        // HTTPでputしたkeyは有効期限がなくなる
+        let key = |key: &str| format!("{}/keys/{}", http, key);
+        let (status, _) = request(Method::PUT, key("1"), None, None, "new").await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+
+        let (status, body) = request(Method::GET, key("1"), None, None, "").await?;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "new"));
+        let (status, _) = request(Method::GET, key("2"), None, None, "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = request(Method::GET, format!("{}/keys", http), None, None, "").await?;
+        assert_eq!(body, r#"{"keys":["1"]}"#);
+
+        drop(conn);
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+// GET /metricsでPrometheusのtext formatのmetricsを返す
+use crate::{
+    metrics::{Encoder, ServerMetrics},
+    Kvs, KvsError, Result,
+};
+use hyper::{
+    header::{self, HeaderValue},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use std::{convert::Infallible, future::Future, net::TcpListener, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::info;
+
+#[derive(Clone)]
+pub(super) struct Exporter {
+    kvs: Arc<Mutex<Kvs>>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl Exporter {
+    pub(super) fn new(kvs: Arc<Mutex<Kvs>>, metrics: Arc<ServerMetrics>) -> Self {
+        Self { kvs, metrics }
+    }
+
+    pub(super) async fn serve<F>(self, listener: TcpListener, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        listener.set_nonblocking(true)?;
+        info!(addr=?listener.local_addr()?, "Metrics endpoint listening...");
+
+        hyper::Server::from_tcp(listener)?
+            .serve(make_service_fn(move |_| {
+                let exporter = self.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let exporter = exporter.clone();
+                        async move { Ok::<_, Infallible>(exporter.handle(req).await) }
+                    }))
+                }
+            }))
+            .with_graceful_shutdown(shutdown)
+            .await
+            .map_err(KvsError::from)
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.uri().path() != "/metrics" {
+            return status(StatusCode::NOT_FOUND);
+        }
+        if req.method() != Method::GET {
+            return status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        let mut response = Response::new(Body::from(self.encode().await));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        response
+    }
+
+    async fn encode(&self) -> String {
+        let mut encoder = Encoder::default();
+        {
+            let kvs = self.kvs.lock().await;
+            let stats = kvs.stats();
+            encoder.gauge("kvs_file_bytes", "Size of kvs file.", stats.file_bytes);
+            encoder.gauge(
+                "kvs_live_bytes",
+                "Bytes of entries referenced by index.",
+                stats.live_bytes,
+            );
+            encoder.gauge(
+                "kvs_stale_bytes",
+                "Bytes of overwritten or deleted entries.",
+                stats.stale_bytes,
+            );
+            encoder.gauge("kvs_keys", "Number of keys in index.", stats.keys);
+            encoder.counter(
+                "kvs_cache_hits_total",
+                "Number of gets served from value cache.",
+                stats.cache_hits,
+            );
+            encoder.counter(
+                "kvs_cache_misses_total",
+                "Number of gets which missed value cache.",
+                stats.cache_misses,
+            );
+            encoder.counter(
+                "kvs_filter_hits_total",
+                "Number of table reads skipped by bloom filter.",
+                stats.filter_hits,
+            );
+            encoder.counter(
+                "kvs_filter_false_positives_total",
+                "Number of table reads which bloom filter did not skip but found no key.",
+                stats.filter_false_positives,
+            );
+            encoder.operations(kvs.metrics());
+        }
+
+        let metrics = &self.metrics;
+        encoder.counter(
+            "kvs_connections_total",
+            "Number of accepted connections.",
+            metrics.connections.get(),
+        );
+        encoder.gauge(
+            "kvs_active_connections",
+            "Number of open connections.",
+            metrics.active_connections.get(),
+        );
+        encoder.counter(
+            "kvs_requests_total",
+            "Number of handled requests.",
+            metrics.requests.get(),
+        );
+        encoder.counter(
+            "kvs_request_errors_total",
+            "Number of requests which returned error.",
+            metrics.request_errors.get(),
+        );
+        encoder.finish()
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::default();
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Client, Kvs, Server};
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    #[tokio::test]
+    async fn metrics() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+        kvs.set_cache_capacity(1024);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let metrics_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let uri = format!("http://{}/metrics", metrics_listener.local_addr()?);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(Server::new(kvs).serve_with(
+            Some(listener),
+            None,
+            Some(metrics_listener),
+            async {
+                shutdown_rx.await.ok();
+            },
+        ));
+
+        let mut client = Client::connect(&addr, None).await?;
+        client.put_raw("1", b"one".to_vec()).await?;
+        client.put_raw("1", b"uno".to_vec()).await?;
+        client.get_raw("1").await?;
+        client.get_raw("1").await?;
+        client.get_raw("2").await.unwrap_err();
+
+        let response = hyper::Client::new().get(uri.parse()?).await?;
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let text = String::from_utf8(body.to_vec())?;
+
+        assert!(text.contains("\nkvs_keys 1\n"));
+        assert!(text.contains("kvs_operations_total{op=\"put\"} 2\n"));
+        assert!(text.contains("kvs_operations_total{op=\"get\"} 3\n"));
+        assert!(text.contains("\nkvs_cache_hits_total 1\n"));
+        assert!(text.contains("\nkvs_cache_misses_total 2\n"));
+        assert!(text.contains("\nkvs_filter_hits_total 0\n"));
+        assert!(text.contains("\nkvs_connections_total 1\n"));
+        assert!(text.contains("\nkvs_active_connections 1\n"));
+        assert!(text.contains("\nkvs_requests_total 5\n"));
+        assert!(text.contains("\nkvs_request_errors_total 1\n"));
+        let stale = text
+            .lines()
+            .find_map(|line| line.strip_prefix("kvs_stale_bytes "))
+            .unwrap();
+        assert_ne!(stale, "0");
+
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+}
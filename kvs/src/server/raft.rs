@@ -0,0 +1,509 @@
+// Raft nodeをserverで駆動する
+// driver taskがNodeを所有し、tick/nodeからのmessage/clientからの要求を順に処理する
+use crate::{
+    entry::Entry,
+    raft::{Cluster, Command, Envelope, LogEntry, Node, NodeId, RaftConfig, Storage},
+    Client, Kvs, KvsError, Result, TlsConfig,
+};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tracing::{error, info, warn};
+
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+// peerへの送信待ちのmessageの上限. 超えた場合は破棄する(Raftは再送で回復する)
+const PEER_QUEUE: usize = 256;
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(200);
+// 要求の結果を待つ上限. quorumが揃わずcommitできない場合に、clientを待たせ続けないようにする
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Reply<T> = oneshot::Sender<Result<T>>;
+
+enum Request {
+    Propose {
+        entry: Entry,
+        reply: Reply<Option<Vec<u8>>>,
+    },
+    Read {
+        reply: Reply<()>,
+    },
+    Message(Envelope),
+}
+
+// workerからdriverに要求を送るhandle
+#[derive(Clone)]
+pub(super) struct Raft {
+    tx: mpsc::Sender<Request>,
+}
+
+impl Raft {
+    pub(super) async fn put(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.propose(Entry::new(key, value)?).await.map(|_| ())
+    }
+
+    pub(super) async fn delete(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.propose(Entry::new(key, Vec::new())?.mark_delete()?)
+            .await
+    }
+
+    // linearizableなreadのために、leadershipを確認し最新のcommitが適用されるまで待つ
+    pub(super) async fn read_barrier(&self) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.request(Request::Read { reply }, rx).await
+    }
+
+    // 他のnodeから受け取ったmessage
+    pub(super) async fn receive(&self, message: &[u8]) -> Result<()> {
+        let envelope = bincode::deserialize(message)?;
+        self.tx
+            .clone()
+            .send(Request::Message(envelope))
+            .await
+            .map_err(|_| stopped())
+    }
+
+    async fn propose(&self, entry: Entry) -> Result<Option<Vec<u8>>> {
+        let (reply, rx) = oneshot::channel();
+        self.request(Request::Propose { entry, reply }, rx).await
+    }
+
+    async fn request<T>(&self, request: Request, rx: oneshot::Receiver<Result<T>>) -> Result<T> {
+        self.tx.clone().send(request).await.map_err(|_| stopped())?;
+        match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(result) => result.map_err(|_| stopped())?,
+            Err(_) => Err(KvsError::Raft("raft request timed out".to_owned())),
+        }
+    }
+}
+
+fn stopped() -> KvsError {
+    KvsError::Raft("raft node stopped".to_owned())
+}
+
+// 他のnodeに接続する際の設定
+pub(super) struct Transport {
+    pub(super) tls: Option<TlsConfig>,
+    pub(super) token: Option<String>,
+}
+
+// driverを起動してhandleを返す
+pub(super) fn start(
+    config: RaftConfig,
+    kvs: Arc<Mutex<Kvs>>,
+    transport: Transport,
+    shutdown: broadcast::Sender<()>,
+    done: mpsc::Sender<()>,
+) -> Result<Raft> {
+    config.validate()?;
+    let storage = Storage::open(&config.dir)?;
+    let node = Node::new(config.id, config.peers(), storage);
+    info!(id = config.id, term = node.term(), "Raft node started");
+
+    let transport = Arc::new(transport);
+    let peers = config
+        .peers()
+        .into_iter()
+        .filter_map(|id| {
+            let addr = config.cluster.addr(id)?.to_owned();
+            let (tx, rx) = mpsc::channel(PEER_QUEUE);
+            let peer = Peer {
+                id,
+                addr,
+                transport: Arc::clone(&transport),
+            };
+            let (shutdown, done) = (shutdown.subscribe(), done.clone());
+            tokio::task::spawn(async move {
+                let _done = done;
+                peer.run(rx, shutdown).await
+            });
+            Some((id, tx))
+        })
+        .collect();
+
+    let (tx, rx) = mpsc::channel(1024);
+    let driver = Driver {
+        node,
+        cluster: config.cluster,
+        kvs,
+        peers,
+        leader: None,
+        proposals: HashMap::new(),
+        reads: HashMap::new(),
+        ready_reads: Vec::new(),
+    };
+    let shutdown = shutdown.subscribe();
+    tokio::task::spawn(async move {
+        let _done = done;
+        if let Err(err) = driver.run(rx, shutdown).await {
+            error!("Raft node stopped: {}", err);
+        }
+    });
+
+    Ok(Raft { tx })
+}
+
+struct Proposal {
+    term: u64,
+    reply: Reply<Option<Vec<u8>>>,
+}
+
+struct Driver {
+    node: Node,
+    cluster: Cluster,
+    kvs: Arc<Mutex<Kvs>>,
+    peers: HashMap<NodeId, mpsc::Sender<Envelope>>,
+    // 最後に確認したleader. 変化をlogに出力する
+    leader: Option<NodeId>,
+    // 適用を待っているproposal. indexごとにproposal時のtermを保持する
+    proposals: HashMap<u64, Proposal>,
+    // leadershipの確認を待っているread
+    reads: HashMap<u64, Reply<()>>,
+    // 指定のindexまで適用されるのを待っているread
+    ready_reads: Vec<(u64, Reply<()>)>,
+}
+
+impl Driver {
+    async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<Request>,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> Result<()> {
+        let mut ticker = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.node.tick()?,
+                request = rx.recv() => match request {
+                    Some(request) => self.handle(request)?,
+                    None => return Ok(()),
+                },
+                _ = shutdown.recv() => {
+                    info!(id = self.node.id(), "Raft node shutdown");
+                    return Ok(());
+                }
+            }
+            self.process().await?;
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Result<()> {
+        match request {
+            Request::Propose { entry, reply } => {
+                let mut buff = Vec::with_capacity(entry.len());
+                entry.encode(&mut buff)?;
+                match self.node.propose(Command::Kvs(buff)) {
+                    Ok((index, term)) => {
+                        // 上書きされたentryのproposalが残っていれば失敗させる
+                        if let Some(old) = self.proposals.insert(index, Proposal { term, reply }) {
+                            let _ = old.reply.send(Err(KvsError::NotLeader(self.leader_addr())));
+                        }
+                    }
+                    Err(err) => self.reply_not_leader(reply, err),
+                }
+            }
+            Request::Read { reply } => match self.node.read_index() {
+                Ok(ctx) => {
+                    self.reads.insert(ctx, reply);
+                }
+                Err(err) => self.reply_not_leader(reply, err),
+            },
+            Request::Message(envelope) => self.node.step(envelope)?,
+        }
+        Ok(())
+    }
+
+    // nodeの状態変化を反映する
+    async fn process(&mut self) -> Result<()> {
+        if self.node.leader() != self.leader {
+            self.leader = self.node.leader();
+            info!(
+                id = self.node.id(),
+                term = self.node.term(),
+                role = ?self.node.role(),
+                leader = ?self.leader,
+                "Leader changed"
+            );
+        }
+        for envelope in self.node.take_messages() {
+            if let Some(peer) = self.peers.get_mut(&envelope.to) {
+                // 詰まっているpeerへのmessageは破棄する
+                let _ = peer.try_send(envelope);
+            }
+        }
+
+        for (ctx, index) in self.node.take_ready_reads() {
+            if let Some(reply) = self.reads.remove(&ctx) {
+                self.ready_reads.push((index, reply));
+            }
+        }
+        // leaderでなくなった場合、確認中のreadは失敗させる
+        if !self.node.is_leader() {
+            let leader = self.leader_addr();
+            for (_, reply) in self.reads.drain() {
+                let _ = reply.send(Err(KvsError::NotLeader(leader.clone())));
+            }
+        }
+
+        let committed = self.node.committed();
+        if !committed.is_empty() {
+            let mut kvs = self.kvs.lock().await;
+            let mut failed = None;
+            for (index, entry) in committed {
+                let entry_term = entry.term;
+                let result = apply(&mut kvs, entry);
+                match &result {
+                    // 存在しないkeyの削除はどのnodeでも同じ結果になるので適用済みとする
+                    Ok(_) | Err(KvsError::NotFound) => self.node.set_applied(index),
+                    Err(err) => failed = Some(format!("failed to apply entry {}: {}", index, err)),
+                }
+                if let Some(proposal) = self.proposals.remove(&index) {
+                    // 別のleaderのentryで上書きされた場合は書き込まれていない
+                    let _ = proposal.reply.send(if proposal.term == entry_term {
+                        result
+                    } else {
+                        Err(KvsError::NotLeader(self.leader_addr()))
+                    });
+                }
+                if failed.is_some() {
+                    break;
+                }
+            }
+            drop(kvs);
+            self.node.persist_applied()?;
+            // 適用できなかったentryを飛ばして進めると他のnodeとkvsの内容が食い違うので、nodeを停止する
+            if let Some(err) = failed {
+                return Err(KvsError::Raft(err));
+            }
+        }
+        // leaderでなくなった場合、commitを待っているproposalは失敗させる
+        // 新しいleaderのもとでcommitされる可能性はあるが、このnodeでは結果を確認できない
+        if !self.node.is_leader() && !self.proposals.is_empty() {
+            let leader = self.leader_addr();
+            for (_, proposal) in self.proposals.drain() {
+                let _ = proposal
+                    .reply
+                    .send(Err(KvsError::NotLeader(leader.clone())));
+            }
+        }
+
+        let applied = self.node.applied();
+        let (ready, pending) = std::mem::take(&mut self.ready_reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(index, _)| *index <= applied);
+        self.ready_reads = pending;
+        for (_, reply) in ready {
+            let _ = reply.send(Ok(()));
+        }
+        Ok(())
+    }
+
+    fn leader_addr(&self) -> Option<String> {
+        self.node
+            .leader()
+            .and_then(|id| self.cluster.addr(id))
+            .map(str::to_owned)
+    }
+
+    fn reply_not_leader<T>(&self, reply: Reply<T>, err: KvsError) {
+        let err = match err {
+            KvsError::NotLeader(_) => KvsError::NotLeader(self.leader_addr()),
+            err => err,
+        };
+        let _ = reply.send(Err(err));
+    }
+}
+
+fn apply(kvs: &mut Kvs, entry: LogEntry) -> Result<Option<Vec<u8>>> {
+    match entry.command {
+        Command::Noop => Ok(None),
+        Command::Kvs(bytes) => kvs.apply_entry(Entry::decode_with_check(Cursor::new(bytes))?),
+    }
+}
+
+// peerへmessageを送り続ける. 接続できない間のmessageは破棄する
+struct Peer {
+    id: NodeId,
+    addr: String,
+    transport: Arc<Transport>,
+}
+
+impl Peer {
+    async fn run(self, mut rx: mpsc::Receiver<Envelope>, mut shutdown: broadcast::Receiver<()>) {
+        let mut client: Option<Client> = None;
+        let mut retry_at = Instant::now();
+        loop {
+            let envelope = tokio::select! {
+                envelope = rx.recv() => match envelope {
+                    Some(envelope) => envelope,
+                    None => return,
+                },
+                _ = shutdown.recv() => return,
+            };
+            if client.is_none() {
+                if Instant::now() < retry_at {
+                    continue;
+                }
+                match self.connect().await {
+                    Ok(c) => client = Some(c),
+                    Err(err) => {
+                        warn!(peer = self.id, addr=?self.addr, "Failed to connect: {}", err);
+                        retry_at = Instant::now() + RECONNECT_INTERVAL;
+                        continue;
+                    }
+                }
+            }
+            let result = match bincode::serialize(&envelope) {
+                Ok(message) => client.as_mut().unwrap().send_raft(message).await,
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                warn!(peer = self.id, addr=?self.addr, "Failed to send: {}", err);
+                client = None;
+            }
+        }
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let mut client = Client::connect(&self.addr, self.transport.tls.as_ref()).await?;
+        if let Some(token) = &self.transport.token {
+            client.auth(token).await?;
+        }
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Client, Cluster, Kvs, RaftConfig, Server};
+    use anyhow::Error;
+    use std::{path::Path, result::Result as StdResult, time::Duration};
+    use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+
+    struct TestNode {
+        addr: String,
+        shutdown: oneshot::Sender<()>,
+        handle: JoinHandle<crate::Result<()>>,
+    }
+
+    impl TestNode {
+        async fn stop(self) -> StdResult<(), Error> {
+            self.shutdown.send(()).unwrap();
+            self.handle.await??;
+            Ok(())
+        }
+    }
+
+    async fn start_cluster(dir: &Path, n: u64) -> StdResult<Vec<TestNode>, Error> {
+        let mut listeners = Vec::new();
+        let mut toml = String::new();
+        for id in 1..=n {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            toml.push_str(&format!(
+                "[[nodes]]\nid = {}\naddr = \"{}\"\n",
+                id,
+                listener.local_addr()?
+            ));
+            listeners.push(listener);
+        }
+        let cluster = Cluster::from_toml(&toml)?;
+
+        let mut nodes = Vec::new();
+        for (id, listener) in (1..=n).zip(listeners) {
+            let addr = listener.local_addr()?.to_string();
+            let server = Server::new(Kvs::new(dir.join(format!("{}.kvs", id)))?).raft(RaftConfig {
+                id,
+                cluster: cluster.clone(),
+                dir: dir.join(format!("{}.raft", id)),
+            });
+            let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+            let handle = tokio::spawn(server.serve(listener, async {
+                shutdown_rx.await.ok();
+            }));
+            nodes.push(TestNode {
+                addr,
+                shutdown,
+                handle,
+            });
+        }
+        Ok(nodes)
+    }
+
+    // leaderが選出されるまでNotLeaderのhintに従ってputを再試行し、leaderのaddressを返す
+    async fn put(addrs: &[&str], key: &str, value: &[u8]) -> StdResult<String, Error> {
+        let mut addr = addrs[0].to_owned();
+        for i in 0..100 {
+            let result = match Client::connect(&addr, None).await {
+                Ok(mut client) => client.put_raw(key, value.to_vec()).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => return Ok(addr),
+                Err(crate::KvsError::NotLeader(Some(leader))) if addrs.contains(&&*leader) => {
+                    addr = leader
+                }
+                Err(_) => {
+                    addr = addrs[i % addrs.len()].to_owned();
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                }
+            }
+        }
+        Err(anyhow::anyhow!("leader was not elected"))
+    }
+
+    #[tokio::test]
+    async fn cluster() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut nodes = start_cluster(tmp_dir.path(), 3).await?;
+        let addrs = nodes
+            .iter()
+            .map(|node| node.addr.clone())
+            .collect::<Vec<_>>();
+        let addrs = addrs.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let leader = put(&addrs, "1", b"one").await?;
+        let mut client = Client::connect(&leader, None).await?;
+        assert_eq!(client.get_raw("1").await?, b"one".to_vec());
+        assert_eq!(client.delete_raw("1").await?, Some(b"one".to_vec()));
+        assert!(client.get_raw("1").await.unwrap_err().is_not_found());
+        client.put_raw("2", b"two".to_vec()).await?;
+
+        // followerへのreadはleaderを案内する
+        let follower = addrs.iter().find(|&&addr| addr != leader).unwrap();
+        match Client::connect(follower, None).await?.get_raw("2").await {
+            Err(crate::KvsError::NotLeader(Some(addr))) => assert_eq!(addr, leader),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // leaderが停止しても残りの過半数で書き込みを続けられる
+        let i = nodes.iter().position(|node| node.addr == leader).unwrap();
+        nodes.remove(i).stop().await?;
+        let rest = addrs
+            .iter()
+            .copied()
+            .filter(|&addr| addr != leader)
+            .collect::<Vec<_>>();
+        let new_leader = put(&rest, "3", b"three").await?;
+        assert_ne!(new_leader, leader);
+        let mut client = Client::connect(&new_leader, None).await?;
+        assert_eq!(client.get_raw("2").await?, b"two".to_vec());
+
+        for node in nodes {
+            node.stop().await?;
+        }
+
+        // 応答したnodeのkvsには合意されたentryが適用されている
+        // followerへのcommitの通知は非同期なので検証しない
+        for (addr, keys) in [(leader, &["2"][..]), (new_leader, &["2", "3"][..])].iter() {
+            let id = addrs.iter().position(|&a| a == addr).unwrap() + 1;
+            let mut kvs = Kvs::new(tmp_dir.path().join(format!("{}.kvs", id)))?;
+            assert!(kvs.get_raw("1").unwrap_err().is_not_found());
+            for key in keys.iter() {
+                assert!(kvs.get_raw(key).is_ok());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+// leader-follower間の非同期replication
+// followerは自身のlogの末尾をoffsetとしてleaderに要求し、受け取ったlogをそのまま追記する
+// そのためfollowerのlogはleaderのlogと同じ内容になる(空のfileか、同じleaderのreplicaから開始する必要がある)
+use super::Context;
+use crate::{
+    acl::Operation,
+    protocol::message::{Message, Operator, Payload},
+    Client, Kvs, KvsError, Result, TlsConfig,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{info, warn};
+
+// leaderが新しいentryの追記を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// 1度に送るlogの大きさの目安
+const MAX_LOG_BYTES: usize = 1024 * 1024;
+// leaderとの接続が切れた場合に再接続するまでの間隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// leader: ReplicateRequestを受け取ったconnectionでoffset以降のlogを送り続ける
+pub(super) async fn ship(
+    operator: &mut Operator,
+    context: &mut Context,
+    offset: u64,
+) -> Result<()> {
+    match ship_log(operator, context, offset).await {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            // followerにも原因を伝える
+            let _ = operator
+                .send(Message::from_payload(Payload::error(&err))?)
+                .await;
+            Err(err)
+        }
+    }
+}
+
+async fn ship_log(operator: &mut Operator, context: &mut Context, mut offset: u64) -> Result<()> {
+    // 全てのkeyを送るので、全てのkeyのgetが許可されている必要がある
+    context.session.authorize(Operation::Get, "")?;
+    info!(offset, "Start shipping log");
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            // followerはrequestを送らないので、受信はconnectionの切断を意味する
+            _ = operator.receive() => {
+                info!(offset, "Follower disconnected");
+                return Ok(());
+            }
+            _ = context.shutdown.recv() => return Ok(()),
+        }
+
+        let log = context.kvs.lock().await.read_log(offset, MAX_LOG_BYTES)?;
+        if log.is_empty() {
+            continue;
+        }
+        let len = log.len() as u64;
+        operator
+            .send(Message::from_payload(Payload::ReplicateResponse {
+                offset,
+                log,
+            })?)
+            .await?;
+        offset += len;
+    }
+}
+
+// follower: leaderに接続してlogを受け取り続ける
+pub(super) struct Follower {
+    pub(super) leader: String,
+    pub(super) tls: Option<TlsConfig>,
+    pub(super) token: Option<String>,
+    pub(super) kvs: Arc<Mutex<Kvs>>,
+}
+
+impl Follower {
+    // shutdownされるまで、接続が切れても再接続する
+    pub(super) async fn run(self, mut shutdown: broadcast::Receiver<()>) {
+        loop {
+            tokio::select! {
+                result = self.follow() => if let Err(err) = result {
+                    warn!(leader=?self.leader, "Replication stopped: {}", err);
+                },
+                _ = shutdown.recv() => return,
+            }
+            tokio::select! {
+                _ = tokio::time::delay_for(RETRY_INTERVAL) => (),
+                _ = shutdown.recv() => return,
+            }
+        }
+    }
+
+    async fn follow(&self) -> Result<()> {
+        let mut client = Client::connect(&self.leader, self.tls.as_ref()).await?;
+        if let Some(token) = &self.token {
+            client.auth(token).await?;
+        }
+        let offset = self.kvs.lock().await.log_position()?;
+        info!(leader=?self.leader, offset, "Start replication");
+        client.replicate(offset).await?;
+
+        loop {
+            let (offset, log) = client.receive_log().await?;
+            let mut kvs = self.kvs.lock().await;
+            let position = kvs.log_position()?;
+            if offset != position {
+                return Err(KvsError::Replication(format!(
+                    "log offset mismatch. expected {} but received {}",
+                    position, offset
+                )));
+            }
+            kvs.apply_log(&log)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Client, Kvs, Server};
+    use anyhow::Error;
+    use std::{future::Future, result::Result as StdResult, time::Duration};
+    use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+
+    type Handle = (String, oneshot::Sender<()>, JoinHandle<crate::Result<()>>);
+
+    async fn spawn(server: Server) -> StdResult<Handle, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let handle = tokio::spawn(server.serve(listener, async {
+            shutdown_rx.await.ok();
+        }));
+        Ok((addr, shutdown_tx, handle))
+    }
+
+    // 非同期に複製されるので、条件を満たすまで待つ
+    async fn eventually<F, Fut>(mut f: F) -> StdResult<(), Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        for _ in 0..100 {
+            if f().await {
+                return Ok(());
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        Err(anyhow::anyhow!("replication timed out"))
+    }
+
+    async fn get(addr: &str, key: &str) -> crate::Result<Vec<u8>> {
+        Client::connect(addr, None).await?.get_raw(key).await
+    }
+
+    #[tokio::test]
+    async fn follow_leader() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let (leader, leader_shutdown, leader_handle) =
+            spawn(Server::new(Kvs::new(tmp_dir.path().join("leader.kvs"))?)).await?;
+
+        let mut client = Client::connect(&leader, None).await?;
+        client.put_raw("1", b"1".to_vec()).await?;
+        client.put_raw("2", b"2".to_vec()).await?;
+
+        let follower_path = tmp_dir.path().join("follower.kvs");
+        let (follower, follower_shutdown, follower_handle) =
+            spawn(Server::new(Kvs::new(&follower_path)?).replica_of(leader.as_str())).await?;
+
+        // 接続前に書き込まれたentryと接続後のentryの両方が複製される
+        client.delete_raw("1").await?;
+        client.put_raw("3", b"3".to_vec()).await?;
+        eventually(|| async { get(&follower, "3").await.is_ok() }).await?;
+        assert!(get(&follower, "1").await.unwrap_err().is_not_found());
+        assert_eq!(get(&follower, "2").await?, b"2".to_vec());
+
+        // followerはread-only
+        let mut follower_client = Client::connect(&follower, None).await?;
+        assert!(follower_client
+            .put_raw("4", b"4".to_vec())
+            .await
+            .unwrap_err()
+            .is_read_only());
+
+        // 再起動したfollowerは続きから複製する
+        follower_shutdown.send(()).unwrap();
+        follower_handle.await??;
+        client.put_raw("4", b"4".to_vec()).await?;
+        let (follower, follower_shutdown, follower_handle) =
+            spawn(Server::new(Kvs::new(&follower_path)?).replica_of(leader.as_str())).await?;
+        eventually(|| async { get(&follower, "4").await.is_ok() }).await?;
+        assert_eq!(get(&follower, "2").await?, b"2".to_vec());
+
+        follower_shutdown.send(()).unwrap();
+        follower_handle.await??;
+        leader_shutdown.send(()).unwrap();
+        leader_handle.await??;
+
+        assert_eq!(
+            std::fs::read(tmp_dir.path().join("leader.kvs"))?,
+            std::fs::read(&follower_path)?
+        );
+        Ok(())
+    }
+}
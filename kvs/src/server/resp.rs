@@ -0,0 +1,586 @@
+// Redis互換のfrontend
+// 対応しているcommandはPING, AUTH, GET, SET, DEL, EXISTS, KEYS, SCAN, EXPIRE, QUIT
+// valueはbincodeを介さずにbytesをそのまま格納する
+use super::Context;
+use crate::{
+    acl::Operation,
+    protocol::resp::{Connection, Value},
+    Kvs, KvsError, Result,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tracing::{field, info, info_span, warn, Span};
+use tracing_futures::Instrument;
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// EXPIREで設定されたkeyの有効期限
+// 永続化はしないので、serverを再起動すると有効期限は失われる
+// HTTP gatewayなど他のfrontendも、読み込む前にpurgeし、書き込んだkeyはclearする
+// lockはkvs、expirationsの順に取る
+#[derive(Default)]
+pub(super) struct Expirations(HashMap<String, Instant>);
+
+impl Expirations {
+    // 期限切れのkeyをkvsから削除する
+    pub(super) fn purge(&mut self, kvs: &mut Kvs, key: &str) -> Result<()> {
+        match self.0.get(key) {
+            Some(&deadline) if deadline <= Instant::now() => {
+                self.0.remove(key);
+                kvs.delete_raw(key).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn purge_all(&mut self, kvs: &mut Kvs) -> Result<()> {
+        let now = Instant::now();
+        let expired = self
+            .0
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.0.remove(&key);
+            kvs.delete_raw(&key)?;
+        }
+        Ok(())
+    }
+
+    // 書き込みや削除で置き換えられたkeyの有効期限を取り除く
+    pub(super) fn clear(&mut self, key: &str) {
+        self.0.remove(key);
+    }
+}
+
+pub(super) struct Worker {
+    remote: SocketAddr,
+    conn: Connection,
+    context: Context,
+}
+
+impl Worker {
+    pub(super) fn new(conn: Connection, remote: SocketAddr, context: Context) -> Self {
+        Self {
+            remote,
+            conn,
+            context,
+        }
+    }
+
+    pub(super) async fn dispatch(mut self) -> Result<()> {
+        info!(remote=?self.remote, "Resp worker dispatched");
+
+        loop {
+            let args = tokio::select! {
+                args = self.conn.read_command() => match args {
+                    Ok(args) => args,
+                    Err(err) if err.is_eof() => {
+                        info!(remote=?self.remote, "Connection closed");
+                        return Ok(());
+                    }
+                    // protocol errorの場合はerrorを返してconnectionを閉じる
+                    Err(err @ KvsError::RespProtocol(_)) => {
+                        self.conn.write_value(&error_value(&err)).await?;
+                        return Err(err);
+                    }
+                    Err(err) => return Err(err),
+                },
+                _ = self.context.shutdown.recv() => {
+                    info!(remote=?self.remote, "Worker shutdown before receiving request");
+                    return Ok(());
+                }
+            };
+
+            let command = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+
+            if command == "QUIT" {
+                return self.conn.write_value(&Value::ok()).await;
+            }
+            self.context.metrics.requests.inc();
+            let span = request_span(&command, &args);
+            let start = Instant::now();
+            let reply = match self.execute(&command, args).instrument(span.clone()).await {
+                Ok(reply) => reply,
+                Err(err) => {
+                    span.in_scope(|| warn!("{}", err));
+                    self.context.metrics.request_errors.inc();
+                    error_value(&err)
+                }
+            };
+            if let Value::Bulk(value) = &reply {
+                span.record("value_bytes", value.len() as u64);
+            }
+            span.record("duration_us", start.elapsed().as_micros() as u64);
+            span.in_scope(|| info!("Request handled"));
+            self.conn.write_value(&reply).await?;
+        }
+    }
+
+    async fn execute(&mut self, command: &str, mut args: Vec<Vec<u8>>) -> Result<Value> {
+        let arity_ok = match command {
+            "PING" => args.len() <= 2,
+            "AUTH" => args.len() == 2 || args.len() == 3,
+            "GET" | "KEYS" => args.len() == 2,
+            "SET" => args.len() >= 3,
+            "DEL" | "EXISTS" | "SCAN" => args.len() >= 2,
+            "EXPIRE" => args.len() == 3,
+            _ => {
+                return Ok(Value::Error(format!(
+                    "ERR unknown command '{}'",
+                    String::from_utf8_lossy(&args[0])
+                )))
+            }
+        };
+        if !arity_ok {
+            return Ok(Value::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                command.to_ascii_lowercase()
+            )));
+        }
+        // command名を取り除いて引数だけにする
+        args.remove(0);
+
+        match command {
+            "PING" => Ok(match args.pop() {
+                Some(message) => Value::Bulk(message),
+                None => Value::Simple("PONG".to_owned()),
+            }),
+            // AUTH [username] password
+            "AUTH" => {
+                let token = String::from_utf8_lossy(&args[args.len() - 1]).into_owned();
+                self.context
+                    .session
+                    .authenticate(&token)
+                    .map(|_| Value::ok())
+            }
+            "GET" => self.get(key(args.remove(0))?).await,
+            "SET" => self.set(args).await,
+            "DEL" => self.del(args).await,
+            "EXISTS" => self.exists(args).await,
+            "KEYS" => {
+                let keys = self.visible_keys(&args[0]).await?;
+                Ok(Value::Array(keys.into_iter().map(bulk).collect()))
+            }
+            "SCAN" => self.scan(args).await,
+            "EXPIRE" => {
+                let seconds = match integer(&args[1]) {
+                    Some(seconds) => seconds,
+                    None => return Ok(not_integer()),
+                };
+                self.expire(key(args.remove(0))?, seconds).await
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    async fn get(&mut self, key: String) -> Result<Value> {
+        self.context.session.authorize(Operation::Get, &key)?;
+        let mut kvs = self.context.kvs.lock().await;
+        self.context
+            .expirations
+            .lock()
+            .await
+            .purge(&mut kvs, &key)?;
+        match kvs.get_raw(&key) {
+            Ok(value) => Ok(Value::Bulk(value)),
+            Err(KvsError::NotFound) => Ok(Value::Null),
+            Err(err) => Err(err),
+        }
+    }
+
+    // SET key value [EX seconds|PX milliseconds]
+    async fn set(&mut self, mut args: Vec<Vec<u8>>) -> Result<Value> {
+        let options = args.split_off(2);
+        let value = args.pop().unwrap();
+        let key = key(args.pop().unwrap())?;
+
+        let ttl = match options.as_slice() {
+            [] => None,
+            [unit, n] => match (
+                String::from_utf8_lossy(unit).to_ascii_uppercase().as_str(),
+                integer(n),
+            ) {
+                ("EX", Some(n)) if n > 0 => Some(Duration::from_secs(n as u64)),
+                ("PX", Some(n)) if n > 0 => Some(Duration::from_millis(n as u64)),
+                ("EX", _) | ("PX", _) => {
+                    return Ok(Value::Error(
+                        "ERR invalid expire time in 'set' command".to_owned(),
+                    ))
+                }
+                _ => return Ok(syntax_error()),
+            },
+            _ => return Ok(syntax_error()),
+        };
+
+        self.context.session.authorize(Operation::Put, &key)?;
+        let mut kvs = self.context.kvs.lock().await;
+        let mut expirations = self.context.expirations.lock().await;
+        kvs.put_raw(key.as_str(), value)?;
+        // SETは既存の有効期限をクリアする
+        match ttl {
+            Some(ttl) => expirations.0.insert(key, Instant::now() + ttl),
+            None => {
+                expirations.clear(&key);
+                None
+            }
+        };
+        Ok(Value::ok())
+    }
+
+    async fn del(&mut self, args: Vec<Vec<u8>>) -> Result<Value> {
+        let keys = args.into_iter().map(key).collect::<Result<Vec<_>>>()?;
+        for key in &keys {
+            self.context.session.authorize(Operation::Delete, key)?;
+        }
+
+        let mut kvs = self.context.kvs.lock().await;
+        let mut expirations = self.context.expirations.lock().await;
+        let mut deleted = 0;
+        for key in keys {
+            expirations.purge(&mut kvs, &key)?;
+            expirations.clear(&key);
+            if kvs.delete_raw(&key)?.is_some() {
+                deleted += 1;
+            }
+        }
+        Ok(Value::Integer(deleted))
+    }
+
+    async fn exists(&mut self, args: Vec<Vec<u8>>) -> Result<Value> {
+        let keys = args.into_iter().map(key).collect::<Result<Vec<_>>>()?;
+        for key in &keys {
+            self.context.session.authorize(Operation::Get, key)?;
+        }
+
+        let mut kvs = self.context.kvs.lock().await;
+        let mut expirations = self.context.expirations.lock().await;
+        let mut exists = 0;
+        for key in keys {
+            expirations.purge(&mut kvs, &key)?;
+            if kvs.contains_key(&key) {
+                exists += 1;
+            }
+        }
+        Ok(Value::Integer(exists))
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]
+    // cursorはsortしたkeyのindex. scan中にkeyが追加、削除された場合の結果は保証しない
+    async fn scan(&mut self, mut args: Vec<Vec<u8>>) -> Result<Value> {
+        let cursor = match integer(&args.remove(0)) {
+            Some(cursor) if cursor >= 0 => cursor as usize,
+            _ => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+        };
+        let mut pattern = b"*".to_vec();
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args.into_iter();
+        while let Some(option) = options.next() {
+            match (
+                String::from_utf8_lossy(&option)
+                    .to_ascii_uppercase()
+                    .as_str(),
+                options.next(),
+            ) {
+                ("MATCH", Some(p)) => pattern = p,
+                ("COUNT", Some(n)) => match integer(&n) {
+                    Some(n) if n > 0 => count = n as usize,
+                    _ => return Ok(syntax_error()),
+                },
+                _ => return Ok(syntax_error()),
+            }
+        }
+
+        let keys = self.visible_keys(&pattern).await?;
+        let end = keys.len().min(cursor.saturating_add(count));
+        let next = if end >= keys.len() { 0 } else { end };
+        let page = keys
+            .into_iter()
+            .skip(cursor)
+            .take(end.saturating_sub(cursor))
+            .map(bulk)
+            .collect();
+        Ok(Value::Array(vec![
+            Value::Bulk(next.to_string().into_bytes()),
+            Value::Array(page),
+        ]))
+    }
+
+    async fn expire(&mut self, key: String, seconds: i64) -> Result<Value> {
+        self.context.session.authorize(Operation::Delete, &key)?;
+        let mut kvs = self.context.kvs.lock().await;
+        let mut expirations = self.context.expirations.lock().await;
+        expirations.purge(&mut kvs, &key)?;
+        if !kvs.contains_key(&key) {
+            return Ok(Value::Integer(0));
+        }
+        if seconds <= 0 {
+            expirations.clear(&key);
+            kvs.delete_raw(&key)?;
+        } else {
+            expirations
+                .0
+                .insert(key, Instant::now() + Duration::from_secs(seconds as u64));
+        }
+        Ok(Value::Integer(1))
+    }
+
+    // patternにmatchし、getが許可されているkeyをsortして返す
+    async fn visible_keys(&mut self, pattern: &[u8]) -> Result<Vec<String>> {
+        let mut kvs = self.context.kvs.lock().await;
+        self.context.expirations.lock().await.purge_all(&mut kvs)?;
+        let mut keys = kvs
+            .owned_keys()
+            .filter(|key| glob_match(pattern, key.as_bytes()))
+            .filter(|key| self.context.session.authorize(Operation::Get, key).is_ok())
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+fn key(arg: Vec<u8>) -> Result<String> {
+    String::from_utf8(arg).map_err(|err| KvsError::from(err.utf8_error()))
+}
+
+fn bulk(key: String) -> Value {
+    Value::Bulk(key.into_bytes())
+}
+
+fn integer(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn not_integer() -> Value {
+    Value::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+fn syntax_error() -> Value {
+    Value::Error("ERR syntax error".to_owned())
+}
+
+// commandごとのspan. AUTHのtokenは記録しない
+fn request_span(command: &str, args: &[Vec<u8>]) -> Span {
+    let span = info_span!(
+        "request",
+        op = command,
+        key = field::Empty,
+        value_bytes = field::Empty,
+        duration_us = field::Empty,
+    );
+    if let ("GET" | "SET" | "DEL" | "EXISTS" | "EXPIRE" | "KEYS", Some(key)) =
+        (command, args.get(1))
+    {
+        span.record("key", String::from_utf8_lossy(key).as_ref());
+    }
+    if let ("SET", Some(value)) = (command, args.get(2)) {
+        span.record("value_bytes", value.len() as u64);
+    }
+    span
+}
+
+fn error_value(err: &KvsError) -> Value {
+    match err {
+        KvsError::PermissionDenied => Value::Error(format!("NOPERM {}", err)),
+        KvsError::ReadOnly => Value::Error(format!("READONLY {}", err)),
+        _ => Value::Error(format!("ERR {}", err)),
+    }
+}
+
+// Redisのglob style pattern(*, ?, [abc], [^a-z], \x)
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => {
+            // 連続する*はひとつとして扱う
+            let rest = match rest.iter().position(|&b| b != b'*') {
+                Some(i) => &rest[i..],
+                None => return true,
+            };
+            (0..=s.len()).any(|i| glob_match(rest, &s[i..]))
+        }
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((b'[', rest)) => match (s.first(), class_match(rest, s.first().copied())) {
+            (Some(_), Some((true, rest))) => glob_match(rest, &s[1..]),
+            // 閉じられていない[はそのままの文字として扱う
+            (Some(b'['), None) => glob_match(rest, &s[1..]),
+            _ => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            s.first() == Some(&rest[0]) && glob_match(&rest[1..], &s[1..])
+        }
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+// [の直後からのpatternを受け取り、matchしたかと]以降のpatternを返す
+fn class_match(pattern: &[u8], c: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negate, mut pattern) = match pattern.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    let c = c?;
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => return None,
+            [b']', rest @ ..] => return Some((matched != negate, rest)),
+            [b'\\', x, rest @ ..] => {
+                matched |= c == *x;
+                pattern = rest;
+            }
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                let (lo, hi) = if lo <= hi { (*lo, *hi) } else { (*hi, *lo) };
+                matched |= lo <= c && c <= hi;
+                pattern = rest;
+            }
+            [x, rest @ ..] => {
+                matched |= c == *x;
+                pattern = rest;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Acl, Protocol, Server};
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    };
+
+    async fn assert_reply(conn: &mut TcpStream, command: &[&str], expected: &[u8]) {
+        let mut buff = Vec::new();
+        Value::Array(
+            command
+                .iter()
+                .map(|arg| Value::Bulk(arg.as_bytes().to_vec()))
+                .collect(),
+        )
+        .encode(&mut buff);
+        conn.write_all(&buff).await.unwrap();
+
+        let mut reply = vec![0_u8; expected.len()];
+        conn.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected),
+            "{:?}",
+            command
+        );
+    }
+
+    #[tokio::test]
+    async fn commands() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let kvs = Kvs::new(tmp_dir.path().join("test.kvs"))?;
+        let acl = Acl::from_toml(
+            r#"
+[[grants]]
+token = "app"
+operations = ["get", "put", "delete"]
+prefixes = ["app:"]
+"#,
+        )?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(Server::new(kvs).protocol(Protocol::Resp).acl(acl).serve(
+            listener,
+            async {
+                shutdown_rx.await.ok();
+            },
+        ));
+
+        let mut conn = TcpStream::connect(addr).await?;
+        assert_reply(&mut conn, &["PING"], b"+PONG\r\n").await;
+        assert_reply(
+            &mut conn,
+            &["GET", "app:1"],
+            b"-NOPERM permission denied\r\n",
+        )
+        .await;
+        assert_reply(&mut conn, &["AUTH", "app"], b"+OK\r\n").await;
+
+        assert_reply(&mut conn, &["set", "app:1", "one"], b"+OK\r\n").await;
+        assert_reply(&mut conn, &["SET", "app:2", "two"], b"+OK\r\n").await;
+        assert_reply(
+            &mut conn,
+            &["SET", "other", "x"],
+            b"-NOPERM permission denied\r\n",
+        )
+        .await;
+        assert_reply(&mut conn, &["GET", "app:1"], b"$3\r\none\r\n").await;
+        assert_reply(&mut conn, &["GET", "app:3"], b"$-1\r\n").await;
+        assert_reply(&mut conn, &["EXISTS", "app:1", "app:3"], b":1\r\n").await;
+        assert_reply(
+            &mut conn,
+            &["KEYS", "app:*"],
+            b"*2\r\n$5\r\napp:1\r\n$5\r\napp:2\r\n",
+        )
+        .await;
+        assert_reply(
+            &mut conn,
+            &["SCAN", "0", "COUNT", "1"],
+            b"*2\r\n$1\r\n1\r\n*1\r\n$5\r\napp:1\r\n",
+        )
+        .await;
+        assert_reply(
+            &mut conn,
+            &["SCAN", "1", "COUNT", "1"],
+            b"*2\r\n$1\r\n0\r\n*1\r\n$5\r\napp:2\r\n",
+        )
+        .await;
+        assert_reply(&mut conn, &["DEL", "app:1", "app:3"], b":1\r\n").await;
+
+        assert_reply(&mut conn, &["EXPIRE", "app:3", "10"], b":0\r\n").await;
+        assert_reply(&mut conn, &["SET", "app:2", "two", "PX", "50"], b"+OK\r\n").await;
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_reply(&mut conn, &["GET", "app:2"], b"$-1\r\n").await;
+        assert_reply(&mut conn, &["SET", "app:4", "four"], b"+OK\r\n").await;
+        assert_reply(&mut conn, &["EXPIRE", "app:4", "0"], b":1\r\n").await;
+        assert_reply(&mut conn, &["KEYS", "*"], b"*0\r\n").await;
+
+        assert_reply(
+            &mut conn,
+            &["FLUSHALL"],
+            b"-ERR unknown command 'FLUSHALL'\r\n",
+        )
+        .await;
+        assert_reply(
+            &mut conn,
+            &["GET"],
+            b"-ERR wrong number of arguments for 'get' command\r\n",
+        )
+        .await;
+        assert_reply(&mut conn, &["QUIT"], b"+OK\r\n").await;
+
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(glob_match(b"todo:*:title", b"todo:1:title"));
+        assert!(!glob_match(b"todo:*", b"task:1"));
+    }
+}
@@ -0,0 +1,329 @@
+// 複数のkvs serverにkeyを分散する
+// keyはvirtual nodeを配置したconsistent hashのringで担当のserverを決める
+// serverを追加/削除した際は、担当が変わるkeyのみをrebalanceで移動する
+use crate::{Client, KvsError, Result, TlsConfig};
+use std::collections::{BTreeMap, HashMap};
+use tracing::info;
+
+// serverごとにringへ配置するvirtual nodeの数のdefault
+pub const DEFAULT_VNODES: u32 = 128;
+
+#[derive(Debug, Clone)]
+pub struct Ring {
+    vnodes: u32,
+    ring: BTreeMap<u64, String>,
+}
+
+impl Ring {
+    pub fn new<I, S>(nodes: I, vnodes: u32) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut ring = Self {
+            vnodes,
+            ring: BTreeMap::new(),
+        };
+        nodes.into_iter().for_each(|node| ring.add(node));
+        ring
+    }
+
+    pub fn add<S: Into<String>>(&mut self, node: S) {
+        let node = node.into();
+        for i in 0..self.vnodes {
+            self.ring
+                .insert(hash(format!("{}#{}", node, i).as_bytes()), node.clone());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, n| n != node);
+    }
+
+    // keyのhash以降で最初のvirtual nodeのserverが担当する
+    pub fn node(&self, key: &str) -> Option<&str> {
+        let h = hash(key.as_bytes());
+        self.ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+// FNV-1aにmurmur3のfinalizerをかけて、似たkeyでもringに散らばるようにする
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+// keyの担当serverに要求を振り分けるclient
+pub struct ShardedClient {
+    ring: Ring,
+    clients: HashMap<String, Client>,
+}
+
+impl ShardedClient {
+    // 全てのserverに接続し、tokenが指定されていれば認証する
+    pub async fn connect(
+        nodes: &[String],
+        vnodes: u32,
+        tls: Option<&TlsConfig>,
+        token: Option<&str>,
+    ) -> Result<Self> {
+        if nodes.is_empty() {
+            return Err(KvsError::InvalidCluster("no shard nodes".to_owned()));
+        }
+        let mut clients = HashMap::new();
+        for node in nodes {
+            clients.insert(node.clone(), connect(node, tls, token).await?);
+        }
+        Ok(Self {
+            ring: Ring::new(nodes.iter().cloned(), vnodes),
+            clients,
+        })
+    }
+
+    pub fn node(&self, key: &str) -> Option<&str> {
+        self.ring.node(key)
+    }
+
+    // 全てのserverにechoする
+    pub async fn echo(&mut self, message: &str) -> Result<String> {
+        let mut response = String::new();
+        for client in self.clients.values_mut() {
+            response = client.echo(message).await?;
+        }
+        Ok(response)
+    }
+
+    pub async fn put<K, T>(&mut self, key: K, value: &T) -> Result<()>
+    where
+        K: Into<String>,
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let key = key.into();
+        self.client(&key)?.put(key, value).await
+    }
+
+    pub async fn get<T>(&mut self, key: &str) -> Result<T>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        self.client(key)?.get(key).await
+    }
+
+    pub async fn delete<T>(&mut self, key: &str) -> Result<Option<T>>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        self.client(key)?.delete(key).await
+    }
+
+    pub async fn put_raw<K: Into<String>>(&mut self, key: K, value: Vec<u8>) -> Result<()> {
+        let key = key.into();
+        self.client(&key)?.put_raw(key, value).await
+    }
+
+    pub async fn get_raw(&mut self, key: &str) -> Result<Vec<u8>> {
+        self.client(key)?.get_raw(key).await
+    }
+
+    pub async fn delete_raw(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.client(key)?.delete_raw(key).await
+    }
+
+    fn client(&mut self, key: &str) -> Result<&mut Client> {
+        let node = self
+            .ring
+            .node(key)
+            .ok_or_else(|| KvsError::InvalidCluster("no shard nodes".to_owned()))?;
+        Ok(self.clients.get_mut(node).unwrap())
+    }
+}
+
+async fn connect(addr: &str, tls: Option<&TlsConfig>, token: Option<&str>) -> Result<Client> {
+    let mut client = Client::connect(addr, tls).await?;
+    if let Some(token) = token {
+        client.auth(token).await?;
+    }
+    Ok(client)
+}
+
+// rebalanceで移動したkey
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub key: String,
+    pub from: String,
+    pub to: String,
+}
+
+// fromのserver構成からtoのserver構成に変更する際、担当が変わるkeyを移動する
+// 移動先に書き込んでから移動元を削除するので、途中で失敗しても再実行すればよい
+// dry_runの場合は移動するkeyを返すだけで書き込まない
+pub async fn rebalance(
+    from: &[String],
+    to: &[String],
+    vnodes: u32,
+    tls: Option<&TlsConfig>,
+    token: Option<&str>,
+    dry_run: bool,
+) -> Result<Vec<Migration>> {
+    let ring = Ring::new(to.iter().cloned(), vnodes);
+    if ring.is_empty() {
+        return Err(KvsError::InvalidCluster("no shard nodes".to_owned()));
+    }
+
+    let mut clients = HashMap::new();
+    for node in from.iter().chain(to.iter()) {
+        if !clients.contains_key(node) {
+            clients.insert(node.clone(), connect(node, tls, token).await?);
+        }
+    }
+
+    let mut migrations = Vec::new();
+    for node in from {
+        let keys = clients.get_mut(node).unwrap().keys("").await?;
+        for key in keys {
+            let owner = ring.node(&key).unwrap();
+            if owner == node {
+                continue;
+            }
+            if !dry_run {
+                let value = clients.get_mut(node).unwrap().get_raw(&key).await?;
+                clients
+                    .get_mut(owner)
+                    .unwrap()
+                    .put_raw(key.as_str(), value)
+                    .await?;
+                clients.get_mut(node).unwrap().delete_raw(&key).await?;
+            }
+            info!(?key, from=?node, to=?owner, dry_run, "Migrate key");
+            migrations.push(Migration {
+                key,
+                from: node.clone(),
+                to: owner.to_owned(),
+            });
+        }
+    }
+    Ok(migrations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvs, Server};
+    use anyhow::Error;
+    use std::result::Result as StdResult;
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    fn nodes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("10.0.0.{}:4002", i)).collect()
+    }
+
+    #[test]
+    fn ring_distribution() {
+        let ring = Ring::new(nodes(4), DEFAULT_VNODES);
+        let mut counts = HashMap::new();
+        for i in 0..10000 {
+            *counts
+                .entry(ring.node(&format!("key{}", i)).unwrap())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        // 均等であれば2500ずつになる
+        assert!(
+            counts.values().all(|&n| n > 1500 && n < 3500),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn ring_add_remove() {
+        let before = Ring::new(nodes(4), DEFAULT_VNODES);
+        let mut after = before.clone();
+        after.add("10.0.0.9:4002");
+
+        // 追加したnodeに移るkeyだけが担当を変える
+        let moved = (0..10000)
+            .map(|i| format!("key{}", i))
+            .filter(|key| before.node(key) != after.node(key))
+            .inspect(|key| assert_eq!(after.node(key), Some("10.0.0.9:4002")))
+            .count();
+        assert!(moved > 1000 && moved < 3000, "{}", moved);
+
+        after.remove("10.0.0.9:4002");
+        assert!((0..1000)
+            .map(|i| format!("key{}", i))
+            .all(|key| before.node(&key) == after.node(&key)));
+        assert!(Ring::new(Vec::<String>::new(), DEFAULT_VNODES)
+            .node("key")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn shard_and_rebalance() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let mut addrs = Vec::new();
+        let mut shutdowns = Vec::new();
+        let mut handles = Vec::new();
+        for i in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            addrs.push(listener.local_addr()?.to_string());
+            let kvs = Kvs::new(tmp_dir.path().join(format!("{}.kvs", i)))?;
+            let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+            handles.push(tokio::spawn(Server::new(kvs).serve(listener, async {
+                shutdown_rx.await.ok();
+            })));
+            shutdowns.push(shutdown_tx);
+        }
+
+        // 2台で書き込む
+        let before = addrs[..2].to_vec();
+        let mut client = ShardedClient::connect(&before, DEFAULT_VNODES, None, None).await?;
+        for i in 0..100 {
+            client.put_raw(format!("key{}", i), vec![i as u8]).await?;
+        }
+        assert_eq!(client.get_raw("key7").await?, vec![7]);
+
+        // 3台に増やす
+        let dry_run = rebalance(&before, &addrs, DEFAULT_VNODES, None, None, true).await?;
+        let migrations = rebalance(&before, &addrs, DEFAULT_VNODES, None, None, false).await?;
+        assert_eq!(dry_run, migrations);
+        assert!(!migrations.is_empty());
+        assert!(migrations.iter().all(|m| m.to == addrs[2]));
+        assert!(
+            rebalance(&before, &addrs, DEFAULT_VNODES, None, None, false)
+                .await?
+                .is_empty()
+        );
+
+        let mut client = ShardedClient::connect(&addrs, DEFAULT_VNODES, None, None).await?;
+        for i in 0..100 {
+            assert_eq!(client.get_raw(&format!("key{}", i)).await?, vec![i as u8]);
+        }
+        let mut third = Client::connect(&addrs[2], None).await?;
+        assert_eq!(third.keys("").await?.len(), migrations.len());
+
+        for shutdown in shutdowns {
+            shutdown.send(()).unwrap();
+        }
+        for handle in handles {
+            handle.await??;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+// Kvsがlogを書き込む先. fileの他にmemory上のbufferや、testのためのwrapperを差し込める
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, Write},
+};
+
+// 任意の位置からの読み込みと、logの末尾への追記ができること
+// Kvsは読み込み後に末尾へseekし直すので、writeは常にlogの末尾に対して行われる
+pub trait Storage: Read + Write + Seek + Send + Sync {
+    // lenより後ろを切り詰める. crashで末尾に残った書き込み途中のentryを取り除くのに利用する
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    // 書き込んだ内容を永続化する. 永続化の概念がないstorageは何もしない
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Storage for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sync_all()
+    }
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+}
@@ -1,16 +1,56 @@
-use crate::{engine::Engine, KvsError, Result};
+use crate::{
+    engine::{Engine, Version},
+    entry::Entry,
+    index::Indexes,
+    lsm::{LsmConfig, LsmEngine},
+    metrics::{EngineMetrics, Stats},
+    KvsError, Result, Storage,
+};
+use fs2::FileExt;
 use std::{
     fs::{self, File},
-    io,
-    path::Path,
+    io::{self, Cursor},
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
+// 開く際に選択するengine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    // 1つのlogに追記し、全てのkeyのoffsetをmemory上のindexに持つ
+    Log,
+    // memtableとSSTableによるengine. keyがmemoryに収まらない場合に利用する. pathはdirectoryになる
+    Lsm,
+}
+
+impl FromStr for EngineKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(EngineKind::Log),
+            "lsm" => Ok(EngineKind::Lsm),
+            _ => Err(KvsError::InvalidEngine(s.to_owned())),
+        }
+    }
+}
+
 pub struct Kvs {
-    engine: Engine<File>,
+    engine: Backend,
+    indexes: Indexes,
+    read_only: bool,
+    // 書き込み用にfileを開いている間、排他lockを保持する. dropで解放される
+    _lock: Option<File>,
 }
 
 impl Kvs {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Kvs::with_engine(path, EngineKind::Log)
+    }
+
+    // engineを選んで書き込み用に開く. pathはlog engineではfile、lsm engineではdirectoryになる
+    pub fn with_engine<P: AsRef<Path>>(path: P, engine: EngineKind) -> Result<Self> {
         let path = path.as_ref();
 
         // make sure root directory exists
@@ -28,24 +68,118 @@ impl Kvs {
             }
         }
 
-        let file = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .read(true)
+        // 複数のprocessが同じfileに追記するとindexのoffsetがずれるので、writerは1つに限る
+        let lock_path = lock_path(path);
+        let lock = fs::OpenOptions::new()
             .write(true)
-            .open(path)?;
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+        match lock.try_lock_exclusive() {
+            Ok(_) => (),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                return Err(KvsError::Locked(lock_path))
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        match engine {
+            EngineKind::Log => {
+                let file = fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .read(true)
+                    .open(path)?;
+                Kvs::open(Box::new(file), false, Some(lock))
+            }
+            EngineKind::Lsm => {
+                let engine = LsmEngine::open(path, LsmConfig::default(), false)?;
+                Ok(Kvs::with_backend(Backend::Lsm(engine), false, Some(lock)))
+            }
+        }
+    }
 
-        Engine::new(file).map(|engine| Self { engine })
+    // lockを取らずに読み込み専用で開く. writerが開いていても利用できる
+    // 書き込みはKvsError::ReadOnlyになる. open以降にwriterが追記したentryはrefreshで反映する
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Kvs::open_read_only_with_engine(path, EngineKind::Log)
+    }
+
+    // lsm engineの場合、open時点のtableを開いたまま保持するので、writerのcompactionで削除されても読める
+    pub fn open_read_only_with_engine<P: AsRef<Path>>(path: P, engine: EngineKind) -> Result<Self> {
+        match engine {
+            EngineKind::Log => Kvs::open(Box::new(File::open(path)?), true, None),
+            EngineKind::Lsm => {
+                let engine = LsmEngine::open(path.as_ref(), LsmConfig::default(), true)?;
+                Ok(Kvs::with_backend(Backend::Lsm(engine), true, None))
+            }
+        }
+    }
+
+    // memory上のbufferにlogを書き込む. dropすると内容は失われる. testなどで利用する
+    pub fn in_memory() -> Result<Self> {
+        Kvs::with_storage(Cursor::new(Vec::new()))
+    }
+
+    // storageの現在の位置から既存のlogを読み込んで開く. lockは取らない
+    pub fn with_storage<S: Storage + 'static>(storage: S) -> Result<Self> {
+        Kvs::open(Box::new(storage), false, None)
+    }
+
+    fn open(storage: Box<dyn Storage>, read_only: bool, lock: Option<File>) -> Result<Self> {
+        let mut engine = Engine::new(storage)?;
+        // read onlyの場合、不完全なentryはwriterが書き込み中の可能性があるので残す
+        if !read_only {
+            engine.truncate_incomplete()?;
+        }
+        Ok(Kvs::with_backend(Backend::Log(engine), read_only, lock))
+    }
+
+    fn with_backend(engine: Backend, read_only: bool, lock: Option<File>) -> Self {
+        Self {
+            engine,
+            indexes: Indexes::default(),
+            read_only,
+            _lock: lock,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // 前回のopen/refresh以降に他のprocessが追記したentryのみを読み込み、indexに反映する
+    // fileを先頭から読み直さないので、追記を追いかける用途で繰り返し呼び出せる. 反映したentryの数を返す
+    pub fn refresh(&mut self) -> Result<usize> {
+        let keys = self.engine.log("refresh")?.refresh()?;
+        if !self.indexes.is_empty() {
+            for key in &keys {
+                match self.engine.get_value(key) {
+                    Ok(value) => self.indexes.insert(key, &value),
+                    Err(KvsError::NotFound) => self.indexes.remove(key),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(keys.len())
     }
 
-    pub fn put<K, T: ?Sized>(&mut self, key: K, value: &T) -> Result<()>
+    fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            Err(KvsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn put<K, T>(&mut self, key: K, value: &T) -> Result<()>
     where
         K: Into<String>,
-        T: serde::Serialize + serde::de::DeserializeOwned,
+        T: serde::Serialize + ?Sized,
     {
         bincode::serialize(value)
             .map_err(KvsError::from)
-            .and_then(|bytes| self.engine.put(key, bytes))
+            .and_then(|bytes| self.put_raw(key, bytes))
     }
 
     pub fn get<T>(&mut self, key: &str) -> Result<T>
@@ -61,7 +195,7 @@ impl Kvs {
     where
         T: serde::Serialize + serde::de::DeserializeOwned,
     {
-        self.engine.delete(key).and_then(|opt| match opt {
+        self.delete_raw(key).and_then(|opt| match opt {
             Some(bytes) => Ok(Some(
                 bincode::deserialize::<T>(bytes.as_slice()).map_err(KvsError::from)?,
             )),
@@ -69,15 +203,188 @@ impl Kvs {
         })
     }
 
-    pub fn keys(&self) -> crate::Keys<'_> {
-        self.engine.keys()
+    // bincodeを介さずにvalueのbytesをそのまま扱う
+    pub fn put_raw<K: Into<String>>(&mut self, key: K, value: Vec<u8>) -> Result<()> {
+        self.check_writable()?;
+        if self.indexes.is_empty() {
+            return self.engine.put(key, value);
+        }
+        let key = key.into();
+        self.engine.put(key.clone(), value.clone())?;
+        self.indexes.insert(&key, &value);
+        Ok(())
+    }
+
+    // 1度の書き込みでまとめて追記する. 同じkeyが複数ある場合は後のvalueが残る
+    pub fn put_batch_raw(&mut self, batch: Vec<(String, Vec<u8>)>) -> Result<()> {
+        self.check_writable()?;
+        if self.indexes.is_empty() {
+            return self.engine.put_batch(batch);
+        }
+        self.engine.put_batch(batch.clone())?;
+        for (key, value) in batch {
+            self.indexes.insert(&key, &value);
+        }
+        Ok(())
+    }
+
+    pub fn get_raw(&mut self, key: &str) -> Result<Vec<u8>> {
+        self.engine.get(key)
+    }
+
+    pub fn delete_raw(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.check_writable()?;
+        let deleted = self.engine.delete(key)?;
+        self.indexes.remove(key);
+        Ok(deleted)
+    }
+
+    // valueをTとしてdeserializeし、extractで取り出した値をindexに登録する
+    // indexはmemory上にのみ持つので、開くたびに作成する. 作成時には全てのvalueを読み込む
+    // Tとしてdeserializeできないvalueはindexに含めない. 同じ名前のindexは置き換える
+    pub fn create_index<T, I, F>(&mut self, name: impl Into<String>, extract: F) -> Result<()>
+    where
+        T: serde::de::DeserializeOwned,
+        I: Ord + Clone + Send + Sync + 'static,
+        F: Fn(&T) -> I + Send + Sync + 'static,
+    {
+        let name = name.into();
+        self.indexes.create(name.clone(), move |bytes: &[u8]| {
+            bincode::deserialize::<T>(bytes)
+                .ok()
+                .map(|value| extract(&value))
+        });
+        let keys = self.owned_keys().collect::<Vec<_>>();
+        for key in keys {
+            let value = self.engine.get_value(&key)?;
+            self.indexes.insert_into(&name, &key, &value);
+        }
+        Ok(())
+    }
+
+    // indexが存在した場合はtrueを返す
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        self.indexes.drop(name)
+    }
+
+    // indexの値がvalueと一致するkeyを返す
+    pub fn lookup<I: Ord + 'static>(&self, name: &str, value: &I) -> Result<Vec<String>> {
+        self.indexes.lookup(name, value)
+    }
+
+    // indexの値がrangeに含まれるkeyをindexの値の昇順に返す. 値が同じ場合はkeyの順になる
+    pub fn range<I, R>(&self, name: &str, range: R) -> Result<Vec<String>>
+    where
+        I: Ord + 'static,
+        R: RangeBounds<I>,
+    {
+        self.indexes.range(name, range)
+    }
+
+    // keyの過去のvalueと削除をlogに書き込まれた順に返す
+    pub fn history<T>(&mut self, key: &str) -> Result<History<T>>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        self.engine
+            .log("history")?
+            .history(key)
+            .map(|versions| History {
+                inner: versions.into_iter(),
+                phantom: PhantomData,
+            })
+    }
+
+    pub fn history_raw(&mut self, key: &str) -> Result<Vec<Version>> {
+        self.engine.log("history")?.history(key)
+    }
+
+    // getで読み込んだvalueをbytes(keyとvalueの合計)まで保持し、同じkeyのgetでfileを読まないようにする
+    // 超えた場合は最も長く参照されていないvalueから捨てる. 0を指定すると無効になる(default)
+    pub fn set_cache_capacity(&mut self, bytes: usize) {
+        self.engine.set_cache_capacity(bytes)
+    }
+
+    // 書き込み済みのentryをstorageに永続化する. fileの場合はfsyncする
+    pub fn sync(&mut self) -> Result<()> {
+        self.engine.sync()
+    }
+
+    // replicationで利用する. logの扱いはEngineを参照
+    // lsm engineはlogを持たないので、log_position/read_log/apply_logはKvsError::Unsupportedになる
+    pub(crate) fn log_position(&mut self) -> Result<u64> {
+        Ok(self.engine.log("replication")?.position())
+    }
+
+    pub(crate) fn engine_kind(&self) -> EngineKind {
+        match &self.engine {
+            Backend::Log(_) => EngineKind::Log,
+            Backend::Lsm(_) => EngineKind::Lsm,
+        }
+    }
+
+    pub(crate) fn read_log(&mut self, offset: u64, max_bytes: usize) -> Result<Vec<u8>> {
+        self.engine.log("replication")?.read_log(offset, max_bytes)
+    }
+
+    pub(crate) fn apply_log(&mut self, log: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.engine.log("replication")?.apply_log(log)?;
+        if !self.indexes.is_empty() {
+            let mut r = log;
+            while !r.is_empty() {
+                let entry = Entry::decode(&mut r)?;
+                if entry.is_deleted() {
+                    self.indexes.remove(&entry.key);
+                } else {
+                    self.indexes.insert(&entry.key, &entry.value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Raftで合意されたentryを適用する. deleteの場合は削除したvalueを返す
+    pub(crate) fn apply_entry(&mut self, entry: Entry) -> Result<Option<Vec<u8>>> {
+        if entry.is_deleted() {
+            self.delete_raw(&entry.key)
+        } else {
+            self.put_raw(entry.key, entry.value).map(|_| None)
+        }
+    }
+
+    // fileとindexの状態、およびopen以降の操作の件数を返す
+    pub fn stats(&self) -> Stats {
+        self.engine.stats()
+    }
+
+    pub(crate) fn metrics(&self) -> &EngineMetrics {
+        self.engine.metrics()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.engine.contains_key(key)
+    }
+
+    // lsm engineはkeyをmemory上に持たず参照を返せないのでKvsError::Unsupportedになる
+    // engineによらない場合はowned_keysを利用する
+    pub fn keys(&self) -> Result<crate::Keys<'_>> {
+        match &self.engine {
+            Backend::Log(engine) => Ok(engine.keys()),
+            Backend::Lsm(_) => Err(KvsError::Unsupported("keys")),
+        }
+    }
+
+    // keyを所有したStringとして返す. lsm engineでは全てのtableを読むので、keyの数に比例して時間がかかる
+    pub fn owned_keys(&self) -> crate::OwnedKeys<'_> {
+        self.engine.owned_keys()
     }
 
     pub fn iter<De>(&mut self) -> Iter<'_, De>
     where
         De: serde::Serialize + serde::de::DeserializeOwned,
     {
-        let keys = self.keys().cloned().collect::<Vec<String>>();
+        let keys = self.owned_keys().collect::<Vec<String>>();
         Iter {
             kvs: self,
             inner: keys.into_iter(),
@@ -86,6 +393,106 @@ impl Kvs {
     }
 }
 
+// Kvsの操作をengineに振り分ける. logに固有の操作はlog()で取り出して利用する
+enum Backend {
+    Log(Engine<Box<dyn Storage>>),
+    Lsm(LsmEngine),
+}
+
+impl Backend {
+    fn log(&mut self, op: &'static str) -> Result<&mut Engine<Box<dyn Storage>>> {
+        match self {
+            Backend::Log(engine) => Ok(engine),
+            Backend::Lsm(_) => Err(KvsError::Unsupported(op)),
+        }
+    }
+
+    fn put<K: Into<String>>(&mut self, key: K, value: Vec<u8>) -> Result<()> {
+        match self {
+            Backend::Log(engine) => engine.put(key, value),
+            Backend::Lsm(engine) => engine.put(key.into(), value),
+        }
+    }
+
+    fn put_batch(&mut self, batch: Vec<(String, Vec<u8>)>) -> Result<()> {
+        match self {
+            Backend::Log(engine) => engine.put_batch(batch),
+            Backend::Lsm(engine) => engine.put_batch(batch),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Vec<u8>> {
+        match self {
+            Backend::Log(engine) => engine.get(key),
+            Backend::Lsm(engine) => engine.get(key),
+        }
+    }
+
+    // metricsには数えない. indexの構築などに利用する
+    fn get_value(&mut self, key: &str) -> Result<Vec<u8>> {
+        match self {
+            Backend::Log(engine) => engine.get_entry(key).map(|entry| entry.value),
+            Backend::Lsm(engine) => engine.lookup(key)?.ok_or(KvsError::NotFound),
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Backend::Log(engine) => engine.delete(key),
+            Backend::Lsm(engine) => engine.delete(key),
+        }
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        match self {
+            Backend::Log(engine) => engine.contains_key(key),
+            Backend::Lsm(engine) => engine.contains_key(key),
+        }
+    }
+
+    fn owned_keys(&self) -> crate::OwnedKeys<'_> {
+        match self {
+            Backend::Log(engine) => crate::OwnedKeys::new(engine.keys().cloned()),
+            Backend::Lsm(engine) => crate::OwnedKeys::new(engine.keys()),
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        match self {
+            Backend::Log(engine) => engine.stats(),
+            Backend::Lsm(engine) => engine.stats(),
+        }
+    }
+
+    fn metrics(&self) -> &EngineMetrics {
+        match self {
+            Backend::Log(engine) => engine.metrics(),
+            Backend::Lsm(engine) => engine.metrics(),
+        }
+    }
+
+    fn set_cache_capacity(&mut self, bytes: usize) {
+        match self {
+            Backend::Log(engine) => engine.set_cache_capacity(bytes),
+            Backend::Lsm(engine) => engine.set_cache_capacity(bytes),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        match self {
+            Backend::Log(engine) => engine.sync(),
+            Backend::Lsm(engine) => engine.sync(),
+        }
+    }
+}
+
+// data fileと同じdirectoryに置くlock file. "todo.kvs"の場合は"todo.kvs.lock"
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    lock_path.into()
+}
+
 pub struct Iter<'a, De> {
     kvs: &'a mut Kvs,
     inner: std::vec::IntoIter<String>,
@@ -94,6 +501,31 @@ pub struct Iter<'a, De> {
 
 use std::marker::PhantomData;
 
+// valueをdeserializeしながらkeyの過去のversionを返す
+pub struct History<T> {
+    inner: std::vec::IntoIter<Version>,
+    phantom: PhantomData<*const T>,
+}
+
+impl<T> Iterator for History<T>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Item = Result<Version<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|version| {
+            Ok(Version {
+                offset: version.offset,
+                value: match version.value {
+                    Some(bytes) => Some(bincode::deserialize::<T>(&bytes)?),
+                    None => None,
+                },
+            })
+        })
+    }
+}
+
 impl<'a, De> Iterator for Iter<'a, De>
 where
     De: serde::Serialize + serde::de::DeserializeOwned,
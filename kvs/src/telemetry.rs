@@ -0,0 +1,98 @@
+// CLIで利用するtracingのsubscriberを設定する
+// KVS_LOG: EnvFilterのdirective. defaultは"kvs=info"
+// KVS_LOG_FORMAT: "text"(default)か"json"
+// KVS_OTEL_ENDPOINT: otel featureが有効な場合、指定するとspanをOTLP/HTTPでcollectorに送る
+#[cfg(feature = "otel")]
+pub(crate) mod otel;
+
+use crate::{KvsError, Result};
+use std::{env, str::FromStr};
+use tracing_subscriber::{
+    fmt::{self, time::ChronoLocal},
+    layer::{Layer, Layered, SubscriberExt},
+    util::SubscriberInitExt,
+    EnvFilter, Registry,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    Text,
+    // 1行に1つのJSON objectとして出力する
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(KvsError::InvalidLogFormat(s.to_owned())),
+        }
+    }
+}
+
+// dropされるまでspanのexportを継続し、drop時にbufferされたspanを送る
+#[derive(Default)]
+pub(crate) struct Guard {
+    #[cfg(feature = "otel")]
+    _provider: Option<opentelemetry::sdk::trace::TracerProvider>,
+}
+
+type Filtered = Layered<EnvFilter, Registry>;
+
+pub(crate) fn init() -> Result<Guard> {
+    let format = match env::var("KVS_LOG_FORMAT") {
+        Ok(format) => format.parse()?,
+        Err(_) => LogFormat::Text,
+    };
+    let layer = fmt::layer()
+        .with_timer(ChronoLocal::rfc3339())
+        .with_target(true);
+    match format {
+        LogFormat::Text => install(layer),
+        LogFormat::Json => install(layer.json()),
+    }
+}
+
+fn install<L>(layer: L) -> Result<Guard>
+where
+    L: Layer<Filtered> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::new(env::var("KVS_LOG").unwrap_or_else(|_| "kvs=info".to_owned()));
+    let subscriber = Registry::default().with(filter).with(layer);
+
+    #[cfg(feature = "otel")]
+    {
+        if let Ok(endpoint) = env::var("KVS_OTEL_ENDPOINT") {
+            use opentelemetry::trace::TracerProvider;
+            let provider = otel::provider(&endpoint)?;
+            let tracer = provider.get_tracer("kvs", Some(env!("CARGO_PKG_VERSION")));
+            subscriber
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init();
+            return Ok(Guard {
+                _provider: Some(provider),
+            });
+        }
+    }
+
+    subscriber.init();
+    Ok(Guard::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_format() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!(matches!(
+            "xml".parse::<LogFormat>(),
+            Err(KvsError::InvalidLogFormat(_))
+        ));
+    }
+}
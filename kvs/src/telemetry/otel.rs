@@ -0,0 +1,278 @@
+// OpenTelemetryのspanをOTLP/HTTP(JSON)でcollectorに送る
+// https://github.com/open-telemetry/opentelemetry-proto/blob/main/docs/specification.md#otlphttp
+use crate::{KvsError, Result};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use hyper::{client::HttpConnector, header, Body, Client, Method, Request, Uri};
+use opentelemetry::{
+    runtime::Runtime,
+    sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::{self, BatchSpanProcessor, TracerProvider},
+        Resource,
+    },
+    trace::{SpanId, SpanKind, StatusCode},
+    Array, KeyValue, Value,
+};
+use serde_json::{json, Value as Json};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// BatchSpanProcessorをtokio 0.2のruntime上で動かす
+#[derive(Debug, Clone)]
+pub(crate) struct Tokio;
+
+impl Runtime for Tokio {
+    type Interval = tokio::time::Interval;
+    type Delay = tokio::time::Delay;
+
+    fn interval(&self, duration: Duration) -> Self::Interval {
+        tokio::time::interval(duration)
+    }
+
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+
+    fn delay(&self, duration: Duration) -> Self::Delay {
+        tokio::time::delay_for(duration)
+    }
+}
+
+// endpointにspanを送るTracerProviderを生成する. tokioのruntime内で呼ぶ必要がある
+pub(crate) fn provider(endpoint: &str) -> Result<TracerProvider> {
+    let processor = BatchSpanProcessor::builder(Exporter::new(endpoint)?, Tokio)
+        .with_scheduled_delay(Duration::from_secs(1))
+        .build();
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(processor)
+        .with_config(
+            trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "kvs")])),
+        )
+        .build())
+}
+
+#[derive(Debug)]
+pub(crate) struct Exporter {
+    // {endpoint}/v1/traces
+    uri: Uri,
+    client: Client<HttpConnector>,
+}
+
+impl Exporter {
+    pub(crate) fn new(endpoint: &str) -> Result<Self> {
+        let uri = format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+            .parse()
+            .map_err(|_| KvsError::InvalidOtelEndpoint(endpoint.to_owned()))?;
+        Ok(Self {
+            uri,
+            client: Client::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl SpanExporter for Exporter {
+    async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+        let body = serde_json::to_vec(&encode(&batch)).map_err(|err| err.to_string())?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|err| err.to_string())?;
+        let response = self
+            .client
+            .request(req)
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("collector responded {}", response.status()).into());
+        }
+        Ok(())
+    }
+}
+
+// ExportTraceServiceRequestのJSON表現
+fn encode(batch: &[SpanData]) -> Json {
+    let resource = batch
+        .first()
+        .map(|span| {
+            span.resource
+                .iter()
+                .map(|(key, value)| attribute(key.as_str(), value))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let spans = batch.iter().map(encode_span).collect::<Vec<_>>();
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": resource },
+            "scopeSpans": [{
+                "scope": { "name": "kvs", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn encode_span(span: &SpanData) -> Json {
+    let mut json = json!({
+        "traceId": span.span_context.trace_id().to_hex(),
+        "spanId": span.span_context.span_id().to_hex(),
+        "name": span.name,
+        "kind": match span.span_kind {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+            SpanKind::Producer => 4,
+            SpanKind::Consumer => 5,
+        },
+        "startTimeUnixNano": unix_nanos(span.start_time),
+        "endTimeUnixNano": unix_nanos(span.end_time),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key.as_str(), value))
+            .collect::<Vec<_>>(),
+        "events": span
+            .message_events
+            .iter()
+            .map(|event| json!({
+                "timeUnixNano": unix_nanos(event.timestamp),
+                "name": event.name,
+                "attributes": event
+                    .attributes
+                    .iter()
+                    .map(|kv| attribute(kv.key.as_str(), &kv.value))
+                    .collect::<Vec<_>>(),
+            }))
+            .collect::<Vec<_>>(),
+        "status": {
+            "code": match span.status_code {
+                StatusCode::Unset => 0,
+                StatusCode::Ok => 1,
+                StatusCode::Error => 2,
+            },
+            "message": span.status_message,
+        },
+    });
+    if span.parent_span_id != SpanId::invalid() {
+        json["parentSpanId"] = span.parent_span_id.to_hex().into();
+    }
+    json
+}
+
+fn attribute(key: &str, value: &Value) -> Json {
+    json!({ "key": key, "value": any_value(value) })
+}
+
+// int64はJSONでは文字列で表現する
+fn any_value(value: &Value) -> Json {
+    match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::I64(n) => json!({ "intValue": n.to_string() }),
+        Value::F64(f) => json!({ "doubleValue": f }),
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Array(array) => {
+            let values = match array {
+                Array::Bool(v) => v.iter().map(|b| json!({ "boolValue": b })).collect(),
+                Array::I64(v) => v
+                    .iter()
+                    .map(|n| json!({ "intValue": n.to_string() }))
+                    .collect(),
+                Array::F64(v) => v.iter().map(|f| json!({ "doubleValue": f })).collect(),
+                Array::String(v) => v.iter().map(|s| json!({ "stringValue": s })).collect(),
+            };
+            json!({ "arrayValue": { "values": Json::Array(values) } })
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response,
+    };
+    use opentelemetry::trace::TracerProvider as _;
+    use std::{convert::Infallible, result::Result as StdResult};
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // 受け取ったrequestのpathとbodyを返すcollector
+    fn collector() -> StdResult<(String, mpsc::UnboundedReceiver<(String, Json)>), Error> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let server = hyper::Server::from_tcp(listener)?.serve(make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let path = req.uri().path().to_owned();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        tx.send((path, serde_json::from_slice(&body).unwrap())).ok();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        }));
+        tokio::spawn(server);
+        Ok((endpoint, rx))
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn export() -> StdResult<(), Error> {
+        let (endpoint, mut rx) = collector()?;
+        let provider = provider(&endpoint)?;
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.get_tracer("kvs", None)));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", op = "put", key = "a", value_bytes = 5);
+            let _enter = span.enter();
+            tracing::info_span!("engine").in_scope(|| {});
+        });
+
+        let (path, body) = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await?
+            .unwrap();
+        assert_eq!(path, "/v1/traces");
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0],
+            json!({ "key": "service.name", "value": { "stringValue": "kvs" } })
+        );
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        let request = spans.iter().find(|s| s["name"] == "request").unwrap();
+        let engine = spans.iter().find(|s| s["name"] == "engine").unwrap();
+        let attributes = request["attributes"].as_array().unwrap();
+        assert!(attributes.contains(&json!({ "key": "op", "value": { "stringValue": "put" } })));
+        assert!(attributes.contains(&json!({ "key": "value_bytes", "value": { "intValue": "5" } })));
+        assert_eq!(engine["parentSpanId"], request["spanId"]);
+        assert_eq!(engine["traceId"], request["traceId"]);
+        assert_eq!(request["spanId"].as_str().unwrap().len(), 16);
+
+        // dropはbatchのtaskの終了を待つのでruntimeのworkerを塞がないようにする
+        tokio::task::spawn_blocking(move || drop(provider)).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_endpoint() {
+        assert!(matches!(
+            Exporter::new("not a uri"),
+            Err(KvsError::InvalidOtelEndpoint(_))
+        ));
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{protocol::message::Operator, KvsError, Result};
+use std::path::PathBuf;
+use tokio::net::TcpStream;
+
+pub(crate) use imp::{Acceptor, Connector};
+
+// TLSで利用する証明書と秘密鍵のpath
+// serverではcaを指定するとclient証明書を要求する(mutual TLS)
+// clientではcaでserver証明書を検証し、cert/keyを指定するとclient証明書を提示する
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub ca: Option<PathBuf>,
+    // clientがserver証明書を検証する際のdomain. 指定がなければ接続先addressのhostを利用する
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    // 証明書関連のpathがひとつでも指定されていればTLSを有効とする
+    pub fn is_enabled(&self) -> bool {
+        self.cert.is_some() || self.key.is_some() || self.ca.is_some()
+    }
+}
+
+fn invalid<M: Into<String>>(message: M) -> KvsError {
+    KvsError::TlsConfig(message.into())
+}
+
+#[cfg(feature = "tls")]
+mod imp {
+    use super::{invalid, TlsConfig};
+    use crate::{
+        protocol::message::{Operator, Stream},
+        Result,
+    };
+    use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+    use tokio::net::TcpStream;
+    use tokio_rustls::{
+        rustls::{
+            internal::pemfile, AllowAnyAuthenticatedClient, Certificate, ClientConfig,
+            NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+        },
+        webpki::DNSNameRef,
+        TlsAcceptor, TlsConnector,
+    };
+
+    // TLSが無効の場合はTcpStreamをそのまま利用する
+    #[derive(Clone)]
+    pub(crate) struct Acceptor(Option<TlsAcceptor>);
+
+    impl Acceptor {
+        pub(crate) fn new(config: Option<&TlsConfig>) -> Result<Self> {
+            config
+                .filter(|config| config.is_enabled())
+                .map(server_config)
+                .transpose()
+                .map(|config| Self(config.map(|config| TlsAcceptor::from(Arc::new(config)))))
+        }
+
+        pub(crate) async fn accept(&self, conn: TcpStream) -> Result<Box<dyn Stream>> {
+            match &self.0 {
+                Some(acceptor) => Ok(Box::new(acceptor.accept(conn).await?)),
+                None => Ok(Box::new(conn)),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub(crate) struct Connector(Option<TlsConnector>);
+
+    impl Connector {
+        pub(crate) fn new(config: Option<&TlsConfig>) -> Result<Self> {
+            config
+                .filter(|config| config.is_enabled())
+                .map(client_config)
+                .transpose()
+                .map(|config| Self(config.map(|config| TlsConnector::from(Arc::new(config)))))
+        }
+
+        pub(crate) async fn connect(&self, server_name: &str, conn: TcpStream) -> Result<Operator> {
+            match &self.0 {
+                Some(connector) => {
+                    let domain = DNSNameRef::try_from_ascii_str(server_name)
+                        .map_err(|_| invalid(format!("invalid server name {}", server_name)))?;
+                    Operator::with_stream(connector.connect(domain, conn).await?)
+                }
+                None => Operator::with_stream(conn),
+            }
+        }
+    }
+
+    fn server_config(config: &TlsConfig) -> Result<ServerConfig> {
+        let (cert, key) = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => return Err(invalid("server requires both tls cert and tls key")),
+        };
+        let client_auth = match &config.ca {
+            Some(ca) => AllowAnyAuthenticatedClient::new(load_root_store(ca)?),
+            None => NoClientAuth::new(),
+        };
+
+        let mut server_config = ServerConfig::new(client_auth);
+        server_config
+            .set_single_cert(load_certs(cert)?, load_private_key(key)?)
+            .map_err(|err| invalid(err.to_string()))?;
+        Ok(server_config)
+    }
+
+    fn client_config(config: &TlsConfig) -> Result<ClientConfig> {
+        let mut client_config = ClientConfig::new();
+        match &config.ca {
+            Some(ca) => client_config.root_store = load_root_store(ca)?,
+            None => return Err(invalid("client requires tls ca")),
+        }
+        match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => client_config
+                .set_single_client_cert(load_certs(cert)?, load_private_key(key)?)
+                .map_err(|err| invalid(err.to_string()))?,
+            (None, None) => (),
+            _ => return Err(invalid("tls cert and tls key must be specified together")),
+        }
+        Ok(client_config)
+    }
+
+    fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+        let certs = pemfile::certs(&mut BufReader::new(File::open(path)?))
+            .map_err(|_| invalid(format!("invalid certificate {}", path.display())))?;
+        if certs.is_empty() {
+            Err(invalid(format!(
+                "certificate not found in {}",
+                path.display()
+            )))
+        } else {
+            Ok(certs)
+        }
+    }
+
+    // PKCS8形式を優先し、なければRSA形式として読む
+    fn load_private_key(path: &Path) -> Result<PrivateKey> {
+        let err = || invalid(format!("invalid private key {}", path.display()));
+        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+            .map_err(|_| err())?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+                .map_err(|_| err())?;
+        }
+        keys.into_iter().next().ok_or_else(err)
+    }
+
+    fn load_root_store(path: &Path) -> Result<RootCertStore> {
+        let mut store = RootCertStore::empty();
+        match store.add_pem_file(&mut BufReader::new(File::open(path)?)) {
+            Ok((valid, _)) if valid > 0 => Ok(store),
+            _ => Err(invalid(format!(
+                "ca certificate not found in {}",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+mod imp {
+    use super::{invalid, TlsConfig};
+    use crate::{
+        protocol::message::{Operator, Stream},
+        Result,
+    };
+    use tokio::net::TcpStream;
+
+    fn disabled(config: Option<&TlsConfig>) -> Result<()> {
+        match config {
+            Some(config) if config.is_enabled() => {
+                Err(invalid("kvs was built without tls feature"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[derive(Clone)]
+    pub(crate) struct Acceptor;
+
+    impl Acceptor {
+        pub(crate) fn new(config: Option<&TlsConfig>) -> Result<Self> {
+            disabled(config).map(|_| Self)
+        }
+
+        pub(crate) async fn accept(&self, conn: TcpStream) -> Result<Box<dyn Stream>> {
+            Ok(Box::new(conn))
+        }
+    }
+
+    #[derive(Clone)]
+    pub(crate) struct Connector;
+
+    impl Connector {
+        pub(crate) fn new(config: Option<&TlsConfig>) -> Result<Self> {
+            disabled(config).map(|_| Self)
+        }
+
+        pub(crate) async fn connect(
+            &self,
+            _server_name: &str,
+            conn: TcpStream,
+        ) -> Result<Operator> {
+            Operator::with_stream(conn)
+        }
+    }
+}
+
+// TLS設定に従ってserverに接続する
+pub(crate) async fn connect(addr: &str, config: Option<&TlsConfig>) -> Result<Operator> {
+    let connector = Connector::new(config)?;
+    let server_name = config
+        .and_then(|config| config.server_name.clone())
+        .unwrap_or_else(|| host(addr).to_owned());
+    let stream = TcpStream::connect(addr).await?;
+    connector.connect(&server_name, stream).await
+}
+
+// "localhost:4002" -> "localhost", "[::1]:4002" -> "::1"
+fn host(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(i) => &addr[..i],
+        None => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_from_addr() {
+        assert_eq!(host("localhost:4002"), "localhost");
+        assert_eq!(host("[::1]:4002"), "::1");
+        assert_eq!(host("localhost"), "localhost");
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tls_tests {
+    use super::*;
+    use crate::{
+        protocol::message::{Message, Payload},
+        Kvs, Server,
+    };
+    use anyhow::Error;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use std::{path::Path, result::Result as StdResult};
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    // CAとCAで署名したserver, client証明書を生成する
+    fn generate_certs(dir: &Path) -> StdResult<(), Error> {
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params)?;
+        std::fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+
+        for name in &["server", "client"] {
+            let cert =
+                Certificate::from_params(CertificateParams::new(vec!["localhost".to_owned()]))?;
+            std::fs::write(
+                dir.join(format!("{}.pem", name)),
+                cert.serialize_pem_with_signer(&ca)?,
+            )?;
+            std::fs::write(
+                dir.join(format!("{}.key", name)),
+                cert.serialize_private_key_pem(),
+            )?;
+        }
+        Ok(())
+    }
+
+    async fn echo(addr: &str, config: &TlsConfig) -> Result<String> {
+        let mut operator = connect(addr, Some(config)).await?;
+        operator
+            .send(Message::from_payload(Payload::EchoRequest {
+                message: "hello tls".to_owned(),
+            })?)
+            .await?;
+        match operator.receive().await? {
+            Payload::EchoResponse { message } => Ok(message),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[tokio::test]
+    async fn mutual_tls() -> StdResult<(), Error> {
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let dir = tmp_dir.path();
+        generate_certs(dir)?;
+
+        let kvs = Kvs::new(dir.join("test.kvs"))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("localhost:{}", listener.local_addr()?.port());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = Server::new(kvs).tls(TlsConfig {
+            cert: Some(dir.join("server.pem")),
+            key: Some(dir.join("server.key")),
+            ca: Some(dir.join("ca.pem")),
+            server_name: None,
+        });
+        let server = tokio::spawn(server.serve(listener, async {
+            shutdown_rx.await.ok();
+        }));
+
+        let client = TlsConfig {
+            cert: Some(dir.join("client.pem")),
+            key: Some(dir.join("client.key")),
+            ca: Some(dir.join("ca.pem")),
+            server_name: None,
+        };
+        assert_eq!(echo(&addr, &client).await?, "hello tls");
+
+        // client証明書がなければ拒否される
+        let anonymous = TlsConfig {
+            cert: None,
+            key: None,
+            ..client
+        };
+        assert!(echo(&addr, &anonymous).await.is_err());
+
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+
+    // TLSを設定したserverではHTTP gatewayもTLSで受け付ける
+    #[tokio::test]
+    async fn gateway_tls() -> StdResult<(), Error> {
+        use std::{fs::File, io::BufReader, sync::Arc};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+
+        let tmp_dir = tempdir::TempDir::new("")?;
+        let dir = tmp_dir.path();
+        generate_certs(dir)?;
+
+        let kvs = Kvs::new(dir.join("test.kvs"))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let http_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let http_addr = http_listener.local_addr()?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = Server::new(kvs).tls(TlsConfig {
+            cert: Some(dir.join("server.pem")),
+            key: Some(dir.join("server.key")),
+            ca: None,
+            server_name: None,
+        });
+        let server =
+            tokio::spawn(
+                server.serve_with(Some(listener), Some(http_listener), None, async {
+                    shutdown_rx.await.ok();
+                }),
+            );
+
+        let request = b"GET /keys/none HTTP/1.1\r\nhost: localhost\r\n\r\n";
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut BufReader::new(File::open(dir.join("ca.pem"))?))
+            .unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut conn = TlsConnector::from(Arc::new(config))
+            .connect(domain, TcpStream::connect(http_addr).await?)
+            .await?;
+        conn.write_all(request).await?;
+        let mut response = [0_u8; 12];
+        conn.read_exact(&mut response).await?;
+        assert_eq!(&response, b"HTTP/1.1 404");
+
+        // 平文のrequestには応答しない
+        let mut conn = TcpStream::connect(http_addr).await?;
+        conn.write_all(request).await?;
+        let mut response = Vec::new();
+        let _ = conn.read_to_end(&mut response).await;
+        assert!(!response.starts_with(b"HTTP"));
+
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+// CLIでvalueのbytesを入出力する際の表現
+// inspectはschemaなしでvalueの中身を推測して表示する
+use crate::{KvsError, Result};
+use serde_json::Value as Json;
+use std::{convert::TryInto, fmt::Write, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueFormat {
+    // bincodeでserializeされたString. Kvs::put::<_, String>と同じ
+    String,
+    // bytesをそのまま入出力する
+    Raw,
+    // JSONのbytes. 出力時は整形する
+    Json,
+    // bytesの16進表現
+    Hex,
+}
+
+impl FromStr for ValueFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(ValueFormat::String),
+            "raw" => Ok(ValueFormat::Raw),
+            "json" => Ok(ValueFormat::Json),
+            "hex" => Ok(ValueFormat::Hex),
+            _ => Err(KvsError::InvalidValueFormat(s.to_owned())),
+        }
+    }
+}
+
+impl ValueFormat {
+    // 入力された文字列を格納するbytesにする
+    pub fn encode(self, input: &str) -> Result<Vec<u8>> {
+        match self {
+            ValueFormat::String => Ok(bincode::serialize(input)?),
+            ValueFormat::Raw => Ok(input.as_bytes().to_vec()),
+            ValueFormat::Json => {
+                let value = serde_json::from_str::<Json>(input)
+                    .map_err(|err| KvsError::InvalidValue(err.to_string()))?;
+                Ok(value.to_string().into_bytes())
+            }
+            ValueFormat::Hex => decode_hex(input),
+        }
+    }
+
+    // 格納されているbytesを出力する. Rawの場合はそのままのbytesを返す
+    pub fn decode(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            ValueFormat::String => Ok(bincode::deserialize::<String>(bytes)?.into_bytes()),
+            ValueFormat::Raw => Ok(bytes.to_vec()),
+            ValueFormat::Json => {
+                let value = serde_json::from_slice::<Json>(bytes)
+                    .map_err(|err| KvsError::InvalidValue(err.to_string()))?;
+                Ok(serde_json::to_vec_pretty(&value)
+                    .map_err(|err| KvsError::InvalidValue(err.to_string()))?)
+            }
+            ValueFormat::Hex => Ok(encode_hex(bytes).into_bytes()),
+        }
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+// 空白は無視する
+fn decode_hex(input: &str) -> Result<Vec<u8>> {
+    let digits = input
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| {
+            c.to_digit(16)
+                .map(|d| d as u8)
+                .ok_or_else(|| KvsError::InvalidValue(format!("invalid hex digit {:?}", c)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if digits.len() % 2 != 0 {
+        return Err(KvsError::InvalidValue(
+            "odd number of hex digits".to_owned(),
+        ));
+    }
+    Ok(digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect())
+}
+
+// valueの型がわからない場合に中身を推測して表示する
+// bincodeのString、JSONの順に試し、どちらでもなければbincodeのfieldの並びとして読む
+pub fn inspect(bytes: &[u8]) -> String {
+    if let Some(s) = read_string(bytes).filter(|(_, n)| *n == bytes.len()) {
+        return format!("{:?}", s.0);
+    }
+    if let Ok(value) = serde_json::from_slice::<Json>(bytes) {
+        if let Ok(pretty) = serde_json::to_string_pretty(&value) {
+            return pretty;
+        }
+    }
+    inspect_bincode(bytes)
+}
+
+// bincodeはschemaを持たないので、先頭から以下の順に当てはまるものとして読む
+// - u64の長さに続くUTF-8の文字列
+// - u64の長さに続くbytes(UUIDなど)
+// - 小さいu32(enumのvariant)
+// - u64(timestampなど)
+// 残りはbytesとして表示する. 推測なので実際の型とは異なる場合がある
+fn inspect_bincode(bytes: &[u8]) -> String {
+    let mut fields = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (field, n) = if let Some((s, n)) = read_string(rest) {
+            (format!("{:?}", s), n)
+        } else if let Some(len) = read_len(rest) {
+            (
+                format!("bytes({}) {}", len, encode_hex(&rest[8..8 + len])),
+                8 + len,
+            )
+        } else if rest.len() >= 4 && u32::from_le_bytes(rest[..4].try_into().unwrap()) < 256 {
+            (
+                format!("u32 {}", u32::from_le_bytes(rest[..4].try_into().unwrap())),
+                4,
+            )
+        } else if rest.len() >= 8 {
+            (
+                format!("u64 {}", u64::from_le_bytes(rest[..8].try_into().unwrap())),
+                8,
+            )
+        } else {
+            (
+                format!("bytes({}) {}", rest.len(), encode_hex(rest)),
+                rest.len(),
+            )
+        };
+        fields.push(field);
+        rest = &rest[n..];
+    }
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| format!("{}: {}", i, field))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// 先頭のu64を長さとみなし、残りのbytesに収まる場合はその長さを返す
+fn read_len(bytes: &[u8]) -> Option<usize> {
+    let len = u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+    if len == 0 || len > (bytes.len() - 8) as u64 {
+        return None;
+    }
+    Some(len as usize)
+}
+
+// 長さ付きの文字列と、読み込んだbytes数を返す. 制御文字を含む場合は文字列とみなさない
+fn read_string(bytes: &[u8]) -> Option<(&str, usize)> {
+    let len = u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+    if len > (bytes.len() - 8) as u64 {
+        return None;
+    }
+    let s = std::str::from_utf8(&bytes[8..8 + len as usize]).ok()?;
+    if s.chars().any(|c| c.is_control() && c != '\n' && c != '\t') {
+        return None;
+    }
+    Some((s, 8 + len as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use serde::Serialize;
+    use std::result::Result as StdResult;
+
+    #[test]
+    fn encode_decode() -> StdResult<(), Error> {
+        let roundtrip = |format: ValueFormat, input: &str| -> StdResult<Vec<u8>, Error> {
+            Ok(format.decode(&format.encode(input)?)?)
+        };
+        assert_eq!(roundtrip(ValueFormat::String, "hello")?, b"hello");
+        assert_eq!(
+            ValueFormat::String.encode("hello")?,
+            bincode::serialize("hello")?
+        );
+        assert_eq!(roundtrip(ValueFormat::Raw, "a b")?, b"a b");
+        assert_eq!(
+            ValueFormat::Json.encode(r#"{ "id": 1 }"#)?,
+            br#"{"id":1}"#.to_vec()
+        );
+        assert_eq!(
+            roundtrip(ValueFormat::Json, r#"{"id":1}"#)?,
+            b"{\n  \"id\": 1\n}"
+        );
+        assert!(ValueFormat::Json.encode("{").is_err());
+        assert_eq!(ValueFormat::Hex.encode("00ff 7F")?, vec![0, 255, 127]);
+        assert_eq!(roundtrip(ValueFormat::Hex, "00ff7f")?, b"00ff7f");
+        assert!(ValueFormat::Hex.encode("abc").is_err());
+        assert!(ValueFormat::Hex.encode("zz").is_err());
+        // Stringとして書き込まれていないvalue
+        assert!(ValueFormat::String.decode(&[0xff]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn inspect_values() -> StdResult<(), Error> {
+        assert_eq!(inspect(&bincode::serialize("hello")?), r#""hello""#);
+        assert_eq!(inspect(br#"{"id":1}"#), "{\n  \"id\": 1\n}");
+
+        #[derive(Serialize)]
+        enum Category {
+            _Work,
+            Hobby,
+        }
+        #[derive(Serialize)]
+        struct Task {
+            // UUIDなどは長さ付きのbytesになる
+            id: Vec<u8>,
+            title: String,
+            category: Category,
+            created_at: i64,
+            tags: Vec<u8>,
+        }
+        let task = Task {
+            id: vec![1, 2, 3, 4],
+            title: "Buy milk".to_owned(),
+            category: Category::Hobby,
+            created_at: 1_591_000_000_000,
+            tags: vec![0xff, 0x01],
+        };
+        assert_eq!(
+            inspect(&bincode::serialize(&task)?),
+            "0: bytes(4) 01020304\n\
+             1: \"Buy milk\"\n\
+             2: u32 1\n\
+             3: u64 1591000000000\n\
+             4: bytes(2) ff01"
+        );
+        assert_eq!(inspect(&[1, 2]), "0: bytes(2) 0102");
+        Ok(())
+    }
+}
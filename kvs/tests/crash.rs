@@ -0,0 +1,218 @@
+// 書き込みの途中でcrashした場合でも、開き直すと書き込みが完了した操作のみが反映されていることを確認する
+use kvs::{Kvs, KvsError, Storage};
+use proptest::prelude::*;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex},
+};
+
+// memory上のdiskに書き込むstorage. 指定したbytes数を超える書き込みを途中まで書いて失敗させる
+// diskはcrash後も残るので、同じdiskから開き直せる
+struct FaultyStorage {
+    disk: Arc<Mutex<Vec<u8>>>,
+    position: u64,
+    // 書き込めるbytes数の残り. Noneの場合は失敗しない
+    remaining: Option<usize>,
+    // trueの場合は1度だけ失敗し、以降の書き込みは成功する(diskの一時的なエラー)
+    transient: bool,
+}
+
+impl FaultyStorage {
+    fn new(disk: Arc<Mutex<Vec<u8>>>, fail_after: Option<usize>) -> Self {
+        Self {
+            disk,
+            position: 0,
+            remaining: fail_after,
+            transient: false,
+        }
+    }
+
+    fn transient(mut self) -> Self {
+        self.transient = true;
+        self
+    }
+}
+
+impl Read for FaultyStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let disk = self.disk.lock().unwrap();
+        let start = (self.position as usize).min(disk.len());
+        let n = buf.len().min(disk.len() - start);
+        buf[..n].copy_from_slice(&disk[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for FaultyStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match self.remaining {
+            Some(0) => {
+                if self.transient {
+                    self.remaining = None;
+                }
+                return Err(io::Error::other("injected fault"));
+            }
+            Some(remaining) => buf.len().min(remaining),
+            None => buf.len(),
+        };
+        let mut disk = self.disk.lock().unwrap();
+        let start = self.position as usize;
+        if disk.len() < start + n {
+            disk.resize(start + n, 0);
+        }
+        disk[start..start + n].copy_from_slice(&buf[..n]);
+        self.position += n as u64;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= n;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FaultyStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.disk.lock().unwrap().len() as i64;
+        self.position = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => len + n,
+            SeekFrom::Current(n) => self.position as i64 + n,
+        } as u64;
+        Ok(self.position)
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.disk.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    // keyの種類を少なくして、上書きと削除が起きやすくする
+    let key = "[a-d]";
+    prop_oneof![
+        3 => (key, prop::collection::vec(any::<u8>(), 0..64)).prop_map(|(k, v)| Op::Put(k, v)),
+        1 => key.prop_map(Op::Delete),
+    ]
+}
+
+// modelには成功した操作のみを反映する
+fn apply_op(kvs: &mut Kvs, model: &mut HashMap<String, Vec<u8>>, op: &Op) -> Result<(), KvsError> {
+    match op.clone() {
+        Op::Put(key, value) => kvs
+            .put_raw(key.clone(), value.clone())
+            .map(|_| model.insert(key, value))
+            .map(|_| ()),
+        Op::Delete(key) => kvs.delete_raw(&key).map(|_| {
+            model.remove(&key);
+        }),
+    }
+}
+
+// 失敗した操作以降は実行しない(crash)
+fn apply(kvs: &mut Kvs, model: &mut HashMap<String, Vec<u8>>, ops: &[Op]) {
+    for op in ops {
+        if apply_op(kvs, model, op).is_err() {
+            return;
+        }
+    }
+}
+
+fn assert_matches(kvs: &mut Kvs, model: &HashMap<String, Vec<u8>>) {
+    let mut keys = kvs.keys().unwrap().cloned().collect::<Vec<_>>();
+    keys.sort();
+    let mut expected = model.keys().cloned().collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(keys, expected);
+    for (key, value) in model {
+        assert_eq!(&kvs.get_raw(key).unwrap(), value, "key {}", key);
+    }
+}
+
+proptest! {
+    #[test]
+    fn crash_and_reopen(
+        before in prop::collection::vec(op(), 0..20),
+        after in prop::collection::vec(op(), 0..20),
+        fail_after in 0..2048_usize,
+    ) {
+        let disk = Arc::new(Mutex::new(Vec::new()));
+        let mut model = HashMap::new();
+
+        let mut kvs = Kvs::with_storage(FaultyStorage::new(disk.clone(), Some(fail_after))).unwrap();
+        apply(&mut kvs, &mut model, &before);
+        drop(kvs);
+
+        // 開き直した後も書き込みを続けられる
+        let mut kvs = Kvs::with_storage(FaultyStorage::new(disk.clone(), None)).unwrap();
+        assert_matches(&mut kvs, &model);
+        apply(&mut kvs, &mut model, &after);
+        drop(kvs);
+
+        let mut kvs = Kvs::with_storage(FaultyStorage::new(disk, None)).unwrap();
+        assert_matches(&mut kvs, &model);
+    }
+
+    // 書き込みに失敗しても、開き直さずにそのまま書き込みを続けられる
+    #[test]
+    fn continue_after_write_error(
+        ops in prop::collection::vec(op(), 0..40),
+        fail_after in 0..2048_usize,
+    ) {
+        let disk = Arc::new(Mutex::new(Vec::new()));
+        let mut model = HashMap::new();
+
+        let storage = FaultyStorage::new(disk.clone(), Some(fail_after)).transient();
+        let mut kvs = Kvs::with_storage(storage).unwrap();
+        for op in &ops {
+            let _ = apply_op(&mut kvs, &mut model, op);
+        }
+        assert_matches(&mut kvs, &model);
+        drop(kvs);
+
+        let mut kvs = Kvs::with_storage(FaultyStorage::new(disk, None)).unwrap();
+        assert_matches(&mut kvs, &model);
+    }
+
+    // 途中のentryが壊れている場合は開けずに失敗し、後ろのentryを切り詰めない
+    #[test]
+    fn corrupt_mid_log(
+        values in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64), 2..10),
+        target in any::<prop::sample::Index>(),
+        byte in any::<prop::sample::Index>(),
+    ) {
+        let disk = Arc::new(Mutex::new(Vec::new()));
+        let mut kvs = Kvs::with_storage(FaultyStorage::new(disk.clone(), None)).unwrap();
+        let mut offsets = vec![0];
+        for (i, value) in values.iter().enumerate() {
+            let key = format!("k{}", i);
+            // header(11 bytes) + key + value
+            offsets.push(offsets[i] + 11 + key.len() + value.len());
+            kvs.put_raw(key, value.clone()).unwrap();
+        }
+        drop(kvs);
+
+        // 最後以外のentryの1byteを反転する
+        let target = target.index(values.len() - 1);
+        let offset = offsets[target] + byte.index(offsets[target + 1] - offsets[target]);
+        disk.lock().unwrap()[offset] ^= 0xFF;
+        let corrupted = disk.lock().unwrap().clone();
+
+        let err = Kvs::with_storage(FaultyStorage::new(disk.clone(), None)).err().unwrap();
+        prop_assert!(err.is_data_corrupt(), "{:?}", err);
+        prop_assert_eq!(&*disk.lock().unwrap(), &corrupted);
+    }
+}
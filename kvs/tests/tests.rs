@@ -1,6 +1,6 @@
 use assert_cmd::prelude::*;
-use kvs::Kvs;
-use predicates::str::contains;
+use kvs::{EngineKind, Kvs, KvsError};
+use predicates::{prelude::*, str::contains};
 use std::process::Command;
 
 #[test]
@@ -12,7 +12,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -36,3 +36,581 @@ fn cli_put_get_delete() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[test]
+fn cli_stats() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("--file")
+            .arg(&file)
+            .args(args)
+            .assert()
+            .success()
+    };
+
+    kvs(&["put", "key1", "value1"]);
+    kvs(&["put", "key1", "value2"]);
+    kvs(&["put", "key2", "value3"]);
+    kvs(&["stats"])
+        .stdout(contains("keys: 2\n"))
+        .stdout(contains("stale_bytes: 0\n").not());
+
+    Ok(())
+}
+
+#[test]
+fn cli_value_format() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("--file")
+            .arg(&file)
+            .args(args)
+            .assert()
+    };
+
+    kvs(&["put", "--format", "json", "json", r#"{ "id": 1 }"#]).success();
+    kvs(&["get", "--format", "raw", "json"])
+        .success()
+        .stdout(r#"{"id":1}"#);
+    kvs(&["get", "--format", "json", "json"])
+        .success()
+        .stdout("{\n  \"id\": 1\n}\n");
+    kvs(&["put", "--format", "json", "json", "{"]).failure();
+
+    // bincodeでserializeされたstruct(u32とString)
+    kvs(&[
+        "put",
+        "--format",
+        "hex",
+        "task",
+        "07000000 0300000000000000 616263",
+    ])
+    .success();
+    kvs(&["get", "--format", "hex", "task"])
+        .success()
+        .stdout("070000000300000000000000616263\n");
+    kvs(&["get", "task"]).failure();
+    kvs(&["inspect", "task"])
+        .success()
+        .stdout("0: u32 7\n1: \"abc\"\n");
+
+    // 削除したvalueはformatで出力する. 出力できない場合も削除は成功する
+    kvs(&["delete", "--format", "json", "json"])
+        .success()
+        .stdout("{\n  \"id\": 1\n}\nSuccessfully deleted\n");
+    kvs(&["delete", "task"])
+        .success()
+        .stdout("Successfully deleted\n")
+        .stderr(contains("Failed to print deleted value"));
+    kvs(&["get", "task"]).code(2);
+
+    Ok(())
+}
+
+#[test]
+fn storage() -> Result<(), anyhow::Error> {
+    let mut kvs = Kvs::in_memory()?;
+    kvs.put("key1", &"value1".to_owned())?;
+    assert_eq!(kvs.get::<String>("key1")?, "value1");
+    kvs.sync()?;
+
+    // 既存のlogを持つstorageから開く
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    Kvs::new(&file)?.put("key2", &"value2".to_owned())?;
+    let log = std::fs::read(&file)?;
+    let mut kvs = Kvs::with_storage(std::io::Cursor::new(log))?;
+    assert_eq!(kvs.get::<String>("key2")?, "value2");
+    kvs.put("key3", &"value3".to_owned())?;
+    assert_eq!(kvs.history_raw("key3")?.len(), 1);
+    assert_eq!(kvs.stats().keys, 2);
+    Ok(())
+}
+
+#[test]
+fn lsm_engine() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let dir = tmp_dir.path().join("test.lsm");
+    let mut kvs = Kvs::with_engine(&dir, EngineKind::Lsm)?;
+    // memtableの上限を超えて、tableに書き出される量を書き込む
+    let value = "x".repeat(1024);
+    for i in 0..5000 {
+        kvs.put(format!("key/{:04}", i), &value)?;
+    }
+    assert_eq!(kvs.delete::<String>("key/0001")?, Some(value.clone()));
+    kvs.put("key/0002", &"updated".to_owned())?;
+    kvs.create_index("len", |v: &String| v.len())?;
+    assert_eq!(kvs.lookup("len", &7_usize)?, vec!["key/0002"]);
+    assert!(kvs
+        .history_raw("key/0002")
+        .is_err_and(|err| matches!(err, KvsError::Unsupported(_))));
+    assert!(Kvs::with_engine(&dir, EngineKind::Lsm)
+        .err()
+        .unwrap()
+        .is_locked());
+    drop(kvs);
+
+    let mut kvs = Kvs::open_read_only_with_engine(&dir, EngineKind::Lsm)?;
+    assert!(kvs.get::<String>("key/0001").unwrap_err().is_not_found());
+    assert_eq!(kvs.get::<String>("key/0002")?, "updated");
+    assert_eq!(kvs.get::<String>("key/4999")?, value);
+    assert_eq!(kvs.owned_keys().count(), 4999);
+    assert!(kvs.contains_key("key/0000"));
+
+    Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&dir)
+        .args(["--engine", "lsm", "get", "key/0002"])
+        .assert()
+        .success()
+        .stdout("updated\n");
+    Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&dir)
+        .args(["--engine", "btree", "get", "key/0002"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid engine btree"));
+    Ok(())
+}
+
+#[test]
+fn lock_file() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    let mut writer = Kvs::new(&file)?;
+    writer.put("key1", &"value1".to_owned())?;
+
+    assert!(Kvs::new(&file).err().unwrap().is_locked());
+    let mut reader = Kvs::open_read_only(&file)?;
+    assert!(reader.is_read_only());
+    assert_eq!(reader.get::<String>("key1")?, "value1");
+    assert!(reader
+        .put("key2", &"value2".to_owned())
+        .unwrap_err()
+        .is_read_only());
+    assert!(reader.delete::<String>("key1").unwrap_err().is_read_only());
+
+    // 別processからも書き込みはできず、参照はできる
+    Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&file)
+        .args(["put", "key2", "value2"])
+        .assert()
+        .failure()
+        .stderr(contains("is locked by another process"));
+    Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&file)
+        .args(["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // writerの追記をrefreshで反映する
+    writer.put("key2", &"value2".to_owned())?;
+    writer.delete::<String>("key1")?;
+    assert!(reader.get::<String>("key2").unwrap_err().is_not_found());
+    assert_eq!(reader.refresh()?, 2);
+    assert_eq!(reader.get::<String>("key2")?, "value2");
+    assert!(reader.get::<String>("key1").unwrap_err().is_not_found());
+    assert_eq!(reader.refresh()?, 0);
+
+    // closeするとlockが解放される
+    drop(writer);
+    Kvs::new(&file)?.put("key3", &"value3".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn secondary_index() -> Result<(), anyhow::Error> {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Task {
+        title: String,
+        created_at: i64,
+    }
+    let task = |title: &str, created_at: i64| Task {
+        title: title.to_owned(),
+        created_at,
+    };
+
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    let mut kvs = Kvs::new(&file)?;
+    kvs.put("t1", &task("b", 30))?;
+    kvs.put("t2", &task("a", 10))?;
+    // Taskでないvalueはindexに含まれない
+    kvs.put("other", &1_u8)?;
+
+    kvs.create_index("by_created_at", |t: &Task| t.created_at)?;
+    kvs.create_index("by_title", |t: &Task| t.title.clone())?;
+    kvs.put("t3", &task("a", 20))?;
+    assert_eq!(
+        kvs.range::<i64, _>("by_created_at", ..)?,
+        vec!["t2", "t3", "t1"]
+    );
+    assert_eq!(kvs.range("by_created_at", 15_i64..)?, vec!["t3", "t1"]);
+    assert_eq!(kvs.lookup("by_title", &"a".to_owned())?, vec!["t2", "t3"]);
+
+    kvs.put("t2", &task("c", 40))?;
+    kvs.delete::<Task>("t3")?;
+    assert_eq!(kvs.range::<i64, _>("by_created_at", ..)?, vec!["t1", "t2"]);
+    assert!(kvs.lookup("by_title", &"a".to_owned())?.is_empty());
+    assert!(kvs.lookup("by_title", &1_i64).is_err());
+    assert!(kvs.drop_index("by_title"));
+    assert!(kvs.lookup("by_title", &"a".to_owned()).is_err());
+
+    // 開き直した場合はindexを作り直す
+    drop(kvs);
+    let mut kvs = Kvs::new(&file)?;
+    kvs.create_index("by_created_at", |t: &Task| t.created_at)?;
+    assert_eq!(kvs.range("by_created_at", ..=30_i64)?, vec!["t1"]);
+    Ok(())
+}
+
+#[test]
+fn cli_history() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("--file")
+            .arg(&file)
+            .args(args)
+            .assert()
+    };
+
+    kvs(&["put", "key1", "v1"]).success();
+    kvs(&["put", "key2", "v2"]).success();
+    kvs(&["put", "key1", "v3"]).success();
+    kvs(&["delete", "key1"]).success();
+    kvs(&["history", "key1"])
+        .success()
+        .stdout("offset: 0 v1\noffset: 50 v3\noffset: 75 (deleted)\n");
+    kvs(&["history", "--format", "hex", "key2"])
+        .success()
+        .stdout("offset: 25 02000000000000007632\n");
+    kvs(&["history", "key3"]).success().stdout("");
+
+    let versions = Kvs::new(&file)?
+        .history::<String>("key1")?
+        .map(|version| version.map(|v| v.value))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        versions,
+        vec![Some("v1".to_owned()), Some("v3".to_owned()), None]
+    );
+    Ok(())
+}
+
+#[test]
+fn cli_dump() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("--file")
+            .arg(&file)
+            .args(args)
+            .assert()
+    };
+
+    kvs(&["put", "key1", "value1"]).success();
+    kvs(&["put", "key2", "value2"]).success();
+    kvs(&["delete", "key1"]).success();
+    kvs(&["dump"])
+        .success()
+        .stdout(contains("offset: 0 len: 29 crc: ok ").and(contains(
+            "|key: key2|value: \"\\u{6}\\0\\0\\0\\0\\0\\0\\0value2\"|",
+        )))
+        .stderr("3 entries, 1 deleted, 0 corrupt\n");
+    kvs(&["dump", "--key", "key1", "--from", "1"])
+        .success()
+        .stdout(contains("|state: Deleted|"))
+        .stderr(contains("1 entries"));
+
+    // 末尾を切り詰めても、それまでのentryは表示する
+    let len = std::fs::metadata(&file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&file)?
+        .set_len(len - 1)?;
+    kvs(&["dump"])
+        .failure()
+        .stdout(contains("offset: 29 ").and(contains("offset 58: ")));
+    Ok(())
+}
+
+#[test]
+fn cli_shell() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+    let history = tmp_dir.path().join("history");
+
+    // stdinがttyでない場合は1行ずつ読み込む
+    assert_cmd::Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&file)
+        .args(["shell", "--history"])
+        .arg(&history)
+        .write_stdin(
+            "put key1 hello world\nget key1\nput key2 v2\nscan key\nget key3\nstats\nexit\n",
+        )
+        .assert()
+        .success()
+        .stdout(contains("hello world\n"))
+        .stdout(contains("key1 hello world\nkey2 v2\n"))
+        .stdout(contains("keys: 2\n"))
+        .stderr(contains("Not Found"));
+
+    // shellでの変更はfileに書き込まれている
+    let mut kvs = Kvs::new(&file)?;
+    assert_eq!(kvs.get::<String>("key2")?, "v2");
+    assert!(std::fs::read_to_string(&history)?.contains("scan key"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_export_import() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let src = tmp_dir.path().join("src.kvs");
+    let dst = tmp_dir.path().join("dst.kvs");
+    let csv = tmp_dir.path().join("export.csv");
+    let kvs = |file: &std::path::Path, args: &[&str]| {
+        assert_cmd::Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("--file")
+            .arg(file)
+            .args(args)
+            .assert()
+    };
+
+    kvs(&src, &["put", "todo/1", "write docs"]).success();
+    kvs(&src, &["put", "todo/2", "a, b"]).success();
+    kvs(&src, &["export"]).success().stdout(
+        r#"{"key":"todo/1","value":"write docs"}
+{"key":"todo/2","value":"a, b"}
+"#,
+    );
+    kvs(
+        &src,
+        &[
+            "export",
+            "--format",
+            "csv",
+            "--output",
+            csv.to_str().unwrap(),
+        ],
+    )
+    .success()
+    .stderr(contains("Exported 2 entries, 0 errors"));
+
+    kvs(&dst, &["import", "--format", "csv", csv.to_str().unwrap()])
+        .success()
+        .stderr(contains("Imported 2 entries, 0 errors"));
+    kvs(&dst, &["get", "todo/2"]).success().stdout("a, b\n");
+
+    // 不正な行を報告し、それ以外は書き込む
+    assert_cmd::Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&dst)
+        .arg("import")
+        .write_stdin("{\"key\":\"todo/3\",\"value\":\"x\"}\n{\"key\":1}\n")
+        .assert()
+        .failure()
+        .stderr(contains("line 2: "))
+        .stderr(contains("Imported 1 entries, 1 errors"));
+    kvs(&dst, &["get", "todo/3"]).success().stdout("x\n");
+
+    Ok(())
+}
+
+// 3つのserver processでRaft clusterを構成し、leaderへの書き込みが各nodeに複製されることを確認する
+#[test]
+fn cli_raft_cluster() -> Result<(), anyhow::Error> {
+    use std::{
+        net::TcpListener,
+        process::{Child, Output},
+        thread,
+        time::Duration,
+    };
+
+    struct Node(Child);
+    impl Drop for Node {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let addrs = (0..3)
+        .map(|_| Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string()))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    let cluster = tmp_dir.path().join("cluster.toml");
+    std::fs::write(
+        &cluster,
+        addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| format!("[[nodes]]\nid = {}\naddr = \"{}\"\n", i + 1, addr))
+            .collect::<String>(),
+    )?;
+
+    let _nodes = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            Ok(Node(
+                Command::cargo_bin("kvs")?
+                    .env("KVS_LOG", "error")
+                    .arg("--file")
+                    .arg(tmp_dir.path().join(format!("{}.kvs", i + 1)))
+                    .args(["server", "--addr", addr, "--raft-id", &(i + 1).to_string()])
+                    .arg("--raft-cluster")
+                    .arg(&cluster)
+                    .spawn()?,
+            ))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    let client = |addr: &str, args: &[&str]| -> Result<Output, anyhow::Error> {
+        Ok(Command::cargo_bin("kvs")?
+            .env("KVS_LOG", "error")
+            .args(["client", "--addr", addr])
+            .args(args)
+            .output()?)
+    };
+
+    // leaderが選出されるまでは全てのnodeが失敗する
+    let mut leader = None;
+    'retry: for _ in 0..50 {
+        for addr in &addrs {
+            if client(addr, &["put", "key1", "value1"])?.status.success() {
+                leader = Some(addr.clone());
+                break 'retry;
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let leader = leader.expect("leader was not elected");
+
+    let output = client(&leader, &["get", "key1"])?;
+    assert!(String::from_utf8(output.stdout)?.contains("value1"));
+
+    // followerはleaderのaddressを返す
+    let follower = addrs.iter().find(|&addr| *addr != leader).unwrap();
+    let output = client(follower, &["put", "key2", "value2"])?;
+    assert_eq!(output.status.code(), Some(5));
+    assert!(String::from_utf8(output.stderr)?.contains(&leader));
+    Ok(())
+}
+
+#[test]
+fn cli_replica_of() -> Result<(), anyhow::Error> {
+    use std::{
+        net::TcpListener,
+        process::{Child, Output},
+        thread,
+        time::Duration,
+    };
+
+    struct Node(Child);
+    impl Drop for Node {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let addrs = (0..2)
+        .map(|_| Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string()))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    let (leader, follower) = (&addrs[0], &addrs[1]);
+
+    let server = |name: &str, addr: &str| -> Result<Command, anyhow::Error> {
+        let mut cmd = Command::cargo_bin("kvs")?;
+        cmd.env("KVS_LOG", "error")
+            .arg("--file")
+            .arg(tmp_dir.path().join(name))
+            .args(["server", "--addr", addr]);
+        Ok(cmd)
+    };
+    let _leader = Node(server("leader.kvs", leader)?.spawn()?);
+    let _follower = Node(
+        server("follower.kvs", follower)?
+            .args(["--replica-of", leader])
+            .spawn()?,
+    );
+
+    let client = |addr: &str, args: &[&str]| -> Result<Output, anyhow::Error> {
+        Ok(Command::cargo_bin("kvs")?
+            .env("KVS_LOG", "error")
+            .args(["client", "--addr", addr])
+            .args(args)
+            .output()?)
+    };
+
+    // leaderが起動するまで待つ
+    let mut put = false;
+    for _ in 0..50 {
+        if client(leader, &["put", "key1", "value1"])?.status.success() {
+            put = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(put, "leader did not start");
+
+    // followerは非同期にleaderのlogを複製する
+    let mut replicated = false;
+    for _ in 0..50 {
+        let output = client(follower, &["get", "key1"])?;
+        if String::from_utf8(output.stdout)?.contains("value1") {
+            replicated = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(replicated, "follower did not replicate the leader's log");
+
+    // followerはread-only
+    let output = client(follower, &["put", "key2", "value2"])?;
+    assert_eq!(output.status.code(), Some(4));
+    Ok(())
+}
+
+#[test]
+fn cli_read_missing_file() -> Result<(), anyhow::Error> {
+    let tmp_dir = tempdir::TempDir::new("")?;
+    let file = tmp_dir.path().join("test.kvs");
+
+    // 参照のみのcommandは、まだないfileを空のstoreとして扱い作成しない
+    Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&file)
+        .args(["get", "key1"])
+        .assert()
+        .code(2)
+        .stderr(contains("Not Found"));
+    Command::cargo_bin("kvs")?
+        .arg("--file")
+        .arg(&file)
+        .arg("stats")
+        .assert()
+        .success()
+        .stdout(contains("keys: 0\n"));
+    assert!(!file.exists());
+
+    Ok(())
+}
@@ -4,7 +4,7 @@ use crate::{
 };
 use hyper::body::Buf;
 use hyper::{Body, Request, Response, StatusCode};
-use kvs::Kvs;
+use kvs::AsyncKvs;
 use serde::Serialize;
 use std::{
     borrow::{Borrow, Cow},
@@ -34,17 +34,20 @@ impl TaskHandler {
         Self {}
     }
 
-    pub fn get_tasks(
+    pub async fn get_tasks(
         &self,
         req: Request<Body>,
-        kvs: &mut Kvs,
+        kvs: &AsyncKvs,
     ) -> Result<Response<Body>, anyhow::Error> {
         let mut tasks: Vec<Task> = kvs
-            .iter::<Task>()
-            // kvsにはtask以外も格納されている可能性があるので、serialize error(=他のdata)と判断して無視する
-            // ただし、その他のエラーは握りつぶさないようにする
-            .filter(|r| r.is_ok() || !r.as_ref().unwrap_err().is_serialize())
-            .collect::<Result<Vec<Task>, _>>()?;
+            .run(|kvs| {
+                kvs.iter::<Task>()
+                    // kvsにはtask以外も格納されている可能性があるので、serialize error(=他のdata)と判断して無視する
+                    // ただし、その他のエラーは握りつぶさないようにする
+                    .filter(|r| r.is_ok() || !r.as_ref().unwrap_err().is_serialize())
+                    .collect::<Result<Vec<Task>, _>>()
+            })
+            .await?;
 
         // filter
         let query: HashMap<Cow<str>, Cow<str>> = req
@@ -85,9 +88,8 @@ impl TaskHandler {
     pub async fn create_task(
         &self,
         req: Request<Body>,
-        kvs: &mut Kvs,
+        kvs: &AsyncKvs,
     ) -> Result<Response<Body>, anyhow::Error> {
-        // TODO: read body then acquire lock
         let create_cmd = serde_json::from_slice::<task::CreateCommand>(
             hyper::body::to_bytes(req.into_body()).await?.bytes(),
         )?;
@@ -95,7 +97,7 @@ impl TaskHandler {
         let task = Task::create(create_cmd)?;
         info!(?task, "Create new task");
 
-        kvs.put(task.id().to_string(), &task)?;
+        kvs.put(task.id().to_string(), &task).await?;
 
         serde_json::to_vec(&task)
             .map(|serialized| Response::new(Body::from(serialized)))
@@ -103,30 +105,73 @@ impl TaskHandler {
     }
 
     // taskの削除
-    pub fn delete_task(
+    pub async fn delete_task(
         &self,
         req: Request<Body>,
-        kvs: &mut Kvs,
+        kvs: &AsyncKvs,
     ) -> Result<Response<Body>, anyhow::Error> {
         // /tasks/{uuid} というpathを想定
-        req.uri()
+        let delete_id = req
+            .uri()
             .path()
             .split('/')
             .nth(2)
-            .ok_or_else(|| anyhow::anyhow!("task id not found in path"))
-            .and_then(|delete_id| {
-                info!("Delete task: {:?}", delete_id);
-                kvs.delete::<Task>(delete_id).map_err(anyhow::Error::from)
-            })
-            .and_then(|opt: Option<Task>| match opt {
-                Some(task) => serde_json::to_vec(&task)
-                    .map(|serialized| Response::new(Body::from(serialized)))
-                    .map_err(anyhow::Error::from),
-                // 最初は削除対象がなくてもOKにしていたが、バグだと気づかなったのでエラーにする
-                None => Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Body::empty())
-                    .map_err(anyhow::Error::from),
-            })
+            .ok_or_else(|| anyhow::anyhow!("task id not found in path"))?;
+        info!("Delete task: {:?}", delete_id);
+        match kvs.delete::<Task>(delete_id).await? {
+            Some(task) => serde_json::to_vec(&task)
+                .map(|serialized| Response::new(Body::from(serialized)))
+                .map_err(anyhow::Error::from),
+            // 最初は削除対象がなくてもOKにしていたが、バグだと気づかなったのでエラーにする
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .map_err(anyhow::Error::from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvs::Kvs;
+
+    async fn body_json(response: Response<Body>) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_get_delete_tasks() -> Result<(), anyhow::Error> {
+        let kvs = AsyncKvs::new(Kvs::in_memory()?)?;
+        let handler = TaskHandler::new();
+        for title in &["Buy milk", "Write docs"] {
+            let req = Request::post("/tasks").body(Body::from(format!(
+                r#"{{"title":"{}","category":"work","content":""}}"#,
+                title
+            )))?;
+            handler.create_task(req, &kvs).await?;
+        }
+
+        let req = Request::get("/tasks?query=milk").body(Body::empty())?;
+        let tasks = body_json(handler.get_tasks(req, &kvs).await?).await["tasks"].clone();
+        assert_eq!(tasks.as_array().unwrap().len(), 1);
+        assert_eq!(tasks[0]["title"], "Buy milk");
+
+        let id = tasks[0]["id"].as_str().unwrap();
+        let req = Request::delete(format!("/tasks/{}", id)).body(Body::empty())?;
+        assert_eq!(
+            handler.delete_task(req, &kvs).await?.status(),
+            StatusCode::OK
+        );
+        let req = Request::delete(format!("/tasks/{}", id)).body(Body::empty())?;
+        assert_eq!(
+            handler.delete_task(req, &kvs).await?.status(),
+            StatusCode::NOT_FOUND
+        );
+        let req = Request::get("/tasks").body(Body::empty())?;
+        let tasks = body_json(handler.get_tasks(req, &kvs).await?).await["tasks"].clone();
+        assert_eq!(tasks.as_array().unwrap().len(), 1);
+        Ok(())
     }
 }
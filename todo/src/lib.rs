@@ -33,6 +33,13 @@ pub mod config {
             .or(path::PathBuf::from_str("./todo.kvs"))
             .expect("Get kvs file path")
     }
+
+    // kvsでgetしたtaskを保持するcacheのbytes数. 0の場合はcacheしない
+    pub fn kvs_cache_bytes() -> usize {
+        env::var("TODO_KVS_CACHE_BYTES")
+            .map(|bytes| bytes.parse::<usize>().expect("Parse TODO_KVS_CACHE_BYTES"))
+            .unwrap_or(0)
+    }
 }
 
 // applicationのstate
@@ -40,15 +47,13 @@ pub mod config {
 // 今はstorage層を組み込んでないので、in memoryに全部もっている
 pub mod state {
     use crate::config;
-    use kvs::Kvs;
+    use kvs::{AsyncKvs, Kvs};
     use std::sync::Arc;
-    use tokio::sync::RwLock;
 
     // app state
-    #[derive(Default)]
     pub struct State {
-        // tokio::sync::RwLockがDefaultの実装を要求するのでOptionでWrapしている
-        pub kvs: RwLock<Option<Kvs>>,
+        // file IOは専用のthreadで行われるので、lockせずにhandlerから利用できる
+        pub kvs: AsyncKvs,
     }
 
     pub type SharedState = Arc<State>;
@@ -59,13 +64,17 @@ pub mod state {
         }
 
         fn new() -> Result<Self, anyhow::Error> {
-            Ok(Self {
-                kvs: RwLock::new(Some(State::kvs()?)),
-            })
+            Ok(Self { kvs: State::kvs()? })
         }
 
-        fn kvs() -> Result<Kvs, anyhow::Error> {
-            Kvs::new(config::kvs_file_path().as_path()).map_err(anyhow::Error::from)
+        fn kvs() -> Result<AsyncKvs, anyhow::Error> {
+            Kvs::new(config::kvs_file_path().as_path())
+                .map(|mut kvs| {
+                    kvs.set_cache_capacity(config::kvs_cache_bytes());
+                    kvs
+                })
+                .and_then(AsyncKvs::new)
+                .map_err(anyhow::Error::from)
         }
     }
 }
@@ -154,20 +163,9 @@ pub mod router {
             _tasks if path.starts_with("/tasks") => {
                 let task_handler = handler::TaskHandler::new();
                 match *method {
-                    Method::GET => {
-                        let mut kvs = state.kvs.write().await;
-                        task_handler.get_tasks(req, &mut kvs.as_mut().unwrap())
-                    }
-                    Method::POST => {
-                        let mut kvs = state.kvs.write().await;
-                        task_handler
-                            .create_task(req, &mut kvs.as_mut().unwrap())
-                            .await
-                    }
-                    Method::DELETE => {
-                        let mut kvs = state.kvs.write().await;
-                        task_handler.delete_task(req, &mut kvs.as_mut().unwrap())
-                    }
+                    Method::GET => task_handler.get_tasks(req, &state.kvs).await,
+                    Method::POST => task_handler.create_task(req, &state.kvs).await,
+                    Method::DELETE => task_handler.delete_task(req, &state.kvs).await,
                     _ => handler::not_found(),
                 }
             }